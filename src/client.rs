@@ -1,27 +1,59 @@
 use crate::{
     certificate::{certificate_filename_or_default, read_certs_from_file},
-    messages::{Message, MessageEncoder},
+    device_id::DeviceIdentity,
+    messages::DeviceId,
+    node::Node,
 };
-use color_eyre::eyre::Result;
-use futures::SinkExt;
-use notify::{RecursiveMode, Watcher};
-use quinn::{ClientConfig, Endpoint};
+use color_eyre::{eyre::eyre, Report, Result};
+use quinn::{ClientConfig, Connection, Endpoint};
 use rustls::RootCertStore;
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::mpsc;
-use tokio_util::codec::FramedWrite;
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
 use tracing::*;
 
-pub async fn connect(
-    server_name: &str,
-    address: &str,
-    certificate_path: Option<String>,
-    source_path: PathBuf,
-) -> Result<()> {
-    // Try to resolve relative source paths.
-    let source_path = source_path.canonicalize()?;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerAddress {
+    server_name: String,
+    address: String,
+}
+
+impl PeerAddress {
+    pub fn new(server_name: String, address: String) -> Self {
+        Self {
+            server_name,
+            address,
+        }
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (server_name, address) = s
+            .split_once('@')
+            .filter(|(server_name, address)| !server_name.is_empty() && !address.is_empty())
+            .ok_or_else(|| {
+                eyre!("Invalid peer {s:?}, expected NAME@ADDRESS, like build-server@10.0.0.2:7777.")
+            })?;
+
+        Ok(Self::new(server_name.to_owned(), address.to_owned()))
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.server_name, self.address)
+    }
+}
 
-    // Create client connection configuration.
+pub fn client_config(
+    certificate_path: Option<String>,
+    identity: &DeviceIdentity,
+) -> Result<ClientConfig> {
+    // Trust the certificate the peers listen with.
     let certificate_path = certificate_filename_or_default(certificate_path);
     let certs = read_certs_from_file(certificate_path)?;
 
@@ -30,74 +62,112 @@ pub async fn connect(
         certificate_store.add(cert)?;
     }
 
-    let client_config = ClientConfig::with_root_certificates(certificate_store);
-
-    // Bind the client socket to an address.
-    let local_address = "0.0.0.0:0".parse()?;
-    let endpoint = Arc::new(Endpoint::client(local_address)?);
-
-    // Setup file watcher.
-    let (watcher_tx, mut watcher_rx) = mpsc::channel(16);
-    let mut watcher = {
-        let source_path = source_path.clone();
-
-        notify::recommended_watcher(move |res: Result<notify::Event, _>| {
-            dbg!(&res);
-            let mut event = match res {
-                Ok(event) => match event.kind {
-                    notify::EventKind::Modify(_) | notify::EventKind::Remove(_) => event,
+    // Prove which device connects with its own certificate.
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(certificate_store)
+        .with_single_cert(
+            vec![identity.certificate().clone()],
+            identity.private_key().clone(),
+        )?;
+    crypto.enable_early_data = true;
+
+    Ok(ClientConfig::new(Arc::new(crypto)))
+}
 
-                    // Don't send other kinds of events to the server.
-                    _ => return,
-                },
+pub async fn connect(
+    endpoint: Endpoint,
+    client_config: ClientConfig,
+    peer: PeerAddress,
+    node: Arc<Node>,
+) {
+    // Stay connected to the peer, backing off while it can't be reached.
+    let mut device_id = None;
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        if !node.is_dialing(&peer) {
+            return;
+        }
+
+        if device_id.as_ref() == Some(node.device_id()) {
+            warn!("Peer {peer} is this device, no longer connecting to it.");
+
+            return;
+        }
+
+        // The peer may already have connected to us.
+        if !device_id.is_some_and(|device_id| node.is_connected(&device_id)) {
+            match connect_peer(&endpoint, &client_config, &peer, &node, &mut device_id).await {
+                Ok(()) => {
+                    info!("Disconnected from {peer}.");
+
+                    delay = MIN_RECONNECT_DELAY;
+                }
                 Err(e) => {
-                    error!("Watcher error: {e:?}");
+                    warn!("Unable to sync with {peer}, retrying in {delay:?}: {e:#}");
 
-                    return;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
                 }
-            };
-
-            // Make paths relative to source path.
-            event.paths = event
-                .paths
-                .into_iter()
-                .map(|path| pathdiff::diff_paths(path, &source_path).unwrap())
-                .collect();
-
-            // Send event.
-            watcher_tx.blocking_send(event).unwrap();
-        })?
-    };
-
-    watcher.watch(&source_path, RecursiveMode::Recursive)?;
-
-    //
-    let remote_address = address.parse()?;
-    while let Some(event) = watcher_rx.recv().await {
-        // Connect to the server.
-        let connection = endpoint
-            .connect_with(client_config.clone(), remote_address, &server_name)?
-            .await?;
-        let (send, _) = connection.open_bi().await?;
-
-        // Wrap connection with codecs.
-        let mut write_framed = FramedWrite::new(send, MessageEncoder);
-        // let read_framed = FramedRead::new(recv, MessageDecoder);
-
-        // Send event message.
-        write_framed
-            .send(&Message::WatcherEvent(event))
-            .await
-            .unwrap();
-
-        write_framed.close().await.unwrap();
+            }
+        }
+
+        tokio::time::sleep(delay).await;
     }
+}
 
-    // //
-    // tokio::signal::ctrl_c().await?;
+async fn connect_peer(
+    endpoint: &Endpoint,
+    client_config: &ClientConfig,
+    peer_address: &PeerAddress,
+    node: &Node,
+    device_id: &mut Option<DeviceId>,
+) -> Result<()> {
+    let connection = dial(endpoint, client_config, peer_address).await?;
+    let peer = node.handshake(connection, true).await?;
+    *device_id = Some(*peer.device_id());
+    if !node.dialed(peer_address, *peer.device_id()) {
+        return Ok(());
+    }
 
-    // Wait for server to clean up.
-    endpoint.wait_idle().await;
+    node.run_peer(peer).await
+}
 
-    Ok(())
+pub async fn dial(
+    endpoint: &Endpoint,
+    client_config: &ClientConfig,
+    peer_address: &PeerAddress,
+) -> Result<Connection> {
+    // Resolve the address on every attempt, the peer may have moved.
+    let address = tokio::net::lookup_host(&peer_address.address)
+        .await?
+        .next()
+        .ok_or_else(|| eyre!("Unable to resolve {:?}.", peer_address.address))?;
+
+    let connection = endpoint
+        .connect_with(client_config.clone(), address, &peer_address.server_name)?
+        .await?;
+    info!("Connected to {peer_address}.");
+
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerAddress;
+
+    #[test]
+    fn peer_address() {
+        // Make sure the name on the certificate is split from the address.
+        let peer: PeerAddress = "build-server@10.0.0.2:7777".parse().unwrap();
+        assert_eq!(peer.server_name, "build-server");
+        assert_eq!(peer.address, "10.0.0.2:7777");
+        assert_eq!(peer.to_string(), "build-server@10.0.0.2:7777");
+
+        // Make sure both parts are required.
+        assert!("10.0.0.2:7777".parse::<PeerAddress>().is_err());
+        assert!("@10.0.0.2:7777".parse::<PeerAddress>().is_err());
+        assert!("build-server@".parse::<PeerAddress>().is_err());
+    }
 }
@@ -0,0 +1,559 @@
+use crate::{
+    client::PeerAddress,
+    device_id::device_id_from_string,
+    folder::FolderConfig,
+    ignore::IgnorePatterns,
+    messages::{
+        Compression, DeviceId, FolderId, FolderMode, HashAlgorithm, DEFAULT_COMPRESSION_LEVEL,
+    },
+    name_policy::{CaseSensitivity, NamePolicy, UnicodeNormalization},
+    node::Node,
+    rate_limit::{Rate, RateLimiter, RateLimits, RateSchedule},
+    session::{SessionOptions, DEFAULT_PARALLEL_TRANSFERS},
+    versioning::Versioning,
+};
+use clap::ValueEnum;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use globset::Glob;
+use notify::{RecursiveMode, Watcher};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::HashSet,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tracing::{level_filters::LevelFilter, *};
+use tracing_subscriber::{reload, Registry};
+
+const RELOAD_DELAY: Duration = Duration::from_millis(200);
+const MAX_COMPRESSION_LEVEL: i32 = 22;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    node: NodeConfig,
+
+    #[serde(default, deserialize_with = "parse_vec")]
+    listen: Vec<SocketAddr>,
+
+    #[serde(default, deserialize_with = "parse_vec")]
+    peers: Vec<PeerAddress>,
+
+    #[serde(default, deserialize_with = "parse_vec")]
+    ignore: Vec<Glob>,
+
+    #[serde(default)]
+    limits: LimitsConfig,
+
+    #[serde(default)]
+    logging: LoggingConfig,
+
+    #[serde(default)]
+    folders: Vec<FolderSection>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NodeConfig {
+    device_id_file: Option<PathBuf>,
+    certificate: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+    control_socket: Option<PathBuf>,
+
+    #[serde(deserialize_with = "choice")]
+    hash_algorithm: HashAlgorithm,
+
+    #[serde(deserialize_with = "choice")]
+    compression: Compression,
+
+    compression_level: i32,
+
+    parallel_transfers: usize,
+
+    #[serde(deserialize_with = "parse_vec")]
+    priority: Vec<Glob>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        // Same defaults as the command line.
+        Self {
+            device_id_file: None,
+            certificate: None,
+            private_key: None,
+            control_socket: None,
+            hash_algorithm: HashAlgorithm::default(),
+            compression: Compression::Zstd,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            parallel_transfers: DEFAULT_PARALLEL_TRANSFERS,
+            priority: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsConfig {
+    #[serde(deserialize_with = "parse_option")]
+    upload: Option<Rate>,
+
+    #[serde(deserialize_with = "parse_option")]
+    download: Option<Rate>,
+
+    #[serde(deserialize_with = "parse_option")]
+    peer_upload: Option<Rate>,
+
+    #[serde(deserialize_with = "parse_option")]
+    peer_download: Option<Rate>,
+
+    #[serde(deserialize_with = "parse_vec")]
+    schedules: Vec<RateSchedule>,
+}
+
+impl LimitsConfig {
+    fn limits(&self) -> RateLimits {
+        RateLimits::new(self.upload, self.download)
+    }
+
+    fn peer_limits(&self) -> RateLimits {
+        RateLimits::new(self.peer_upload, self.peer_download)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingConfig {
+    #[serde(deserialize_with = "parse")]
+    level: LevelFilter,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::INFO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FolderSection {
+    #[serde(deserialize_with = "parse")]
+    id: FolderId,
+
+    path: PathBuf,
+
+    #[serde(default, deserialize_with = "choice")]
+    mode: FolderMode,
+
+    #[serde(default, deserialize_with = "parse")]
+    versioning: Versioning,
+
+    #[serde(default, deserialize_with = "choice")]
+    unicode_normalization: UnicodeNormalization,
+
+    #[serde(default, deserialize_with = "choice")]
+    case_sensitivity: CaseSensitivity,
+
+    #[serde(default, deserialize_with = "parse_device_ids")]
+    devices: Vec<DeviceId>,
+
+    #[serde(default, deserialize_with = "parse_vec")]
+    ignore: Vec<Glob>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read configuration {path:?}."))?;
+        let mut config: Config =
+            toml::from_str(&text).wrap_err_with(|| format!("Invalid configuration {path:?}."))?;
+
+        // Relative paths are relative to the configuration, wherever the daemon is started from.
+        let base = path.parent().unwrap_or(Path::new(""));
+        config.resolve_paths(base);
+
+        config
+            .validate()
+            .wrap_err_with(|| format!("Invalid configuration {path:?}."))?;
+
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut PathBuf| *path = base.join(&*path);
+
+        let node = &mut self.node;
+        for path in [
+            &mut node.device_id_file,
+            &mut node.certificate,
+            &mut node.private_key,
+            &mut node.control_socket,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            resolve(path);
+        }
+
+        for folder in &mut self.folders {
+            resolve(&mut folder.path);
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(1..=MAX_COMPRESSION_LEVEL).contains(&self.node.compression_level) {
+            return Err(eyre!(
+                "node.compression_level must be between 1 and {MAX_COMPRESSION_LEVEL}, got {}.",
+                self.node.compression_level
+            ));
+        }
+
+        if self.node.parallel_transfers == 0 {
+            return Err(eyre!("node.parallel_transfers must be at least 1."));
+        }
+
+        if self.folders.is_empty() {
+            return Err(eyre!(
+                "No folder to synchronize, add a [[folders]] table with an id and a path."
+            ));
+        }
+
+        let mut folder_ids = HashSet::new();
+        for (i, folder) in self.folders.iter().enumerate() {
+            if !folder_ids.insert(&folder.id) {
+                return Err(eyre!(
+                    "folders[{i}]: folder {} is configured more than once.",
+                    folder.id
+                ));
+            }
+
+            if !folder.path.is_dir() {
+                return Err(eyre!(
+                    "folders[{i}]: path {:?} of folder {} is not a directory.",
+                    folder.path,
+                    folder.id
+                ));
+            }
+        }
+
+        // Patterns that only fail together are caught before anything uses them.
+        for folder_config in self.folders() {
+            IgnorePatterns::new(folder_config.ignore_patterns().to_vec()).wrap_err_with(|| {
+                format!("Invalid ignore patterns for folder {}", folder_config.id())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn device_id_filename(&self) -> Option<String> {
+        path_to_string(&self.node.device_id_file)
+    }
+
+    pub fn cert_filename(&self) -> Option<String> {
+        path_to_string(&self.node.certificate)
+    }
+
+    pub fn private_key_filename(&self) -> Option<String> {
+        path_to_string(&self.node.private_key)
+    }
+
+    pub fn control_socket(&self) -> Option<PathBuf> {
+        self.node.control_socket.clone()
+    }
+
+    pub fn listen(&self) -> &[SocketAddr] {
+        &self.listen
+    }
+
+    pub fn peers(&self) -> &[PeerAddress] {
+        &self.peers
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.logging.level
+    }
+
+    pub fn session_options(&self) -> SessionOptions {
+        let rate_limiter = RateLimiter::new(
+            self.limits.limits(),
+            self.limits.peer_limits(),
+            self.limits.schedules.clone(),
+        );
+
+        SessionOptions::new(
+            self.node.hash_algorithm,
+            self.node.compression,
+            self.node.compression_level,
+            rate_limiter,
+        )
+        .with_transfers(self.node.parallel_transfers, self.node.priority.clone())
+    }
+
+    pub fn apply_limits(&self, rate_limiter: &RateLimiter) {
+        rate_limiter.set_limits(
+            self.limits.limits(),
+            self.limits.peer_limits(),
+            self.limits.schedules.clone(),
+        );
+    }
+
+    pub fn folders(&self) -> Vec<FolderConfig> {
+        // Patterns ignored by every folder go first.
+        self.folders
+            .iter()
+            .map(|folder| {
+                let mut folder_config = FolderConfig::new(folder.id.clone(), folder.path.clone())
+                    .with_mode(folder.mode)
+                    .with_versioning(folder.versioning)
+                    .with_name_policy(NamePolicy::new(
+                        folder.unicode_normalization,
+                        folder.case_sensitivity,
+                    ))
+                    .with_ignore_patterns(&self.ignore)
+                    .with_ignore_patterns(&folder.ignore);
+                for device_id in &folder.devices {
+                    folder_config.share_with(*device_id);
+                }
+
+                folder_config
+            })
+            .collect()
+    }
+}
+
+pub async fn watch_config(
+    path: PathBuf,
+    mut config: Config,
+    node: Arc<Node>,
+    log_level: reload::Handle<LevelFilter, Registry>,
+) -> Result<()> {
+    // Editors often replace the file instead of writing it, so watch the folder holding it.
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid configuration path {path:?}."))?
+        .to_owned();
+    let (changes_tx, mut changes_rx) = mpsc::channel(16);
+    let mut watcher =
+        notify::recommended_watcher(move |res: Result<notify::Event, _>| match res {
+            Ok(event) => {
+                if event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == Some(&file_name))
+                {
+                    let _ = changes_tx.try_send(());
+                }
+            }
+            Err(e) => error!("Configuration watcher error: {e:?}"),
+        })?;
+
+    let folder = match path.parent() {
+        Some(folder) if !folder.as_os_str().is_empty() => folder,
+        _ => Path::new("."),
+    };
+    watcher.watch(folder, RecursiveMode::NonRecursive)?;
+
+    while changes_rx.recv().await.is_some() {
+        // Let the writer finish before reading the new version.
+        tokio::time::sleep(RELOAD_DELAY).await;
+        while changes_rx.try_recv().is_ok() {}
+
+        let new_config = match Config::load(&path) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                error!("Keeping the current configuration: {e:#}");
+
+                continue;
+            }
+        };
+        if new_config == config {
+            continue;
+        }
+
+        info!("Reloading configuration {path:?}.");
+        if new_config.node != config.node || new_config.listen != config.listen {
+            warn!("Changes to the node settings or listen addresses take effect after a restart.");
+        }
+
+        // A configuration the node refuses leaves the current one in place, later edits may fix it.
+        if let Err(e) = node.reload(&new_config).await {
+            error!("Keeping the current configuration: {e:#}");
+
+            continue;
+        }
+        if let Err(e) = log_level.modify(|level| *level = new_config.log_level()) {
+            error!("Unable to change the log level: {e}");
+        }
+
+        config = new_config;
+    }
+
+    Ok(())
+}
+
+fn path_to_string(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref().map(|path| path.display().to_string())
+}
+
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = String::deserialize(deserializer)?;
+
+    value
+        .parse()
+        .map_err(|e| de::Error::custom(format!("{e:#}")))
+}
+
+fn parse_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    parse(deserializer).map(Some)
+}
+
+fn parse_vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| de::Error::custom(format!("{e:#}")))
+        })
+        .collect()
+}
+
+fn parse_device_ids<'de, D>(deserializer: D) -> Result<Vec<DeviceId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| device_id_from_string(value).map_err(|e| de::Error::custom(format!("{e:#}"))))
+        .collect()
+}
+
+fn choice<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    // Accept the same names as the command line.
+    let value = String::deserialize(deserializer)?;
+    T::from_str(&value, true).map_err(|_| {
+        let names: Vec<_> = T::value_variants()
+            .iter()
+            .filter_map(ValueEnum::to_possible_value)
+            .map(|value| value.get_name().to_owned())
+            .collect();
+
+        de::Error::custom(format!(
+            "unknown value {value:?}, expected one of {}",
+            names.join(", ")
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::{
+        messages::FolderMode,
+        name_policy::{NamePolicy, UnicodeNormalization},
+        versioning::Versioning,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn config() {
+        let folder = std::env::temp_dir();
+        let text = format!(
+            r#"
+            listen = ["0.0.0.0:7777"]
+            peers = ["build-server@10.0.0.2:7777"]
+            ignore = ["*.tmp"]
+
+            [node]
+            device_id_file = "device_id"
+            compression = "lz4"
+
+            [limits]
+            upload = "2M"
+            schedules = ["22:00-06:00=-/-"]
+
+            [logging]
+            level = "debug"
+
+            [[folders]]
+            id = "projects"
+            path = {folder:?}
+            mode = "receive-only"
+            versioning = "staggered:90"
+            unicode_normalization = "nfc"
+            devices = ["{}"]
+            ignore = ["target"]
+            "#,
+            "ab".repeat(32)
+        );
+
+        // Make sure every section parses and relative paths follow the configuration.
+        let mut config: Config = toml::from_str(&text).unwrap();
+        config.resolve_paths(Path::new("/etc/entangler"));
+        config.validate().unwrap();
+        assert_eq!(config.listen().len(), 1);
+        assert_eq!(config.peers()[0].to_string(), "build-server@10.0.0.2:7777");
+        assert_eq!(
+            config.device_id_filename().as_deref(),
+            Some("/etc/entangler/device_id")
+        );
+        assert_eq!(
+            config.log_level(),
+            tracing::level_filters::LevelFilter::DEBUG
+        );
+
+        let folders = config.folders();
+        assert_eq!(folders[0].mode(), FolderMode::ReceiveOnly);
+        assert_eq!(folders[0].versioning(), Versioning::Staggered { days: 90 });
+        assert_eq!(
+            folders[0].name_policy(),
+            NamePolicy::new(UnicodeNormalization::Nfc, Default::default())
+        );
+        assert_eq!(folders[0].devices(), &[[0xab; 32]]);
+        assert_eq!(folders[0].ignore_patterns().len(), 2);
+
+        // Make sure mistakes are reported with their location.
+        let error = toml::from_str::<Config>("[limits]\nupload = \"fast\"\n").unwrap_err();
+        assert!(error.to_string().contains("line 2"));
+        assert!(toml::from_str::<Config>("[node]\ncompresion = \"lz4\"\n").is_err());
+        assert!(toml::from_str::<Config>("[node]\ncompression = \"gzip\"\n").is_err());
+
+        // Make sure invalid settings are refused.
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.validate().is_err());
+
+        let text = format!("[[folders]]\nid = \"a\"\npath = {folder:?}\n");
+        let config: Config = toml::from_str(&format!("{text}{text}")).unwrap();
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,623 @@
+use crate::{
+    messages::{FolderId, WirePath},
+    node::Node,
+    rate_limit::Direction,
+    verify::Problem,
+    versioning::Version,
+};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use clap::ValueEnum;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::Permissions,
+    future::Future,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::*;
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "entangler.sock";
+const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    Pause {
+        folder: String,
+    },
+    Resume {
+        folder: String,
+    },
+    Rescan {
+        folder: Option<String>,
+    },
+    Verify {
+        folder: Option<String>,
+        #[serde(default)]
+        peers: bool,
+        #[serde(default)]
+        repair: bool,
+    },
+    Override {
+        folder: String,
+    },
+    Revert {
+        folder: String,
+    },
+    Versions {
+        path: PathBuf,
+    },
+    Restore {
+        path: PathBuf,
+        #[serde(default, with = "unix_time")]
+        at: Option<SystemTime>,
+    },
+    Peers,
+    Transfers,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Status(Status),
+    Peers(Vec<PeerStatus>),
+    Transfers(Vec<TransferStatus>),
+    Mismatches(Vec<Mismatch>),
+    Versions(Vec<VersionStatus>),
+    Restored(VersionStatus),
+    Done,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub device_id: String,
+    pub folders: Vec<FolderStatus>,
+    pub peers: Vec<PeerStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FolderStatus {
+    pub id: String,
+    pub path: PathBuf,
+    pub mode: String,
+    pub paused: bool,
+    pub files: usize,
+    pub bytes: u64,
+    pub in_sync_files: usize,
+    pub in_sync_bytes: u64,
+    pub pending_uploads: usize,
+    pub pending_downloads: usize,
+    pub pending_comparisons: usize,
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub local_changes: Vec<String>,
+    pub errors: Vec<FileError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileError {
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub device_id: Option<String>,
+    pub address: String,
+    pub connected: bool,
+    #[serde(with = "unix_time")]
+    pub since: Option<SystemTime>,
+    #[serde(with = "unix_time")]
+    pub last_seen: Option<SystemTime>,
+    pub folders: Vec<String>,
+    pub upload_rate: u64,
+    pub download_rate: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferStatus {
+    pub device_id: String,
+    pub folder_id: String,
+    pub path: String,
+    pub direction: Option<String>,
+    #[serde(with = "unix_time")]
+    pub started: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub folder_id: String,
+    pub path: String,
+    pub problem: String,
+    pub repaired: bool,
+}
+
+impl Mismatch {
+    pub fn new(folder_id: &FolderId, path: &WirePath, problem: &Problem, repaired: bool) -> Self {
+        Self {
+            folder_id: folder_id.to_string(),
+            path: path.to_string(),
+            problem: problem.to_string(),
+            repaired,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionStatus {
+    pub folder_id: String,
+    pub path: String,
+    #[serde(with = "unix_time")]
+    pub replaced: Option<SystemTime>,
+    pub size: u64,
+    pub device_id: String,
+}
+
+impl VersionStatus {
+    pub fn new(folder_id: &FolderId, path: &WirePath, version: &Version) -> Self {
+        Self {
+            folder_id: folder_id.to_string(),
+            path: path.to_string(),
+            replaced: Some(version.time()),
+            size: version.size(),
+            device_id: version.device_id().to_owned(),
+        }
+    }
+}
+
+pub fn serve_control(path: &Path, node: Arc<Node>) -> Result<impl Future<Output = ()>> {
+    // A socket left behind by a daemon that did not shut down is taken over.
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(eyre!(
+                "Another daemon is listening on control socket {path:?}."
+            ));
+        }
+
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .wrap_err_with(|| format!("Unable to listen on control socket {path:?}."))?;
+
+    // Whoever can connect controls the daemon, so only its own user may.
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))
+        .wrap_err_with(|| format!("Unable to restrict access to control socket {path:?}."))?;
+    info!("Listening for control requests on {path:?}.");
+
+    Ok(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Unable to accept control connection: {e:?}");
+
+                    return;
+                }
+            };
+
+            let node = node.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_control_connection(stream, &node).await {
+                    warn!("Control connection failed: {e:#}");
+                }
+            });
+        }
+    })
+}
+
+async fn handle_control_connection(stream: UnixStream, node: &Node) -> Result<()> {
+    // Every line holds a request and gets a line with the response.
+    let mut framed = Framed::new(stream, LinesCodec::new_with_max_length(MAX_REQUEST_LENGTH));
+    while let Some(line) = framed.next().await {
+        let response = match serde_json::from_str::<Request>(&line?) {
+            Ok(request) => {
+                debug!("Control request: {request:?}");
+
+                handle_request(request, node)
+                    .await
+                    .unwrap_or_else(|e| Response::Error(format!("{e:#}")))
+            }
+            Err(e) => Response::Error(format!("Invalid request: {e}")),
+        };
+
+        framed.send(serde_json::to_string(&response)?).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_request(request: Request, node: &Node) -> Result<Response> {
+    match request {
+        Request::Status => Ok(Response::Status(node.status().await)),
+        Request::Pause { folder } => {
+            node.pause(&folder.parse()?).await?;
+
+            Ok(Response::Done)
+        }
+        Request::Resume { folder } => {
+            node.resume(&folder.parse()?).await?;
+
+            Ok(Response::Done)
+        }
+        Request::Rescan { folder } => {
+            let folder_ids: Vec<FolderId> = match folder {
+                Some(folder) => vec![folder.parse()?],
+                None => node.folder_ids().cloned().collect(),
+            };
+            for folder_id in &folder_ids {
+                node.rescan(folder_id).await?;
+            }
+
+            Ok(Response::Done)
+        }
+        Request::Verify {
+            folder,
+            peers,
+            repair,
+        } => {
+            let folder_ids: Vec<FolderId> = match folder {
+                Some(folder) => vec![folder.parse()?],
+                None => node.folder_ids().cloned().collect(),
+            };
+            let mut mismatches = Vec::new();
+            for folder_id in &folder_ids {
+                mismatches.extend(node.verify(folder_id, peers, repair).await?);
+            }
+
+            Ok(Response::Mismatches(mismatches))
+        }
+        Request::Override { folder } => {
+            node.override_peers(&folder.parse()?).await?;
+
+            Ok(Response::Done)
+        }
+        Request::Revert { folder } => {
+            node.revert_local_changes(&folder.parse()?).await?;
+
+            Ok(Response::Done)
+        }
+        Request::Versions { path } => Ok(Response::Versions(node.versions(&path).await?)),
+        Request::Restore { path, at } => Ok(Response::Restored(node.restore(&path, at).await?)),
+        Request::Peers => Ok(Response::Peers(node.peer_statuses())),
+        Request::Transfers => Ok(Response::Transfers(node.transfer_statuses())),
+        Request::Shutdown => {
+            node.shutdown();
+
+            Ok(Response::Done)
+        }
+    }
+}
+
+pub async fn send_request(path: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(path).await.wrap_err_with(|| {
+        format!("Unable to reach the daemon on control socket {path:?}, is it running with --control-socket?")
+    })?;
+
+    let mut framed = Framed::new(stream, LinesCodec::new());
+    framed.send(serde_json::to_string(request)?).await?;
+    let line = framed
+        .next()
+        .await
+        .ok_or_else(|| eyre!("The daemon closed the control connection without a response."))??;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+pub fn print_response(response: Response, json: bool) -> Result<()> {
+    // Scripts get the response as the daemon sent it.
+    if json {
+        let failed = matches!(response, Response::Error(_));
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        if failed {
+            return Err(eyre!("The daemon refused the request."));
+        }
+
+        return Ok(());
+    }
+
+    match response {
+        Response::Status(status) => {
+            println!("Device {}", status.device_id);
+            for folder in &status.folders {
+                print_folder_status(folder);
+            }
+
+            println!();
+            print_peer_statuses(&status.peers);
+        }
+        Response::Peers(peers) => print_peer_statuses(&peers),
+        Response::Transfers(transfers) => {
+            for transfer in transfers {
+                let started = transfer.started.map(format_time).unwrap_or_default();
+                let direction = match transfer.direction.as_deref() {
+                    Some("upload") => "to",
+                    Some("download") => "from",
+                    _ => "with",
+                };
+                println!(
+                    "{} in folder {} {direction} {}, started {started}",
+                    transfer.path, transfer.folder_id, transfer.device_id
+                );
+            }
+        }
+        Response::Mismatches(mismatches) => {
+            for mismatch in &mismatches {
+                let repaired = if mismatch.repaired { ", repaired" } else { "" };
+                println!(
+                    "{} in folder {}: {}{repaired}",
+                    mismatch.path, mismatch.folder_id, mismatch.problem
+                );
+            }
+
+            // Checks run from scripts fail while anything is left to fix.
+            let unrepaired = mismatches
+                .iter()
+                .filter(|mismatch| !mismatch.repaired)
+                .count();
+            if unrepaired > 0 {
+                return Err(eyre!("{unrepaired} files did not verify."));
+            }
+
+            println!("Every file matches the index.");
+        }
+        Response::Versions(versions) => {
+            let Some(first) = versions.first() else {
+                println!("No versions of this file are kept.");
+
+                return Ok(());
+            };
+
+            println!("{} in folder {}", first.path, first.folder_id);
+            for version in &versions {
+                println!(
+                    "  {}  {}  replaced by device {}",
+                    version.replaced.map(format_time).unwrap_or_default(),
+                    format_bytes(version.size),
+                    version.device_id
+                );
+            }
+        }
+        Response::Restored(version) => println!(
+            "Restored {} in folder {} as it was before device {} replaced it on {}.",
+            version.path,
+            version.folder_id,
+            version.device_id,
+            version.replaced.map(format_time).unwrap_or_default()
+        ),
+        Response::Done => {}
+        Response::Error(message) => return Err(eyre!(message)),
+    }
+
+    Ok(())
+}
+
+fn print_folder_status(folder: &FolderStatus) {
+    let paused = if folder.paused { ", paused" } else { "" };
+    println!(
+        "Folder {} at {:?} ({}{paused})",
+        folder.id, folder.path, folder.mode
+    );
+    println!(
+        "  In sync: {} of {} files, {} of {}",
+        folder.in_sync_files,
+        folder.files,
+        format_bytes(folder.in_sync_bytes),
+        format_bytes(folder.bytes)
+    );
+    println!(
+        "  Pending: {} uploads, {} downloads, {} files to compare",
+        folder.pending_uploads, folder.pending_downloads, folder.pending_comparisons
+    );
+
+    println!("  Conflicts: {}", folder.conflicts.len());
+    for path in &folder.conflicts {
+        println!("    {path}");
+    }
+
+    // Only receive only folders have changes they keep to themselves.
+    if !folder.local_changes.is_empty() {
+        println!("  Local changes: {}", folder.local_changes.len());
+        for path in &folder.local_changes {
+            println!("    {path}");
+        }
+    }
+
+    println!("  Errors: {}", folder.errors.len());
+    for error in &folder.errors {
+        println!("    {}: {}", error.path, error.error);
+    }
+}
+
+fn print_peer_statuses(peers: &[PeerStatus]) {
+    for peer in peers {
+        let device_id = peer.device_id.as_deref().unwrap_or("unknown device");
+        match peer.since {
+            Some(since) if peer.connected => println!(
+                "{device_id} at {}, connected since {}, sharing {}, sending {}/s, receiving {}/s",
+                peer.address,
+                format_time(since),
+                peer.folders.join(", "),
+                format_bytes(peer.upload_rate),
+                format_bytes(peer.download_rate)
+            ),
+            _ => match peer.last_seen {
+                Some(last_seen) => println!(
+                    "{device_id} at {}, last seen {}",
+                    peer.address,
+                    format_time(last_seen)
+                ),
+                None => println!("{device_id} at {}, never seen", peer.address),
+            },
+        }
+    }
+}
+
+pub fn value_name<T: ValueEnum>(value: T) -> String {
+    // Report values with the names the command line takes.
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+pub fn direction_name(direction: Direction) -> String {
+    match direction {
+        Direction::Upload => "upload",
+        Direction::Download => "download",
+    }
+    .to_owned()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    // Same suffixes as the rate limits take.
+    const UNITS: &[&str] = &["K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = None;
+    for next_unit in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+
+        value /= 1024.0;
+        unit = Some(next_unit);
+    }
+
+    match unit {
+        Some(unit) => format!("{value:.1}{unit}"),
+        None => format!("{bytes} bytes"),
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Local>::from(time)
+        .format(TIME_FORMAT)
+        .to_string()
+}
+
+pub fn parse_time(s: &str) -> Result<SystemTime> {
+    // Take times like they are printed, seconds and the time of day may be left out.
+    let time = NaiveDateTime::parse_from_str(s, TIME_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|date| date.and_time(Default::default()))
+        })
+        .map_err(|_| {
+            eyre!("Invalid time {s:?}, expected YYYY-MM-DD HH:MM:SS, like \"2026-10-18 14:30:00\".")
+        })?;
+
+    time.and_local_timezone(Local)
+        .earliest()
+        .map(Into::into)
+        .ok_or_else(|| eyre!("Time {s:?} does not exist in the local time zone."))
+}
+
+mod unix_time {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    // Times travel as seconds since the Unix epoch, which scripts handle easily.
+    pub fn serialize<S: Serializer>(
+        time: &Option<SystemTime>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        time.map(|time| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        })
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<SystemTime>, D::Error> {
+        let seconds = Option::<u64>::deserialize(deserializer)?;
+
+        Ok(seconds.map(|seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_bytes, format_time, parse_time, PeerStatus, Request, Response};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn control_messages() {
+        // Make sure requests read like the documented JSON.
+        let request: Request =
+            serde_json::from_str(r#"{"command": "pause", "folder": "docs"}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Pause {
+                folder: "docs".to_owned()
+            }
+        );
+        let request: Request = serde_json::from_str(r#"{"command": "rescan"}"#).unwrap();
+        assert_eq!(request, Request::Rescan { folder: None });
+        assert!(serde_json::from_str::<Request>(r#"{"command": "reboot"}"#).is_err());
+        let request: Request =
+            serde_json::from_str(r#"{"command": "override", "folder": "docs"}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Override {
+                folder: "docs".to_owned()
+            }
+        );
+        let request: Request =
+            serde_json::from_str(r#"{"command": "restore", "path": "/srv/docs/notes.txt"}"#)
+                .unwrap();
+        assert_eq!(
+            request,
+            Request::Restore {
+                path: "/srv/docs/notes.txt".into(),
+                at: None
+            }
+        );
+
+        // Make sure responses survive the round trip, with times in seconds.
+        let response = Response::Peers(vec![PeerStatus {
+            device_id: None,
+            address: "build@10.0.0.2:7777".to_owned(),
+            connected: true,
+            since: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            last_seen: None,
+            folders: vec!["docs".to_owned()],
+            upload_rate: 0,
+            download_rate: 2048,
+        }]);
+        let text = serde_json::to_string(&response).unwrap();
+        assert!(text.contains(r#""since":1700000000"#));
+        assert_eq!(serde_json::from_str::<Response>(&text).unwrap(), response);
+
+        assert_eq!(serde_json::to_string(&Response::Done).unwrap(), r#""done""#);
+
+        // Make sure sizes read like the rate limits.
+        assert_eq!(format_bytes(512), "512 bytes");
+        assert_eq!(format_bytes(1536), "1.5K");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0G");
+
+        // Make sure times are taken like they are printed.
+        let time = parse_time("2026-10-18 14:30:05").unwrap();
+        assert_eq!(format_time(time), "2026-10-18 14:30:05");
+        assert_eq!(
+            format_time(parse_time("2026-10-18").unwrap()),
+            "2026-10-18 00:00:00"
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
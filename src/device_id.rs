@@ -0,0 +1,116 @@
+use crate::messages::DeviceId;
+use color_eyre::eyre::{eyre, Result};
+use quinn::Connection;
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+const DEVICE_CERTIFICATE_NAME: &str = "entangler";
+
+#[derive(Debug, Clone)]
+pub struct DeviceIdentity {
+    certificate: Certificate,
+    private_key: PrivateKey,
+}
+
+impl DeviceIdentity {
+    pub fn device_id(&self) -> DeviceId {
+        device_id_from_certificate(&self.certificate)
+    }
+
+    pub fn certificate(&self) -> &Certificate {
+        &self.certificate
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+}
+
+pub fn device_id_filename_or_default(device_id_filename: Option<String>) -> String {
+    device_id_filename.unwrap_or_else(|| "device_id".to_string())
+}
+
+pub async fn read_or_generate_device_identity<P>(device_id_path: P) -> Result<DeviceIdentity>
+where
+    P: AsRef<Path>,
+{
+    let device_id_path = device_id_path.as_ref();
+
+    // Generate a new identity the first time this device runs, only this user may read its key.
+    if !device_id_path.exists() {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec![DEVICE_CERTIFICATE_NAME.to_owned()])?;
+        let pem = certificate.serialize_pem()? + &certificate.serialize_private_key_pem();
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(device_id_path)
+            .await?
+            .write_all(pem.as_bytes())
+            .await?;
+    }
+
+    // Parse the stored certificate and its key.
+    let pem = tokio::fs::read(device_id_path).await?;
+    let mut certificate = None;
+    let mut private_key = None;
+    for item in rustls_pemfile::read_all(&mut pem.as_slice())? {
+        match item {
+            Item::X509Certificate(der) => certificate = Some(Certificate(der)),
+            Item::PKCS8Key(der) => private_key = Some(PrivateKey(der)),
+            _ => {}
+        }
+    }
+
+    match (certificate, private_key) {
+        (Some(certificate), Some(private_key)) => Ok(DeviceIdentity {
+            certificate,
+            private_key,
+        }),
+        _ => Err(eyre!(
+            "Invalid device identity in {device_id_path:?}, expected a certificate and its private key. Remove the file to generate a new identity."
+        )),
+    }
+}
+
+pub fn device_id_from_certificate(certificate: &Certificate) -> DeviceId {
+    // A device is named after its certificate, which only the holder of the key can present.
+    Sha256::digest(&certificate.0).into()
+}
+
+pub fn peer_device_id(connection: &Connection) -> Result<DeviceId> {
+    let certificates = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok())
+        .ok_or_else(|| eyre!("Peer did not present a certificate."))?;
+    let certificate = certificates
+        .first()
+        .ok_or_else(|| eyre!("Peer did not present a certificate."))?;
+
+    Ok(device_id_from_certificate(certificate))
+}
+
+pub fn device_id_from_string(device_id: &str) -> Result<DeviceId> {
+    if device_id.len() != 2 * std::mem::size_of::<DeviceId>() || !device_id.is_ascii() {
+        return Err(eyre!(
+            "Invalid device id {device_id:?}, expected {} hexadecimal digits.",
+            2 * std::mem::size_of::<DeviceId>()
+        ));
+    }
+
+    let mut parsed_device_id = DeviceId::default();
+    for (index, byte) in parsed_device_id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&device_id[index * 2..index * 2 + 2], 16)
+            .map_err(|e| eyre!("Invalid device id {device_id:?}: {e}"))?;
+    }
+
+    Ok(parsed_device_id)
+}
+
+pub fn device_id_to_string(device_id: &DeviceId) -> String {
+    device_id.iter().map(|byte| format!("{byte:02x}")).collect()
+}
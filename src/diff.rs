@@ -0,0 +1,205 @@
+use crate::{
+    control::{direction_name, format_bytes},
+    directory_sync::{start_directory_sync, Differences},
+    file_sync::{is_conflict, local_file_info, request_file_info, sync_direction},
+    index::Index,
+    messages::{BlockInfo, FolderId, WirePath},
+    node::open_folder_stream,
+    outcome::is_peer_error,
+    rate_limit::Direction,
+    session::Session,
+};
+use color_eyre::Result;
+use futures::SinkExt;
+use quinn::Connection;
+use std::{cmp::Ordering, collections::HashSet};
+use tokio::sync::RwLock;
+
+#[derive(Debug)]
+pub struct FileDiff {
+    path: WirePath,
+    direction: Direction,
+    bytes: u64,
+    conflict: bool,
+}
+
+#[derive(Debug)]
+pub struct FolderDiff {
+    folder_id: FolderId,
+    files: Vec<FileDiff>,
+    local_removals: Vec<WirePath>,
+    remote_removals: Vec<WirePath>,
+    held_back: Vec<WirePath>,
+    failures: Vec<(WirePath, String)>,
+}
+
+impl FolderDiff {
+    fn bytes(&self, direction: Direction) -> (usize, u64) {
+        let files = self.files.iter().filter(|file| file.direction == direction);
+        files.fold((0, 0), |(count, bytes), file| {
+            (count + 1, bytes + file.bytes)
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+            && self.local_removals.is_empty()
+            && self.remote_removals.is_empty()
+            && self.held_back.is_empty()
+            && self.failures.is_empty()
+    }
+}
+
+pub async fn diff_folder(
+    folder_id: &FolderId,
+    index: &RwLock<Index>,
+    connection: &Connection,
+    session: &Session,
+) -> Result<FolderDiff> {
+    // Walk both folders like a sync would, without transferring or removing anything.
+    let (mut write_framed, mut read_framed) = open_folder_stream(connection, folder_id).await?;
+    let mut differences = Differences::default();
+    start_directory_sync(
+        &WirePath::default(),
+        &mut write_framed,
+        &mut read_framed,
+        index,
+        session,
+        &mut differences,
+    )
+    .await?;
+
+    let mut folder_diff = FolderDiff {
+        folder_id: folder_id.clone(),
+        files: Vec::new(),
+        local_removals: differences.local_removals().to_vec(),
+        remote_removals: differences.remote_removals().to_vec(),
+        held_back: Vec::new(),
+        failures: Vec::new(),
+    };
+
+    // Ask the peer about every file that differs and decide which way it would go.
+    let mode = index.read().await.mode();
+    let remote_mode = session.folder_mode(folder_id);
+    for (path, _) in differences.files() {
+        let (received_file_info, received_block_infos) = match request_file_info(
+            path,
+            session.hash_algorithm(),
+            &mut write_framed,
+            &mut read_framed,
+        )
+        .await
+        {
+            Ok(received) => received,
+            Err(e) if is_peer_error(&e) => {
+                folder_diff.failures.push((path.clone(), format!("{e:#}")));
+
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let (file_info, block_infos) =
+            match local_file_info(path, session.hash_algorithm(), index).await {
+                Ok(local) => local,
+                Err(e) => {
+                    folder_diff.failures.push((path.clone(), format!("{e:#}")));
+
+                    continue;
+                }
+            };
+
+        if file_info.has_same_content(&received_file_info) {
+            continue;
+        }
+
+        let conflict = is_conflict(&file_info, &received_file_info);
+        match sync_direction(&file_info, &received_file_info, mode, remote_mode) {
+            Ordering::Greater if file_info.is_removed() => {
+                folder_diff.remote_removals.push(path.clone())
+            }
+            Ordering::Less if received_file_info.is_removed() => {
+                folder_diff.local_removals.push(path.clone())
+            }
+            Ordering::Greater => folder_diff.files.push(FileDiff {
+                path: path.clone(),
+                direction: Direction::Upload,
+                bytes: changed_bytes(&block_infos, &received_block_infos),
+                conflict,
+            }),
+            Ordering::Less => folder_diff.files.push(FileDiff {
+                path: path.clone(),
+                direction: Direction::Download,
+                bytes: changed_bytes(&received_block_infos, &block_infos),
+                conflict,
+            }),
+            Ordering::Equal => folder_diff.held_back.push(path.clone()),
+        }
+    }
+
+    write_framed.close().await?;
+
+    Ok(folder_diff)
+}
+
+fn changed_bytes(block_infos: &[BlockInfo], other_block_infos: &[BlockInfo]) -> u64 {
+    // Blocks the other side already has anywhere in the file are not sent again.
+    let hashes: HashSet<_> = other_block_infos
+        .iter()
+        .map(|block_info| block_info.hash())
+        .collect();
+
+    block_infos
+        .iter()
+        .filter(|block_info| !hashes.contains(block_info.hash()))
+        .map(|block_info| block_info.block_size() as u64)
+        .sum()
+}
+
+pub fn print_folder_diff(folder_diff: &FolderDiff) {
+    if folder_diff.is_empty() {
+        println!("Folder {} is in sync.", folder_diff.folder_id);
+
+        return;
+    }
+
+    println!("Folder {}", folder_diff.folder_id);
+    for file in &folder_diff.files {
+        let conflict = if file.conflict { ", conflict" } else { "" };
+        println!(
+            "  {} {} ({}{conflict})",
+            direction_name(file.direction),
+            file.path,
+            format_bytes(file.bytes)
+        );
+    }
+
+    for path in &folder_diff.local_removals {
+        println!("  delete {path}");
+    }
+
+    for path in &folder_diff.remote_removals {
+        println!("  delete on peer {path}");
+    }
+
+    for path in &folder_diff.held_back {
+        println!("  hold back {path}, the folder modes keep it from syncing");
+    }
+
+    for (path, error) in &folder_diff.failures {
+        println!("  skip {path}: {error}");
+    }
+
+    let (uploads, upload_bytes) = folder_diff.bytes(Direction::Upload);
+    let (downloads, download_bytes) = folder_diff.bytes(Direction::Download);
+    println!(
+        "  {uploads} uploads ({}), {downloads} downloads ({}), {} deletions, {} conflicts",
+        format_bytes(upload_bytes),
+        format_bytes(download_bytes),
+        folder_diff.local_removals.len() + folder_diff.remote_removals.len(),
+        folder_diff
+            .files
+            .iter()
+            .filter(|file| file.conflict)
+            .count()
+    );
+}
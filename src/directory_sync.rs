@@ -0,0 +1,234 @@
+use crate::{
+    index::Index,
+    messages::{
+        DirectoryEntry, DirectoryEntryKind, DirectoryInfo, FileInfo, FolderMode, HashAlgorithm,
+        Message, MessageDecoder, MessageEncoder, WirePath,
+    },
+    outcome::receive_message,
+    rate_limit::Direction,
+    session::Session,
+};
+use color_eyre::{eyre::eyre, Result};
+use futures::SinkExt;
+use quinn::{RecvStream, SendStream};
+use rayon::prelude::*;
+use std::{collections::BTreeMap, ffi::OsString};
+use tokio::sync::RwLock;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::*;
+
+#[derive(Debug, Default)]
+pub struct Differences {
+    files: Vec<(WirePath, Option<Direction>)>,
+    local_removals: Vec<WirePath>,
+    remote_removals: Vec<WirePath>,
+}
+
+impl Differences {
+    pub fn files(&self) -> &[(WirePath, Option<Direction>)] {
+        &self.files
+    }
+
+    pub fn local_removals(&self) -> &[WirePath] {
+        &self.local_removals
+    }
+
+    pub fn remote_removals(&self) -> &[WirePath] {
+        &self.remote_removals
+    }
+}
+
+pub async fn start_directory_sync(
+    path: &WirePath,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+    differences: &mut Differences,
+) -> Result<()> {
+    // Compare directories with the hash algorithm of the session, hashing what the folder lacks.
+    if path.depth() == 0 {
+        prepare_digests(index, session.hash_algorithm()).await?;
+    }
+
+    // Send directory info.
+    let directory_info = index
+        .read()
+        .await
+        .directory_info(path, session.hash_algorithm());
+    debug!("Sending directory information: {directory_info:?}");
+
+    write_framed
+        .send(&Message::DirectoryInfo(directory_info.clone()))
+        .await?;
+
+    // Receive directory info.
+    let Message::DirectoryInfo(received_directory_info) = receive_message(read_framed).await?
+    else {
+        return Err(eyre!("Did not receive directory info."));
+    };
+    debug!("Received directory information: {received_directory_info:?}");
+
+    // Skip the whole subtree when both hashes match.
+    if directory_info.has_same_content(&received_directory_info) {
+        info!("Directory {path:?} is already in sync.");
+
+        return Ok(());
+    }
+
+    // Pair local and remote entries by name, as this folder would store the remote names.
+    let (name_policy, mode) = {
+        let index = index.read().await;
+        (index.name_policy(), index.mode())
+    };
+    let remote_mode = session.folder_mode(index.read().await.folder_id());
+    let mut entries: BTreeMap<OsString, (Option<&DirectoryEntry>, Option<&DirectoryEntry>)> =
+        BTreeMap::new();
+    for entry in directory_info.entries() {
+        entries.entry(entry.name().to_owned()).or_default().0 = Some(entry);
+    }
+    for entry in received_directory_info.entries() {
+        entries
+            .entry(name_policy.local_name(entry.name()))
+            .or_default()
+            .1 = Some(entry);
+    }
+
+    // Only descend into the entries that differ.
+    for (name, (entry, received_entry)) in entries {
+        let entry_path = path.join(&name)?;
+
+        // Ignored files are neither sent nor received.
+        if index.read().await.is_ignored(&entry_path) {
+            continue;
+        }
+
+        // Never rename behind the back of the user, skip what the peer can't store.
+        if !session.can_represent(&entry_path) {
+            warn!("Skipping {entry_path:?}, the peer can't represent names that are not unicode.");
+
+            continue;
+        }
+
+        // Files only one side has go the other way, unless the other side removed them later, which is settled when they are compared.
+        let (kind, direction) = match (entry, received_entry) {
+            (Some(entry), Some(received_entry)) => {
+                if entry.kind() != received_entry.kind() {
+                    warn!("Skipping {entry_path:?}, it is a file on one side and a folder on the other.");
+
+                    continue;
+                }
+
+                if entry.hash() == received_entry.hash() {
+                    continue;
+                }
+
+                (entry.kind(), None)
+            }
+            // A mirror leaves nothing behind that the mirrored folder doesn't have.
+            (Some(_), None) if remote_mode == FolderMode::Mirror && mode.receives() => {
+                differences.local_removals.push(entry_path);
+
+                continue;
+            }
+            (None, Some(_)) if mode == FolderMode::Mirror && remote_mode.receives() => {
+                differences.remote_removals.push(entry_path);
+
+                continue;
+            }
+            (Some(entry), None) => (entry.kind(), Some(Direction::Upload)),
+            (None, Some(entry)) => (entry.kind(), Some(Direction::Download)),
+            (None, None) => continue,
+        };
+
+        match kind {
+            DirectoryEntryKind::File => differences.files.push((entry_path, direction)),
+            DirectoryEntryKind::Directory => {
+                Box::pin(start_directory_sync(
+                    &entry_path,
+                    write_framed,
+                    read_framed,
+                    index,
+                    session,
+                    differences,
+                ))
+                .await?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn handle_directory_sync(
+    received_directory_info: &DirectoryInfo,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    index: &RwLock<Index>,
+) -> Result<()> {
+    debug!("Received directory information: {received_directory_info:?}");
+
+    // Hash like the peer did, the root is asked for first.
+    let hash_algorithm = received_directory_info.hash_algorithm();
+    if received_directory_info.path().depth() == 0 {
+        prepare_digests(index, hash_algorithm).await?;
+    }
+
+    // Reply with our view of the same directory. The peer drives the rest of the reconciliation.
+    let directory_info = {
+        let index = index.read().await;
+        let path = index
+            .name_policy()
+            .local_path(received_directory_info.path())?;
+
+        index.directory_info(&path, hash_algorithm)
+    };
+    debug!("Sending directory information: {directory_info:?}");
+
+    write_framed
+        .send(&Message::DirectoryInfo(directory_info))
+        .await?;
+
+    Ok(())
+}
+
+async fn prepare_digests(index: &RwLock<Index>, hash_algorithm: HashAlgorithm) -> Result<()> {
+    let (root, file_infos) = {
+        let index = index.read().await;
+        (
+            index.root().to_owned(),
+            index.missing_digests(hash_algorithm),
+        )
+    };
+    if file_infos.is_empty() {
+        return Ok(());
+    }
+
+    // Hash the files outside of the index so transfers go on meanwhile.
+    info!(
+        "Hashing {} files with {hash_algorithm:?} to compare directories.",
+        file_infos.len()
+    );
+    let file_digests = tokio::task::spawn_blocking(move || {
+        file_infos
+            .into_par_iter()
+            .filter_map(|file_info| {
+                let local_path = file_info.path().to_local(&root);
+                match FileInfo::with_blocks(&local_path, file_info.path().clone(), hash_algorithm) {
+                    Ok((digest, _)) => Some((file_info, digest.content_hash())),
+                    Err(e) => {
+                        debug!("Fail to hash file {local_path:?}: {e:?}");
+
+                        None
+                    }
+                }
+            })
+            .collect()
+    })
+    .await?;
+    index
+        .write()
+        .await
+        .add_digests(hash_algorithm, file_digests);
+
+    Ok(())
+}
@@ -1,25 +1,102 @@
 use crate::{
-    messages::{BlockInfo, FileInfo, Message, MessageDecoder, MessageEncoder},
+    folder::transfer_direction,
+    index::Index,
+    merkle_tree::MerkleTree,
+    messages::{
+        BlockData, BlockInfo, BlockRequest, Compression, Error, ErrorCode, FileInfo,
+        FileInfoRequest, FolderMode, HashAlgorithm, Message, MessageDecoder, MessageEncoder,
+        WirePath,
+    },
+    node::{open_folder_stream, ChangeKind},
+    outcome::{is_peer_error, receive_message, receive_outcome, send_outcome},
     path_id_cache::PathIdCache,
+    rate_limit::Direction,
+    removal::apply_removal,
+    session::Session,
+    versioning::keep_versions,
 };
 use color_eyre::{eyre::eyre, Result};
-use futures::{SinkExt, TryStreamExt};
-use quinn::{RecvStream, SendStream};
-use std::path::Path;
+use futures::SinkExt;
+use quinn::{Connection, RecvStream, SendStream};
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::{
     fs::File,
-    io::{AsyncReadExt, BufReader},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::RwLock,
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::*;
 
+const MAX_SYNC_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
 pub async fn start_file_sync(
-    path: impl AsRef<Path>,
+    path: &WirePath,
     write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
     read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
-) -> Result<()> {
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<Option<Direction>> {
+    if !session.can_represent(path) {
+        return Err(eyre!(
+            "The peer can't represent names that are not unicode."
+        ));
+    }
+
+    let folder_id = index.read().await.folder_id().clone();
+    let _transfer = session.start_transfer(folder_id, path.clone());
+
+    // Retry transient failures, every attempt ends with an outcome so the stream stays usable.
+    let mut attempt = 1;
+    loop {
+        match exchange_file_sync(path, write_framed, read_framed, index, session).await {
+            Err(e) if attempt < MAX_SYNC_ATTEMPTS && ErrorCode::from_report(&e).is_transient() => {
+                warn!("Attempt {attempt} to sync file {path:?} failed, retrying: {e:#}");
+
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub async fn transfer_file(
+    path: &WirePath,
+    connection: &Connection,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<Option<Direction>> {
+    // Give every transfer a stream of its own so large files don't hold back the rest.
+    let folder_id = index.read().await.folder_id().clone();
+    let (mut write_framed, mut read_framed) = open_folder_stream(connection, &folder_id).await?;
+
+    let direction =
+        start_file_sync(path, &mut write_framed, &mut read_framed, index, session).await?;
+    write_framed.close().await?;
+
+    Ok(direction)
+}
+
+async fn exchange_file_sync(
+    path: &WirePath,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<Option<Direction>> {
+    // Refuse to touch a file that another name already stands for.
+    check_name_collision(path, index).await?;
+
     // Send file info.
-    let file_info = FileInfo::with_file(path)?;
+    let path_id = PathIdCache::calculate_path_id(path);
+    let (file_info, block_infos) = local_file_info(path, session.hash_algorithm(), index).await?;
     info!("Sending file information: {file_info:?}");
 
     write_framed
@@ -27,42 +104,418 @@ pub async fn start_file_sync(
         .await?;
 
     // Receive file info.
-    let Some(Message::FileInfo(received_file_info)) = read_framed.try_next().await? else {
-       return Err(eyre!("Did not receive file info.")) ;
+    let result = match receive_message(read_framed).await {
+        Ok(Message::FileInfo(received_file_info)) => {
+            info!("Received file information: {received_file_info:?}");
+
+            sync_file(
+                &file_info,
+                &block_infos,
+                &received_file_info,
+                write_framed,
+                read_framed,
+                index,
+                session,
+            )
+            .await
+        }
+        Ok(message) => Err(eyre!("Did not receive file info, got: {message:?}")),
+        Err(e) => Err(e),
     };
+
+    match result {
+        // The peer always reports whether the file made it.
+        Ok(direction) => {
+            receive_outcome(&path_id, read_framed).await?;
+
+            Ok(direction)
+        }
+
+        // The error from the peer already was its outcome.
+        Err(e) if is_peer_error(&e) => Err(e),
+
+        // Tell the peer to stop and wait for it to wind down before reusing the stream.
+        Err(e) => {
+            write_framed
+                .send(&Message::Error(Error::from_report(path_id, &e)))
+                .await?;
+            if let Err(outcome) = receive_outcome(&path_id, read_framed).await {
+                debug!("Peer aborted sync of file {path:?}: {outcome:#}");
+            }
+
+            Err(e)
+        }
+    }
+}
+
+pub async fn handle_file_sync(
+    received_file_info: &FileInfo,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<()> {
+    // Always report how the sync ended so the peer knows whether the file made it.
+    let result = reply_file_sync(
+        received_file_info,
+        write_framed,
+        read_framed,
+        index,
+        session,
+    )
+    .await;
+    let path_id = PathIdCache::calculate_path_id(received_file_info.path());
+    send_outcome(&path_id, &result, write_framed).await?;
+
+    result
+}
+
+async fn reply_file_sync(
+    received_file_info: &FileInfo,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<()> {
     info!("Received file information: {received_file_info:?}");
 
-    // Check if we should send or receive the file based on modification date.
-    if file_info.last_modified() > received_file_info.last_modified() {
-        send_file_blocks(&file_info, write_framed).await?;
-    } else if file_info.last_modified() < received_file_info.last_modified() {
-        receive_file_blocks().await?;
+    // Name the file like this folder stores it and refuse to overwrite a colliding name.
+    let path = &index
+        .read()
+        .await
+        .name_policy()
+        .local_path(received_file_info.path())?;
+    if index.read().await.is_ignored(path) {
+        return Err(eyre!("File {path:?} is ignored by the peer."));
     }
+    check_paused(index).await?;
+    check_name_collision(path, index).await?;
+
+    let folder_id = index.read().await.folder_id().clone();
+    let _transfer = session.start_transfer(folder_id, path.clone());
+
+    // Send our file info, hashed like the peer did so both merkle trees can be compared.
+    let (file_info, block_infos) =
+        local_file_info(path, received_file_info.hash_algorithm(), index).await?;
+    info!("Sending file information: {file_info:?}");
+
+    write_framed
+        .send(&Message::FileInfo(file_info.clone()))
+        .await?;
+
+    sync_file(
+        &file_info,
+        &block_infos,
+        received_file_info,
+        write_framed,
+        read_framed,
+        index,
+        session,
+    )
+    .await?;
 
     Ok(())
 }
 
-pub async fn handle_file_sync(file_info: &FileInfo) -> Result<()> {
-    todo!();
+pub async fn handle_file_info_request(
+    request: &FileInfoRequest,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    index: &RwLock<Index>,
+) -> Result<()> {
+    // The peer only wants to look, so failures are reported without ending the stream.
+    let result = reply_file_info(request, write_framed, index).await;
+    if let Err(e) = &result {
+        let path_id = PathIdCache::calculate_path_id(request.path());
+        write_framed
+            .send(&Message::Error(Error::from_report(path_id, e)))
+            .await?;
+    }
+
+    result
+}
+
+async fn reply_file_info(
+    request: &FileInfoRequest,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    index: &RwLock<Index>,
+) -> Result<()> {
+    // Describe the file like a sync would, without touching it.
+    let path = &index
+        .read()
+        .await
+        .name_policy()
+        .local_path(request.path())?;
+    if index.read().await.is_ignored(path) {
+        return Err(eyre!("File {path:?} is ignored by the peer."));
+    }
+
+    let (file_info, block_infos) = local_file_info(path, request.hash_algorithm(), index).await?;
+    write_framed.send(&Message::FileInfo(file_info)).await?;
+    for block_info in block_infos {
+        write_framed.send(&Message::BlockInfo(block_info)).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn request_file_info(
+    path: &WirePath,
+    hash_algorithm: HashAlgorithm,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+) -> Result<(FileInfo, Vec<BlockInfo>)> {
+    let request = FileInfoRequest::new(path.clone(), hash_algorithm);
+    write_framed
+        .send(&Message::FileInfoRequest(request))
+        .await?;
+
+    let Message::FileInfo(file_info) = receive_message(read_framed).await? else {
+        return Err(eyre!("Did not receive file info."));
+    };
+
+    let mut block_infos = Vec::new();
+    for _ in 0..file_info.number_blocks() {
+        let Message::BlockInfo(block_info) = receive_message(read_framed).await? else {
+            return Err(eyre!("Did not receive block info."));
+        };
+
+        block_infos.push(block_info);
+    }
+
+    Ok((file_info, block_infos))
+}
+
+pub async fn handle_block_request(
+    request: &BlockRequest,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<()> {
+    // Like file info requests, failures are reported without ending the stream.
+    let result = send_requested_blocks(request, write_framed, index, session).await;
+    if let Err(e) = &result {
+        write_framed
+            .send(&Message::Error(Error::from_report(*request.path_id(), e)))
+            .await?;
+    }
+
+    result
+}
+
+async fn send_requested_blocks(
+    request: &BlockRequest,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<()> {
+    // Serve blocks of indexed files only, at the offsets they were indexed at.
+    let (path, local_path, block_infos) = {
+        let index = index.read().await;
+        let path = index
+            .path(request.path_id())
+            .ok_or_else(|| eyre!("Requested blocks of a file the peer doesn't have."))?
+            .clone();
+        let local_path = path.to_local(index.root());
+        let block_infos = index.block_infos(&path).to_vec();
+        (path, local_path, block_infos)
+    };
+
+    let compression = if is_compressed_path(&local_path) {
+        Compression::None
+    } else {
+        session.compression()
+    };
+    for offset in request.offsets() {
+        let block_info = block_infos
+            .iter()
+            .find(|block_info| block_info.offset() == *offset)
+            .ok_or_else(|| eyre!("File {path:?} has no block at offset {offset}."))?;
+        let buffer = read_block_at(&local_path, block_info).await?;
+        let block_data = BlockData::compressed(
+            *request.path_id(),
+            *offset,
+            &buffer,
+            compression,
+            session.compression_level(),
+        )?;
+
+        session.rate_limiter().upload(block_data.data().len()).await;
+        session.count_bytes(Direction::Upload, block_data.data().len());
+        write_framed.send(&Message::BlockData(block_data)).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn local_file_info(
+    path: &WirePath,
+    hash_algorithm: HashAlgorithm,
+    index: &RwLock<Index>,
+) -> Result<(FileInfo, Vec<BlockInfo>)> {
+    // A missing file is described by its removal, if this folder ever had it.
+    let local_path = path.to_local(index.read().await.root());
+    if !local_path.exists() {
+        let file_info = index.read().await.removed_file_info(path, hash_algorithm);
+
+        return Ok((file_info, Vec::new()));
+    }
+
+    // Describe the local file with the path the peer knows it by.
+    let (file_info, block_infos) =
+        FileInfo::with_blocks(&local_path, path.clone(), hash_algorithm)?;
+    let version = index.read().await.version_for(&file_info);
+
+    Ok((file_info.with_version(version), block_infos))
+}
+
+async fn sync_file(
+    file_info: &FileInfo,
+    block_infos: &[BlockInfo],
+    received_file_info: &FileInfo,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<Option<Direction>> {
+    // Nothing to transfer when both root hashes match.
+    if file_info.has_same_content(received_file_info) {
+        info!("File {:?} is already in sync.", file_info.path());
+
+        return Ok(None);
+    }
+
+    let (local_path, mode, folder_id) = {
+        let index = index.read().await;
+        let local_path = file_info.path().to_local(index.root());
+        (local_path, index.mode(), index.folder_id().clone())
+    };
+    let local_path = &local_path;
+    let newer = newer_side(file_info, received_file_info);
+    let ordering = sync_direction(
+        file_info,
+        received_file_info,
+        mode,
+        session.folder_mode(&folder_id),
+    );
+    if is_conflict(file_info, received_file_info) {
+        warn!(
+            "File {:?} changed here and on the peer, keeping the newer change.",
+            file_info.path()
+        );
+        index.write().await.add_conflict(file_info.path().clone());
+    }
+
+    match (ordering, newer) {
+        (Ordering::Equal, Ordering::Greater) if !mode.sends() => warn!(
+            "Local change to {:?} is not sent, folder {folder_id} only receives.",
+            file_info.path()
+        ),
+        (Ordering::Equal, Ordering::Less) if !mode.receives() => info!(
+            "Ignoring change to {:?} from the peer, folder {folder_id} is {mode:?}.",
+            file_info.path()
+        ),
+        _ => {}
+    }
+
+    // Report which way the file went, a newer removal removes the file on the other side.
+    match ordering {
+        Ordering::Greater if file_info.is_removed() => {
+            session.set_transfer_direction(&folder_id, file_info.path(), Direction::Upload);
+
+            Ok(Some(Direction::Upload))
+        }
+        Ordering::Less if received_file_info.is_removed() => {
+            session.set_transfer_direction(&folder_id, file_info.path(), Direction::Download);
+            apply_removal(file_info.path(), index, session).await?;
+            index
+                .write()
+                .await
+                .set_version(file_info.path(), received_file_info.version());
+
+            Ok(Some(Direction::Download))
+        }
+        Ordering::Greater => {
+            session.set_transfer_direction(&folder_id, file_info.path(), Direction::Upload);
+            send_file_blocks(file_info, local_path, write_framed, read_framed, session).await?;
+
+            Ok(Some(Direction::Upload))
+        }
+        Ordering::Less => {
+            session.set_transfer_direction(&folder_id, file_info.path(), Direction::Download);
+            receive_file_blocks(
+                received_file_info,
+                file_info.path(),
+                block_infos,
+                write_framed,
+                read_framed,
+                index,
+                session,
+            )
+            .await?;
+
+            Ok(Some(Direction::Download))
+        }
+        Ordering::Equal => Ok(None),
+    }
+}
+
+pub fn newer_side(file_info: &FileInfo, received_file_info: &FileInfo) -> Ordering {
+    // The newer version wins, the modification date settles changes made without knowing each other.
+    file_info
+        .version()
+        .cmp(&received_file_info.version())
+        .then_with(|| {
+            file_info
+                .last_modified()
+                .cmp(received_file_info.last_modified())
+        })
+}
+
+pub fn sync_direction(
+    file_info: &FileInfo,
+    received_file_info: &FileInfo,
+    mode: FolderMode,
+    remote_mode: FolderMode,
+) -> Ordering {
+    if file_info.has_same_content(received_file_info) {
+        return Ordering::Equal;
+    }
+
+    // Folder modes may hold a side back, and files a side never had are not removed from the other.
+    match transfer_direction(mode, remote_mode, newer_side(file_info, received_file_info)) {
+        Ordering::Greater if file_info.version() == 0 => Ordering::Equal,
+        Ordering::Less if received_file_info.version() == 0 => Ordering::Equal,
+        ordering => ordering,
+    }
+}
+
+pub fn is_conflict(file_info: &FileInfo, received_file_info: &FileInfo) -> bool {
+    // The same version changed on both sides, only the newer change survives.
+    file_info.version() == received_file_info.version()
+        && file_info.version() != 0
+        && !file_info.has_same_content(received_file_info)
 }
 
 async fn send_file_blocks(
     file_info: &FileInfo,
+    local_path: &Path,
     write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    session: &Session,
 ) -> Result<()> {
     info!("Star sending file blocks: {file_info:?}");
 
-    // Open file with buffered reads.
+    // Open file.
     let block_size = file_info.block_size() as usize;
-    let file = File::open(file_info.path()).await?;
-    let mut file = BufReader::with_capacity(block_size, file);
+    let mut file = File::open(local_path).await?;
 
     // Send block info.
     let path_id = PathIdCache::calculate_path_id(file_info.path());
-    let mut buffer = vec![0u8; block_size as usize];
+    let hash_algorithm = file_info.hash_algorithm();
+    let mut buffer = vec![0u8; block_size];
     let mut offset = 0;
     loop {
-        let bytes_read = file.read(&mut buffer).await?;
+        let bytes_read = read_block(&mut file, &mut buffer).await?;
 
         // Check if we reached the end of the file.
         if bytes_read == 0 {
@@ -70,7 +523,8 @@ async fn send_file_blocks(
         }
 
         // Create block info object.
-        let block_info = BlockInfo::from_buffer(&buffer[0..bytes_read], path_id, offset);
+        let block_info =
+            BlockInfo::from_buffer(&buffer[0..bytes_read], path_id, offset, hash_algorithm);
 
         // Send block info.
         write_framed.send(&Message::BlockInfo(block_info)).await?;
@@ -79,9 +533,368 @@ async fn send_file_blocks(
         offset += bytes_read as u64;
     }
 
+    // Receive the blocks the peer could not find locally.
+    let Message::BlockRequest(block_request) = receive_message(read_framed).await? else {
+        return Err(eyre!("Did not receive block request."));
+    };
+    if block_request.path_id() != &path_id {
+        return Err(eyre!("Received block request for another file."));
+    }
+
+    info!(
+        "Sending {} of {} blocks of file {:?}.",
+        block_request.offsets().len(),
+        file_info.number_blocks(),
+        file_info.path()
+    );
+
+    // Send requested block data, compressed unless the content already is.
+    let compression = if is_compressed_path(local_path) {
+        Compression::None
+    } else {
+        session.compression()
+    };
+    for offset in block_request.offsets() {
+        file.seek(SeekFrom::Start(*offset)).await?;
+
+        let bytes_read = read_block(&mut file, &mut buffer).await?;
+        let block_data = BlockData::compressed(
+            path_id,
+            *offset,
+            &buffer[0..bytes_read],
+            compression,
+            session.compression_level(),
+        )?;
+
+        session.rate_limiter().upload(block_data.data().len()).await;
+        session.count_bytes(Direction::Upload, block_data.data().len());
+        write_framed.send(&Message::BlockData(block_data)).await?;
+    }
+
+    Ok(())
+}
+
+async fn receive_file_blocks(
+    file_info: &FileInfo,
+    path: &WirePath,
+    local_block_infos: &[BlockInfo],
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<()> {
+    info!("Star receiving file blocks: {file_info:?}");
+    let local_path = &path.to_local(index.read().await.root());
+
+    // Don't allocate anything for a file the peer describes inconsistently.
+    file_info.check_blocks()?;
+
+    // Receive block info.
+    let path_id = PathIdCache::calculate_path_id(file_info.path());
+    let mut block_infos = Vec::new();
+    for block_index in 0..file_info.number_blocks() {
+        let Message::BlockInfo(block_info) = receive_message(read_framed).await? else {
+            return Err(eyre!("Did not receive block info."));
+        };
+        if block_info.path_id() != &path_id {
+            return Err(eyre!("Received block info for another file."));
+        }
+        file_info.check_block(block_index, &block_info)?;
+
+        block_infos.push(block_info);
+    }
+
+    // Assemble the new content on a temporary file next to the destination, never leaving it behind.
+    let temporary_file = TemporaryFile::next_to(local_path);
+    if let Some(parent) = local_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut file = File::create(&temporary_file.0).await?;
+    file.set_len(file_info.size()).await?;
+
+    // Find which blocks changed by descending both merkle trees.
+    let merkle_tree = MerkleTree::new(
+        file_info.hash_algorithm(),
+        block_infos
+            .iter()
+            .map(|block_info| *block_info.hash())
+            .collect(),
+    );
+    let local_merkle_tree = MerkleTree::new(
+        file_info.hash_algorithm(),
+        local_block_infos
+            .iter()
+            .map(|block_info| *block_info.hash())
+            .collect(),
+    );
+    let differing_blocks: HashSet<_> = merkle_tree
+        .differing_leaves(&local_merkle_tree)
+        .into_iter()
+        .collect();
+
+    // Copy unchanged blocks from the local file and changed ones from anywhere we have them.
+    // The local file may have changed since it was indexed, so every copied block is checked.
+    let mut missing_block_infos = Vec::new();
+    for (block_index, block_info) in block_infos.iter().enumerate() {
+        let mut buffer = None;
+        if !differing_blocks.contains(&block_index) {
+            buffer = read_block_at(local_path, block_info)
+                .await
+                .ok()
+                .filter(|buffer| has_hash(buffer, block_info));
+        }
+        if buffer.is_none() {
+            buffer = index.read().await.read_block(block_info).await;
+        }
+
+        match buffer {
+            Some(buffer) => {
+                file.seek(SeekFrom::Start(block_info.offset())).await?;
+                file.write_all(&buffer).await?;
+            }
+            None => missing_block_infos.push(block_info.clone()),
+        }
+    }
+
+    info!(
+        "Reused {} of {} blocks of file {:?}.",
+        block_infos.len() - missing_block_infos.len(),
+        block_infos.len(),
+        file_info.path()
+    );
+
+    // Request the blocks we could not find.
+    let offsets = missing_block_infos
+        .iter()
+        .map(|block_info| block_info.offset())
+        .collect();
+    write_framed
+        .send(&Message::BlockRequest(BlockRequest::new(path_id, offsets)))
+        .await?;
+
+    // Receive and verify block data.
+    let mut received_bytes = 0;
+    let mut block_bytes = 0;
+    for block_info in &missing_block_infos {
+        let Message::BlockData(block_data) = receive_message(read_framed).await? else {
+            return Err(eyre!("Did not receive block data."));
+        };
+        if block_data.path_id() != &path_id || block_data.offset() != block_info.offset() {
+            return Err(eyre!("Received unexpected block data."));
+        }
+
+        // Slow down reading so the peer has to slow down sending.
+        session
+            .rate_limiter()
+            .download(block_data.data().len())
+            .await;
+        session.count_bytes(Direction::Download, block_data.data().len());
+
+        let data = block_data.decompress(block_info.block_size() as usize)?;
+        let received_block_info = BlockInfo::from_buffer(
+            &data,
+            path_id,
+            block_data.offset(),
+            block_info.hash_algorithm(),
+        );
+        if received_block_info.hash() != block_info.hash() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Block at offset {} of file {:?} is corrupted.",
+                    block_info.offset(),
+                    file_info.path()
+                ),
+            )
+            .into());
+        }
+
+        file.seek(SeekFrom::Start(block_data.offset())).await?;
+        file.write_all(&data).await?;
+
+        trace!(
+            "Received block at offset {} with {:?} compression.",
+            block_data.offset(),
+            block_data.compression()
+        );
+        received_bytes += block_data.data().len();
+        block_bytes += data.len();
+    }
+
+    if !missing_block_infos.is_empty() {
+        info!(
+            "Received {received_bytes} bytes for {block_bytes} bytes of file {:?}.",
+            file_info.path()
+        );
+    }
+
+    // Keep the modification date of the peer so the file is not synced back.
+    file.sync_all().await?;
+    let file = file.into_std().await;
+    file.set_modified(*file_info.last_modified())?;
+    drop(file);
+
+    // Keep the content being replaced without holding the index, it stays in place until replaced.
+    if local_path.exists() {
+        let (root, versioning) = {
+            let index = index.read().await;
+            (index.root().to_owned(), index.versioning())
+        };
+        let (path, device_id) = (path.clone(), *session.device_id());
+        tokio::task::spawn_blocking(move || keep_versions(&root, &path, versioning, &device_id))
+            .await??;
+    }
+
+    // Replace the destination with the new content, holding the index so the watcher sees it indexed.
+    let mut index = index.write().await;
+    tokio::fs::rename(&temporary_file.0, local_path).await?;
+
+    // Update the index with the new content, keeping the version of the peer.
+    index.remove_file(path);
+    index.add_file_info(file_info.clone().with_path(path.clone()));
+    for block_info in block_infos {
+        index.add_block_info(block_info);
+    }
+
+    // Pass the new content on to the other peers.
+    session.notify(
+        index.folder_id().clone(),
+        ChangeKind::Modified,
+        path.clone(),
+    );
+
     Ok(())
 }
 
-async fn receive_file_blocks() -> Result<()> {
-    todo!();
+pub async fn check_paused(index: &RwLock<Index>) -> Result<()> {
+    // Paused folders catch up once resumed, so there is no point in retrying.
+    let index = index.read().await;
+    if index.is_paused() {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Folder {} is paused on the peer.", index.folder_id()),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+async fn check_name_collision(path: &WirePath, index: &RwLock<Index>) -> Result<()> {
+    let (mut directory, name_policy) = {
+        let index = index.read().await;
+        (index.root().to_owned(), index.name_policy())
+    };
+
+    // Compare every name on the way with its siblings, the folder may treat them as the same.
+    let mut parent = WirePath::default();
+    for name in path.components() {
+        let mut entries = match tokio::fs::read_dir(&directory).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            if name_policy.collides(name, &entry.file_name()) {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "{path:?} collides with {:?} in this folder.",
+                        parent.join(&entry.file_name())?
+                    ),
+                )
+                .into());
+            }
+        }
+
+        directory.push(name);
+        parent = parent.join(name)?;
+    }
+
+    Ok(())
+}
+
+async fn read_block_at(path: &Path, block_info: &BlockInfo) -> Result<Vec<u8>> {
+    // Read the block from the same offset of the file.
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(block_info.offset())).await?;
+
+    let mut buffer = vec![0u8; block_info.block_size() as usize];
+    file.read_exact(&mut buffer).await?;
+
+    Ok(buffer)
+}
+
+fn has_hash(buffer: &[u8], block_info: &BlockInfo) -> bool {
+    block_info.hash_algorithm().hash(buffer) == *block_info.hash()
+}
+
+async fn read_block(file: &mut File, buffer: &mut [u8]) -> Result<usize> {
+    // Fill the buffer unless the end of the file is reached first.
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        let read = file.read(&mut buffer[bytes_read..]).await?;
+        if read == 0 {
+            break;
+        }
+
+        bytes_read += read;
+    }
+
+    Ok(bytes_read)
+}
+
+const TEMPORARY_EXTENSION: &str = "entangler-tmp";
+
+pub struct TemporaryFile(PathBuf);
+
+impl TemporaryFile {
+    pub fn next_to(path: &Path) -> Self {
+        Self(temporary_path(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        // Failed and aborted transfers remove what they received, a finished one moved it away already.
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("Fail to remove temporary file {:?}: {e}", self.0);
+            }
+        }
+    }
+}
+
+pub fn temporary_path(path: &Path) -> PathBuf {
+    // Two peers may send the same file at once, give each transfer a file of its own.
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(format!(".{:08x}.", rand::random::<u32>()));
+    file_name.push(TEMPORARY_EXTENSION);
+
+    path.with_file_name(file_name)
+}
+
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4",
+    "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz",
+    "zip", "zst",
+];
+
+fn is_compressed_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            COMPRESSED_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+pub fn is_temporary_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == TEMPORARY_EXTENSION)
 }
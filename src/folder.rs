@@ -0,0 +1,363 @@
+use crate::{
+    device_id::device_id_from_string,
+    messages::{DeviceId, FolderId, FolderMode},
+    name_policy::NamePolicy,
+    versioning::Versioning,
+};
+use clap::ValueEnum;
+use color_eyre::{eyre::eyre, Report, Result};
+use globset::Glob;
+use std::{cmp::Ordering, path::PathBuf, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderConfig {
+    id: FolderId,
+    path: PathBuf,
+    mode: FolderMode,
+    versioning: Versioning,
+    name_policy: NamePolicy,
+    devices: Vec<DeviceId>,
+    ignore_patterns: Vec<Glob>,
+}
+
+impl FolderConfig {
+    pub fn new(id: FolderId, path: PathBuf) -> Self {
+        Self {
+            id,
+            path,
+            mode: FolderMode::default(),
+            versioning: Versioning::default(),
+            name_policy: NamePolicy::default(),
+            devices: Vec::new(),
+            ignore_patterns: Vec::new(),
+        }
+    }
+
+    pub fn with_mode(self, mode: FolderMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub fn with_versioning(self, versioning: Versioning) -> Self {
+        Self { versioning, ..self }
+    }
+
+    pub fn with_name_policy(self, name_policy: NamePolicy) -> Self {
+        Self {
+            name_policy,
+            ..self
+        }
+    }
+
+    pub fn with_ignore_patterns(mut self, ignore_patterns: &[Glob]) -> Self {
+        self.ignore_patterns.extend_from_slice(ignore_patterns);
+        self
+    }
+
+    pub fn share_with(&mut self, device_id: DeviceId) {
+        if !self.devices.contains(&device_id) {
+            self.devices.push(device_id);
+        }
+    }
+
+    pub fn id(&self) -> &FolderId {
+        &self.id
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn mode(&self) -> FolderMode {
+        self.mode
+    }
+
+    pub fn versioning(&self) -> Versioning {
+        self.versioning
+    }
+
+    pub fn name_policy(&self) -> NamePolicy {
+        self.name_policy
+    }
+
+    pub fn devices(&self) -> &[DeviceId] {
+        &self.devices
+    }
+
+    pub fn ignore_patterns(&self) -> &[Glob] {
+        &self.ignore_patterns
+    }
+}
+
+impl FromStr for FolderConfig {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (id, path) = s
+            .split_once('=')
+            .filter(|(_, path)| !path.is_empty())
+            .ok_or_else(|| {
+                eyre!("Invalid folder {s:?}, expected ID=PATH, like projects=/srv/projects.")
+            })?;
+
+        Ok(Self::new(id.parse()?, PathBuf::from(path)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderShare {
+    folder_id: FolderId,
+    device_id: DeviceId,
+}
+
+impl FolderShare {
+    pub fn apply(self, folders: &mut [FolderConfig]) -> Result<()> {
+        let folder = folders
+            .iter_mut()
+            .find(|folder| folder.id == self.folder_id)
+            .ok_or_else(|| eyre!("Can't share unknown folder {}.", self.folder_id))?;
+        folder.share_with(self.device_id);
+
+        Ok(())
+    }
+}
+
+impl FromStr for FolderShare {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (folder_id, device_id) = s
+            .split_once('=')
+            .ok_or_else(|| eyre!("Invalid share {s:?}, expected FOLDER_ID=DEVICE_ID."))?;
+
+        Ok(Self {
+            folder_id: folder_id.parse()?,
+            device_id: device_id_from_string(device_id)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderModeSetting {
+    folder_id: FolderId,
+    mode: FolderMode,
+}
+
+impl FolderModeSetting {
+    pub fn apply(self, folders: &mut [FolderConfig]) -> Result<()> {
+        let folder = folders
+            .iter_mut()
+            .find(|folder| folder.id == self.folder_id)
+            .ok_or_else(|| eyre!("Can't set the mode of unknown folder {}.", self.folder_id))?;
+        folder.mode = self.mode;
+
+        Ok(())
+    }
+}
+
+impl FromStr for FolderModeSetting {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (folder_id, mode) = s.split_once('=').ok_or_else(|| {
+            eyre!("Invalid folder mode {s:?}, expected FOLDER_ID=MODE, like builds=receive-only.")
+        })?;
+
+        Ok(Self {
+            folder_id: folder_id.parse()?,
+            mode: FolderMode::from_str(mode, true).map_err(|e| eyre!(e))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderVersioningSetting {
+    folder_id: FolderId,
+    versioning: Versioning,
+}
+
+impl FolderVersioningSetting {
+    pub fn apply(self, folders: &mut [FolderConfig]) -> Result<()> {
+        let folder = folders
+            .iter_mut()
+            .find(|folder| folder.id == self.folder_id)
+            .ok_or_else(|| {
+                eyre!(
+                    "Can't set the versioning of unknown folder {}.",
+                    self.folder_id
+                )
+            })?;
+        folder.versioning = self.versioning;
+
+        Ok(())
+    }
+}
+
+impl FromStr for FolderVersioningSetting {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (folder_id, versioning) = s.split_once('=').ok_or_else(|| {
+            eyre!("Invalid folder versioning {s:?}, expected FOLDER_ID=VERSIONING, like documents=staggered.")
+        })?;
+
+        Ok(Self {
+            folder_id: folder_id.parse()?,
+            versioning: versioning.parse()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderNamesSetting {
+    folder_id: FolderId,
+    name_policy: NamePolicy,
+}
+
+impl FolderNamesSetting {
+    pub fn apply(self, folders: &mut [FolderConfig]) -> Result<()> {
+        let folder = folders
+            .iter_mut()
+            .find(|folder| folder.id == self.folder_id)
+            .ok_or_else(|| eyre!("Can't set the names of unknown folder {}.", self.folder_id))?;
+        folder.name_policy = self.name_policy;
+
+        Ok(())
+    }
+}
+
+impl FromStr for FolderNamesSetting {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (folder_id, name_policy) = s.split_once('=').ok_or_else(|| {
+            eyre!("Invalid folder names {s:?}, expected FOLDER_ID=NORMALIZATION[:CASE], like music=nfc:insensitive.")
+        })?;
+
+        Ok(Self {
+            folder_id: folder_id.parse()?,
+            name_policy: name_policy.parse()?,
+        })
+    }
+}
+
+pub fn transfer_direction(local: FolderMode, remote: FolderMode, newer: Ordering) -> Ordering {
+    let send = local.sends() && remote.receives();
+    let receive = local.receives() && remote.sends();
+
+    // A mirror overwrites whatever differs, otherwise the newer side wins if it may send.
+    match newer {
+        _ if send && local == FolderMode::Mirror => Ordering::Greater,
+        _ if receive && remote == FolderMode::Mirror => Ordering::Less,
+        Ordering::Greater if send => Ordering::Greater,
+        Ordering::Less if receive => Ordering::Less,
+        _ => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        transfer_direction, FolderConfig, FolderModeSetting, FolderNamesSetting, FolderShare,
+        FolderVersioningSetting,
+    };
+    use crate::{
+        messages::FolderMode,
+        name_policy::{CaseSensitivity, NamePolicy, UnicodeNormalization},
+        versioning::Versioning,
+    };
+    use std::{cmp::Ordering, path::Path};
+
+    #[test]
+    fn folder_config() {
+        // Make sure folders and shares parse.
+        let mut folders = vec!["projects=/srv/projects".parse::<FolderConfig>().unwrap()];
+        assert_eq!(folders[0].id().to_string(), "projects");
+        assert_eq!(folders[0].path(), Path::new("/srv/projects"));
+        assert!("projects".parse::<FolderConfig>().is_err());
+        assert!("a/b=/srv".parse::<FolderConfig>().is_err());
+
+        let device_id = "ab".repeat(32);
+        let share: FolderShare = format!("projects={device_id}").parse().unwrap();
+        assert!(format!("projects={}", "ab".repeat(31))
+            .parse::<FolderShare>()
+            .is_err());
+
+        // Make sure shares only apply to known folders.
+        share.clone().apply(&mut folders).unwrap();
+        assert_eq!(folders[0].devices(), &[[0xab; 32]]);
+
+        let mut other_folders = vec!["photos=/srv/photos".parse::<FolderConfig>().unwrap()];
+        assert!(share.apply(&mut other_folders).is_err());
+    }
+
+    #[test]
+    fn folder_mode() {
+        // Make sure modes only apply to known folders.
+        let mut folders = vec!["builds=/srv/builds".parse::<FolderConfig>().unwrap()];
+        let setting: FolderModeSetting = "builds=receive-only".parse().unwrap();
+        setting.clone().apply(&mut folders).unwrap();
+        assert_eq!(folders[0].mode(), FolderMode::ReceiveOnly);
+        assert!("builds=upside-down".parse::<FolderModeSetting>().is_err());
+
+        let mut other_folders = vec!["photos=/srv/photos".parse::<FolderConfig>().unwrap()];
+        assert!(setting.apply(&mut other_folders).is_err());
+
+        // Make sure versioning only applies to known folders.
+        let setting: FolderVersioningSetting = "builds=simple:3".parse().unwrap();
+        setting.clone().apply(&mut folders).unwrap();
+        assert_eq!(folders[0].versioning(), Versioning::Simple { count: 3 });
+        assert!("builds".parse::<FolderVersioningSetting>().is_err());
+        assert!(setting.apply(&mut other_folders).is_err());
+
+        // Make sure name policies only apply to known folders.
+        let setting: FolderNamesSetting = "builds=nfc:insensitive".parse().unwrap();
+        setting.clone().apply(&mut folders).unwrap();
+        assert_eq!(
+            folders[0].name_policy(),
+            NamePolicy::new(UnicodeNormalization::Nfc, CaseSensitivity::Insensitive)
+        );
+        assert!("builds=upper".parse::<FolderNamesSetting>().is_err());
+        assert!(setting.apply(&mut other_folders).is_err());
+
+        // Make sure both sides of a sync always agree on the direction.
+        let modes = [
+            FolderMode::SendReceive,
+            FolderMode::SendOnly,
+            FolderMode::ReceiveOnly,
+            FolderMode::Mirror,
+        ];
+        for local in modes {
+            for remote in modes {
+                for newer in [Ordering::Less, Ordering::Equal, Ordering::Greater] {
+                    assert_eq!(
+                        transfer_direction(local, remote, newer),
+                        transfer_direction(remote, local, newer.reverse()).reverse()
+                    );
+                }
+            }
+        }
+
+        // Make sure a receive only folder never sends, and a mirror overwrites newer files.
+        use FolderMode::*;
+        assert_eq!(
+            transfer_direction(ReceiveOnly, SendReceive, Ordering::Greater),
+            Ordering::Equal
+        );
+        assert_eq!(
+            transfer_direction(SendReceive, ReceiveOnly, Ordering::Less),
+            Ordering::Equal
+        );
+        assert_eq!(
+            transfer_direction(SendOnly, SendReceive, Ordering::Less),
+            Ordering::Equal
+        );
+        assert_eq!(
+            transfer_direction(Mirror, ReceiveOnly, Ordering::Less),
+            Ordering::Greater
+        );
+        assert_eq!(
+            transfer_direction(Mirror, Mirror, Ordering::Greater),
+            Ordering::Equal
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::messages::WirePath;
+use color_eyre::Result;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+pub const METADATA_DIRECTORY: &str = ".entangler";
+
+#[derive(Debug, Clone, Default)]
+pub struct IgnorePatterns {
+    glob_set: GlobSet,
+}
+
+impl IgnorePatterns {
+    pub fn new(patterns: Vec<Glob>) -> Result<Self> {
+        // What this device keeps about the folder never syncs.
+        let mut builder = GlobSetBuilder::new();
+        builder.add(Glob::new(METADATA_DIRECTORY)?);
+        for pattern in patterns {
+            builder.add(pattern);
+        }
+
+        Ok(Self {
+            glob_set: builder.build()?,
+        })
+    }
+
+    pub fn is_ignored(&self, path: &WirePath) -> bool {
+        // Ignoring a folder ignores everything inside it.
+        let path = path.to_relative();
+        path.ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| self.glob_set.is_match(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IgnorePatterns;
+    use crate::messages::WirePath;
+    use globset::Glob;
+    use std::path::Path;
+
+    #[test]
+    fn ignore_patterns() {
+        let ignore_patterns = IgnorePatterns::new(vec![
+            Glob::new("*.tmp").unwrap(),
+            Glob::new("target").unwrap(),
+        ])
+        .unwrap();
+        let path = |path| WirePath::from_relative(Path::new(path)).unwrap();
+
+        // Make sure patterns match files in any folder.
+        assert!(ignore_patterns.is_ignored(&path("build.tmp")));
+        assert!(ignore_patterns.is_ignored(&path("src/build.tmp")));
+        assert!(!ignore_patterns.is_ignored(&path("src/main.rs")));
+
+        // Make sure everything inside an ignored folder is ignored.
+        assert!(ignore_patterns.is_ignored(&path("target")));
+        assert!(ignore_patterns.is_ignored(&path("target/debug/entangler")));
+        assert!(!ignore_patterns.is_ignored(&path("src/target.rs")));
+
+        // Make sure the metadata of the folder is always ignored.
+        assert!(ignore_patterns.is_ignored(&path(".entangler/versions/notes.txt")));
+        assert!(!ignore_patterns.is_ignored(&path("src/.entangler")));
+    }
+}
@@ -0,0 +1,692 @@
+use crate::{
+    ignore::IgnorePatterns,
+    messages::{
+        name_bytes, BlockHash, BlockInfo, DirectoryEntry, DirectoryEntryKind, DirectoryInfo,
+        FileInfo, FolderId, FolderMode, HashAlgorithm, PathId, WirePath,
+    },
+    name_policy::NamePolicy,
+    path_id_cache::PathIdCache,
+    scraper::scrape,
+    versioning::Versioning,
+};
+use color_eyre::Result;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsString,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
+use tracing::*;
+
+#[derive(Default)]
+struct Directory {
+    hash: BlockHash,
+    entries: BTreeMap<OsString, DirectoryEntry>,
+}
+
+#[derive(Default)]
+struct Digests {
+    files: HashMap<WirePath, BlockHash>,
+    directories: HashMap<WirePath, BlockHash>,
+}
+
+#[derive(Default)]
+pub struct Index {
+    folder_id: FolderId,
+    mode: FolderMode,
+    versioning: Versioning,
+    root: PathBuf,
+    hash_algorithm: HashAlgorithm,
+    name_policy: NamePolicy,
+    ignore_patterns: IgnorePatterns,
+    paused: bool,
+    conflicts: BTreeSet<WirePath>,
+    local_changes: BTreeSet<WirePath>,
+    errors: BTreeMap<WirePath, String>,
+    path_ids: PathIdCache,
+    files: BTreeMap<WirePath, FileInfo>,
+    removals: BTreeMap<WirePath, (u64, SystemTime)>,
+    file_blocks: HashMap<PathId, Vec<BlockInfo>>,
+    blocks: HashMap<(HashAlgorithm, BlockHash), Vec<(PathId, u64)>>,
+    directories: HashMap<WirePath, Directory>,
+    digests: HashMap<HashAlgorithm, Digests>,
+}
+
+impl Index {
+    pub async fn with_folder(
+        folder_id: FolderId,
+        path: PathBuf,
+        hash_algorithm: HashAlgorithm,
+        name_policy: NamePolicy,
+        ignore_patterns: IgnorePatterns,
+    ) -> Result<Self> {
+        let mut index = Self {
+            folder_id,
+            root: path.clone(),
+            hash_algorithm,
+            name_policy,
+            ignore_patterns: ignore_patterns.clone(),
+            ..Default::default()
+        };
+
+        // Scrape the folder in the background.
+        let (file_info_tx, mut file_info_rx) = mpsc::channel(16);
+        let (block_info_tx, mut block_info_rx) = mpsc::channel(16);
+        let scraper = tokio::spawn(scrape(
+            path,
+            hash_algorithm,
+            ignore_patterns,
+            file_info_tx,
+            block_info_tx,
+        ));
+
+        // Collect everything the scraper finds.
+        let mut file_info_done = false;
+        let mut block_info_done = false;
+        while !file_info_done || !block_info_done {
+            tokio::select! {
+                file_info = file_info_rx.recv(), if !file_info_done => match file_info {
+                    // Version 0 stands for a missing file, whatever is on disk is at least the first version.
+                    Some(Ok(file_info)) => index.insert_file_info(file_info.with_version(1)),
+                    Some(Err(e)) => error!("Fail to index file: {e:?}"),
+                    None => file_info_done = true,
+                },
+                block_info = block_info_rx.recv(), if !block_info_done => match block_info {
+                    Some(Ok(block_info)) => index.add_block_info(block_info),
+                    Some(Err(e)) => error!("Fail to index block: {e:?}"),
+                    None => block_info_done = true,
+                },
+            }
+        }
+
+        scraper.await?;
+
+        // Summarize every directory once everything is known.
+        index.rebuild_directory_hashes();
+
+        info!(
+            "Indexed {} files with {} unique blocks in folder {}.",
+            index.files.len(),
+            index.blocks.len(),
+            index.folder_id
+        );
+
+        Ok(index)
+    }
+
+    pub fn with_mode(self, mode: FolderMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub fn with_versioning(self, versioning: Versioning) -> Self {
+        Self { versioning, ..self }
+    }
+
+    pub fn folder_id(&self) -> &FolderId {
+        &self.folder_id
+    }
+
+    pub fn mode(&self) -> FolderMode {
+        self.mode
+    }
+
+    pub fn versioning(&self) -> Versioning {
+        self.versioning
+    }
+
+    pub fn set_versioning(&mut self, versioning: Versioning) {
+        self.versioning = versioning;
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn name_policy(&self) -> NamePolicy {
+        self.name_policy
+    }
+
+    pub fn is_ignored(&self, path: &WirePath) -> bool {
+        self.ignore_patterns.is_ignored(path)
+    }
+
+    pub fn ignore_patterns(&self) -> &IgnorePatterns {
+        &self.ignore_patterns
+    }
+
+    pub fn set_ignore_patterns(&mut self, ignore_patterns: IgnorePatterns) {
+        // Forget the files ignored from now on. Files no longer ignored are indexed once they change.
+        self.ignore_patterns = ignore_patterns;
+        let ignored: Vec<_> = self
+            .files
+            .keys()
+            .filter(|path| self.ignore_patterns.is_ignored(path))
+            .cloned()
+            .collect();
+        for path in ignored {
+            self.remove_file(&path);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &WirePath> {
+        self.conflicts.iter()
+    }
+
+    pub fn add_conflict(&mut self, path: WirePath) {
+        // Remembered until the file changes here again, by then someone had a look at it.
+        self.conflicts.insert(path);
+    }
+
+    pub fn local_changes(&self) -> impl Iterator<Item = &WirePath> {
+        self.local_changes.iter()
+    }
+
+    pub fn add_local_change(&mut self, path: WirePath) {
+        // Remembered until the peers replace the file or the change is reverted.
+        self.local_changes.insert(path);
+    }
+
+    pub fn forget_local_change(&mut self, path: &WirePath) {
+        self.local_changes.remove(path);
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = (&WirePath, &String)> {
+        self.errors.iter()
+    }
+
+    pub fn set_error(&mut self, path: WirePath, error: Option<String>) {
+        // Only the last attempt to sync a path matters.
+        match error {
+            Some(error) => self.errors.insert(path, error),
+            None => self.errors.remove(&path),
+        };
+    }
+
+    pub fn file_info(&self, path: &WirePath) -> Option<&FileInfo> {
+        self.files.get(path)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &FileInfo> {
+        self.files.values()
+    }
+
+    pub fn removed_file_info(&self, path: &WirePath, hash_algorithm: HashAlgorithm) -> FileInfo {
+        // A file this folder never had is at version 0, older than anything the peer has.
+        let (version, removed) = self
+            .removals
+            .get(path)
+            .copied()
+            .unwrap_or((0, SystemTime::UNIX_EPOCH));
+
+        FileInfo::removed(path.clone(), removed, hash_algorithm).with_version(version)
+    }
+
+    pub fn path(&self, path_id: &PathId) -> Option<&WirePath> {
+        self.path_ids.get_path(path_id)
+    }
+
+    pub fn block_infos(&self, path: &WirePath) -> &[BlockInfo] {
+        self.file_blocks
+            .get(&PathIdCache::calculate_path_id(path))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn version_for(&self, file_info: &FileInfo) -> u64 {
+        // A file that changed since it was indexed is newer than the indexed version.
+        match self.files.get(file_info.path()) {
+            Some(known)
+                if known.size() == file_info.size()
+                    && known.last_modified() == file_info.last_modified() =>
+            {
+                known.version()
+            }
+            Some(known) => known.version() + 1,
+            None => self.recreated_version(file_info.path()),
+        }
+    }
+
+    fn recreated_version(&self, path: &WirePath) -> u64 {
+        // A file created again is newer than its removal.
+        self.removals
+            .get(path)
+            .map_or(1, |(version, _)| version + 1)
+    }
+
+    pub fn set_version(&mut self, path: &WirePath, version: u64) {
+        if let Some(file_info) = self.files.get_mut(path) {
+            *file_info = file_info.clone().with_version(version);
+        }
+        if let Some(removal) = self.removals.get_mut(path) {
+            removal.0 = version;
+        }
+    }
+
+    pub fn update_file(&mut self, file_info: FileInfo, block_infos: Vec<BlockInfo>) -> bool {
+        // Only new content makes a new version, touching a file keeps the version it had.
+        let known = self.files.get(file_info.path());
+        let changed = known.is_none_or(|known| !known.has_same_content(&file_info));
+        let version = match known {
+            Some(known) if changed => known.version() + 1,
+            Some(known) => known.version(),
+            None => self.recreated_version(file_info.path()),
+        };
+        if changed {
+            self.conflicts.remove(file_info.path());
+        }
+
+        self.remove_file(&file_info.path().clone());
+        self.add_file_info(file_info.with_version(version));
+        for block_info in block_infos {
+            self.add_block_info(block_info);
+        }
+
+        changed
+    }
+
+    pub fn add_file_info(&mut self, file_info: FileInfo) {
+        let path = file_info.path().clone();
+        self.insert_file_info(file_info);
+
+        if let Some(parent) = path.parent() {
+            self.update_directory_hashes(parent);
+        }
+    }
+
+    fn insert_file_info(&mut self, file_info: FileInfo) {
+        let path = file_info.path().clone();
+        self.forget_digests(&path);
+
+        // Add the file to its directory.
+        if let Some(name) = path.file_name() {
+            let entry = DirectoryEntry::new(
+                name.to_owned(),
+                DirectoryEntryKind::File,
+                file_info.content_hash(),
+            );
+            self.set_directory_entry(&path, Some(entry));
+        }
+
+        // Make sure every directory up to the root is known.
+        let mut directory = path.parent();
+        while let Some(path) = directory {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                break;
+            };
+
+            self.directories
+                .entry(parent.clone())
+                .or_default()
+                .entries
+                .entry(name.to_owned())
+                .or_insert_with(|| {
+                    DirectoryEntry::new(
+                        name.to_owned(),
+                        DirectoryEntryKind::Directory,
+                        BlockHash::default(),
+                    )
+                });
+
+            directory = Some(parent);
+        }
+
+        self.path_ids.add_path(&path);
+        self.removals.remove(&path);
+        self.files.insert(path, file_info);
+    }
+
+    pub fn add_block_info(&mut self, block_info: BlockInfo) {
+        let path_id = *block_info.path_id();
+
+        self.blocks
+            .entry(block_key(&block_info))
+            .or_default()
+            .push((path_id, block_info.offset()));
+        self.file_blocks
+            .entry(path_id)
+            .or_default()
+            .push(block_info);
+    }
+
+    pub fn remove_path(&mut self, path: &WirePath) -> bool {
+        // Forget the file, or every file below the directory, remembering the removal as a newer version.
+        let paths: Vec<_> = self
+            .files
+            .range(path..)
+            .map(|(file_path, _)| file_path)
+            .take_while(|file_path| file_path.starts_with(path))
+            .cloned()
+            .collect();
+
+        let removed = !paths.is_empty();
+        let now = SystemTime::now();
+        for path in paths {
+            let version = self.files[&path].version();
+            self.conflicts.remove(&path);
+            self.remove_file(&path);
+            self.removals.insert(path, (version + 1, now));
+        }
+
+        removed
+    }
+
+    pub fn remove_file(&mut self, path: &WirePath) {
+        let path_id = &PathIdCache::calculate_path_id(path);
+        self.path_ids.remove_path(path_id);
+        self.files.remove(path);
+        self.local_changes.remove(path);
+        self.forget_digests(path);
+
+        // Remove the file from its directory.
+        self.set_directory_entry(path, None);
+        if let Some(parent) = path.parent() {
+            self.update_directory_hashes(parent);
+        }
+
+        // Forget every location that pointed into the file.
+        let Some(block_infos) = self.file_blocks.remove(path_id) else {
+            return;
+        };
+
+        for block_info in block_infos {
+            let Some(locations) = self.blocks.get_mut(&block_key(&block_info)) else {
+                continue;
+            };
+
+            locations.retain(|(location_path_id, _)| location_path_id != path_id);
+            if locations.is_empty() {
+                self.blocks.remove(&block_key(&block_info));
+            }
+        }
+    }
+
+    pub fn directory_info(&self, path: &WirePath, hash_algorithm: HashAlgorithm) -> DirectoryInfo {
+        // Describe the directory with the digests of another hash algorithm when asked to.
+        if hash_algorithm != self.hash_algorithm {
+            let digests = self.digests.get(&hash_algorithm);
+            let entries = self.digest_entries(digests, hash_algorithm, path);
+            let hash = self.directory_digest(digests, hash_algorithm, path);
+
+            return DirectoryInfo::new(
+                path.clone(),
+                hash_algorithm,
+                hash,
+                entries.into_values().collect(),
+            );
+        }
+
+        // An unknown directory is reported empty.
+        let Some(directory) = self.directories.get(path) else {
+            return DirectoryInfo::new(
+                path.clone(),
+                self.hash_algorithm,
+                BlockHash::default(),
+                Vec::new(),
+            );
+        };
+
+        DirectoryInfo::new(
+            path.clone(),
+            self.hash_algorithm,
+            directory.hash,
+            directory.entries.values().cloned().collect(),
+        )
+    }
+
+    pub fn missing_digests(&self, hash_algorithm: HashAlgorithm) -> Vec<FileInfo> {
+        // Files are indexed with one hash algorithm, the others are hashed when a peer needs them.
+        if hash_algorithm == self.hash_algorithm {
+            return Vec::new();
+        }
+
+        let digests = self.digests.get(&hash_algorithm);
+        self.files
+            .values()
+            .filter(|file_info| {
+                digests.is_none_or(|digests| !digests.files.contains_key(file_info.path()))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn add_digests(
+        &mut self,
+        hash_algorithm: HashAlgorithm,
+        file_digests: Vec<(FileInfo, BlockHash)>,
+    ) {
+        // Skip files that changed while they were hashed, they are hashed again next time.
+        let mut digests = self.digests.remove(&hash_algorithm).unwrap_or_default();
+        for (file_info, hash) in file_digests {
+            if self.files.get(file_info.path()) == Some(&file_info) {
+                digests.files.insert(file_info.path().clone(), hash);
+            }
+        }
+
+        // Summarize the deepest directories first so parents reuse the digests of their children.
+        let mut paths: Vec<_> = self.directories.keys().cloned().collect();
+        paths.sort_by_key(|path| std::cmp::Reverse(path.depth()));
+        for path in paths {
+            let hash = self.directory_digest(Some(&digests), hash_algorithm, &path);
+            digests.directories.insert(path, hash);
+        }
+
+        self.digests.insert(hash_algorithm, digests);
+    }
+
+    fn digest_entries(
+        &self,
+        digests: Option<&Digests>,
+        hash_algorithm: HashAlgorithm,
+        path: &WirePath,
+    ) -> BTreeMap<OsString, DirectoryEntry> {
+        let Some(directory) = self.directories.get(path) else {
+            return BTreeMap::new();
+        };
+
+        // A file that was not hashed yet never matches, so the peers compare it as a whole.
+        directory
+            .entries
+            .iter()
+            .filter_map(|(name, entry)| {
+                let entry_path = path.join(name).ok()?;
+                let hash = match entry.kind() {
+                    DirectoryEntryKind::File => digests
+                        .and_then(|digests| digests.files.get(&entry_path))
+                        .copied()
+                        .unwrap_or_default(),
+                    DirectoryEntryKind::Directory => {
+                        self.directory_digest(digests, hash_algorithm, &entry_path)
+                    }
+                };
+
+                Some((
+                    name.clone(),
+                    DirectoryEntry::new(name.clone(), entry.kind(), hash),
+                ))
+            })
+            .collect()
+    }
+
+    fn directory_digest(
+        &self,
+        digests: Option<&Digests>,
+        hash_algorithm: HashAlgorithm,
+        path: &WirePath,
+    ) -> BlockHash {
+        if let Some(hash) = digests.and_then(|digests| digests.directories.get(path)) {
+            return *hash;
+        }
+
+        directory_hash(
+            hash_algorithm,
+            &self.digest_entries(digests, hash_algorithm, path),
+        )
+    }
+
+    fn forget_digests(&mut self, path: &WirePath) {
+        // The file and every directory above it need to be hashed again.
+        for digests in self.digests.values_mut() {
+            digests.files.remove(path);
+
+            let mut directory = path.parent();
+            while let Some(path) = directory {
+                digests.directories.remove(&path);
+                directory = path.parent();
+            }
+        }
+    }
+
+    fn set_directory_entry(&mut self, path: &WirePath, entry: Option<DirectoryEntry>) {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return;
+        };
+
+        match entry {
+            Some(entry) => {
+                self.directories
+                    .entry(parent)
+                    .or_default()
+                    .entries
+                    .insert(name.to_owned(), entry);
+            }
+            None => {
+                if let Some(directory) = self.directories.get_mut(&parent) {
+                    directory.entries.remove(name);
+                }
+            }
+        }
+    }
+
+    fn update_directory_hashes(&mut self, path: WirePath) {
+        // Walk up to the root refreshing the hash of every directory on the way.
+        let mut directory_path = Some(path);
+        while let Some(path) = directory_path {
+            let Some(directory) = self.directories.get_mut(&path) else {
+                break;
+            };
+
+            let entry = if directory.entries.is_empty() && path.depth() > 0 {
+                self.directories.remove(&path);
+
+                None
+            } else {
+                directory.hash = directory_hash(self.hash_algorithm, &directory.entries);
+
+                path.file_name().map(|name| {
+                    DirectoryEntry::new(
+                        name.to_owned(),
+                        DirectoryEntryKind::Directory,
+                        directory.hash,
+                    )
+                })
+            };
+
+            self.set_directory_entry(&path, entry);
+            directory_path = path.parent();
+        }
+    }
+
+    fn rebuild_directory_hashes(&mut self) {
+        // Hash the deepest directories first so parents see the final hashes of their children.
+        let mut paths: Vec<_> = self.directories.keys().cloned().collect();
+        paths.sort_by_key(|path| std::cmp::Reverse(path.depth()));
+
+        for path in paths {
+            let directory = self.directories.get_mut(&path).unwrap();
+            directory.hash = directory_hash(self.hash_algorithm, &directory.entries);
+
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            let entry = DirectoryEntry::new(
+                name.to_owned(),
+                DirectoryEntryKind::Directory,
+                directory.hash,
+            );
+            self.set_directory_entry(&path, Some(entry));
+        }
+    }
+
+    pub async fn read_block(&self, block_info: &BlockInfo) -> Option<Vec<u8>> {
+        let locations = self.blocks.get(&block_key(block_info))?;
+
+        // Try every known location until one still holds the expected content.
+        for (path_id, offset) in locations {
+            let Some(path) = self.path_ids.get_path(path_id) else {
+                continue;
+            };
+
+            let path = path.to_local(&self.root);
+            match read_block_at(&path, *offset, block_info).await {
+                Ok(Some(buffer)) => return Some(buffer),
+                Ok(None) => debug!("Stale block at offset {offset} of file {path:?}."),
+                Err(e) => debug!("Fail to read block at offset {offset} of file {path:?}: {e:?}"),
+            }
+        }
+
+        None
+    }
+}
+
+async fn read_block_at(
+    path: &Path,
+    offset: u64,
+    block_info: &BlockInfo,
+) -> Result<Option<Vec<u8>>> {
+    // Read the block from the file.
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut buffer = vec![0u8; block_info.block_size() as usize];
+    file.read_exact(&mut buffer).await?;
+
+    // Make sure the file did not change since it was indexed.
+    let read_block_info = BlockInfo::from_buffer(
+        &buffer,
+        *block_info.path_id(),
+        offset,
+        block_info.hash_algorithm(),
+    );
+    if read_block_info.hash() != block_info.hash() {
+        return Ok(None);
+    }
+
+    Ok(Some(buffer))
+}
+
+fn block_key(block_info: &BlockInfo) -> (HashAlgorithm, BlockHash) {
+    (block_info.hash_algorithm(), *block_info.hash())
+}
+
+fn directory_hash(
+    hash_algorithm: HashAlgorithm,
+    entries: &BTreeMap<OsString, DirectoryEntry>,
+) -> BlockHash {
+    let mut buffer = Vec::new();
+    for entry in entries.values() {
+        match entry.kind() {
+            DirectoryEntryKind::File => buffer.push(0),
+            DirectoryEntryKind::Directory => buffer.push(1),
+        }
+
+        buffer.extend_from_slice(&name_bytes(entry.name()));
+        buffer.push(0);
+        buffer.extend_from_slice(entry.hash());
+    }
+
+    hash_algorithm.hash(&buffer)
+}
@@ -1,17 +1,171 @@
 mod certificate;
 mod client;
+mod config;
+mod control;
+mod device_id;
+mod diff;
+mod directory_sync;
 mod file_sync;
+mod folder;
+mod ignore;
+mod index;
+mod merkle_tree;
 mod messages;
+mod name_policy;
+mod node;
+mod outcome;
 mod path_id_cache;
+mod rate_limit;
+mod removal;
+mod scheduler;
 mod scraper;
 mod server;
+mod session;
+mod sync_summary;
+mod transfers;
+mod verify;
+mod versioning;
 
 use certificate::generate_self_signed_cert;
-use clap::Parser;
-use client::connect;
+use clap::{Args, Parser};
+use client::PeerAddress;
 use color_eyre::eyre::Result;
-use server::listen;
-use std::path::PathBuf;
+use config::{watch_config, Config};
+use control::{parse_time, print_response, send_request, Request, DEFAULT_CONTROL_SOCKET};
+use diff::print_folder_diff;
+use folder::{
+    FolderConfig, FolderModeSetting, FolderNamesSetting, FolderShare, FolderVersioningSetting,
+};
+use globset::Glob;
+use messages::{Compression, FolderId, FolderMode, HashAlgorithm, DEFAULT_COMPRESSION_LEVEL};
+use name_policy::{CaseSensitivity, NamePolicy, UnicodeNormalization};
+use node::Node;
+use rate_limit::{Rate, RateLimiter, RateLimits, RateSchedule};
+use session::{SessionOptions, DEFAULT_PARALLEL_TRANSFERS};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tracing::{error, level_filters::LevelFilter};
+use tracing_subscriber::{prelude::*, reload, Registry};
+use versioning::Versioning;
+
+const INCOMPLETE_SYNC_EXIT_CODE: i32 = 2;
+
+#[derive(Debug, Args)]
+struct RateLimitArgs {
+    /// Maximum upload rate to all peers together, in bytes per second with an optional K, M or G suffix.
+    #[arg(long)]
+    upload_limit: Option<Rate>,
+
+    /// Maximum download rate from all peers together.
+    #[arg(long)]
+    download_limit: Option<Rate>,
+
+    /// Maximum upload rate to each peer.
+    #[arg(long)]
+    peer_upload_limit: Option<Rate>,
+
+    /// Maximum download rate from each peer.
+    #[arg(long)]
+    peer_download_limit: Option<Rate>,
+
+    /// Limits to use instead of the global ones during part of the day, as HH:MM-HH:MM=UPLOAD/DOWNLOAD with `-` for no limit. Can be repeated.
+    #[arg(long = "limit-schedule")]
+    limit_schedules: Vec<RateSchedule>,
+}
+
+impl RateLimitArgs {
+    fn rate_limiter(self) -> RateLimiter {
+        RateLimiter::new(
+            RateLimits::new(self.upload_limit, self.download_limit),
+            RateLimits::new(self.peer_upload_limit, self.peer_download_limit),
+            self.limit_schedules,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+struct ControlArgs {
+    /// Control socket of the daemon.
+    #[arg(long, default_value = DEFAULT_CONTROL_SOCKET)]
+    control_socket: PathBuf,
+
+    /// Print the response of the daemon as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+impl ControlArgs {
+    async fn send(self, request: Request) -> Result<()> {
+        print_response(
+            send_request(&self.control_socket, &request).await?,
+            self.json,
+        )
+    }
+}
+
+#[derive(Debug, Args)]
+struct NodeArgs {
+    /// Preferred algorithm used to hash file blocks.
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::default())]
+    hash_algorithm: HashAlgorithm,
+
+    /// Preferred compression of file blocks sent to the peer.
+    #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+    compression: Compression,
+
+    /// Zstd compression level, higher levels trade speed for smaller transfers.
+    #[arg(long, default_value_t = DEFAULT_COMPRESSION_LEVEL, value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: i32,
+
+    #[command(flatten)]
+    rate_limits: RateLimitArgs,
+
+    /// Number of files transferred at the same time to each peer.
+    #[arg(long, default_value_t = DEFAULT_PARALLEL_TRANSFERS as u16, value_parser = clap::value_parser!(u16).range(1..))]
+    parallel_transfers: u16,
+
+    /// Glob of files to transfer before any other, like `src/**` or `*.md`. Earlier patterns go first. Can be repeated.
+    #[arg(long = "priority")]
+    priority_patterns: Vec<Glob>,
+
+    /// Glob of files to leave out of every folder, like `*.tmp` or `target`. Can be repeated.
+    #[arg(long = "ignore")]
+    ignore_patterns: Vec<Glob>,
+
+    /// Path to the file holding the identity of this device.
+    #[arg(long)]
+    device_id_filename: Option<String>,
+
+    /// Unix socket to accept control requests on, like the ones of the status or pause commands.
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+}
+
+impl NodeArgs {
+    async fn node(self, folders: Vec<FolderConfig>) -> Result<Arc<Node>> {
+        let folders = folders
+            .into_iter()
+            .map(|folder| folder.with_ignore_patterns(&self.ignore_patterns))
+            .collect();
+
+        Node::new(
+            folders,
+            SessionOptions::new(
+                self.hash_algorithm,
+                self.compression,
+                self.compression_level,
+                self.rate_limits.rate_limiter(),
+            )
+            .with_transfers(self.parallel_transfers.into(), self.priority_patterns),
+            self.device_id_filename,
+        )
+        .await
+    }
+}
 
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
@@ -31,7 +185,7 @@ enum Command {
     /// Listen for incoming connections.
     Listen {
         /// Address to listen on.
-        address: String,
+        address: SocketAddr,
 
         /// Path to folder to synchronize.
         source_path: PathBuf,
@@ -41,6 +195,25 @@ enum Command {
 
         /// Private certificate key.
         private_key_filename: Option<String>,
+
+        /// Whether the folder sends changes, receives them, or both.
+        #[arg(long, value_enum, default_value_t = FolderMode::default())]
+        mode: FolderMode,
+
+        /// How the folder keeps the files peers replace or remove: off, trash[:DAYS], simple[:COUNT] or staggered[:DAYS].
+        #[arg(long, default_value = "off")]
+        versioning: Versioning,
+
+        /// Unicode form used to store file names in the folder.
+        #[arg(long, value_enum, default_value_t = UnicodeNormalization::default())]
+        unicode_normalization: UnicodeNormalization,
+
+        /// Whether names that only differ in case refer to the same file in the folder.
+        #[arg(long, value_enum, default_value_t = CaseSensitivity::default())]
+        case_sensitivity: CaseSensitivity,
+
+        #[command(flatten)]
+        node: NodeArgs,
     },
 
     /// Connect to another client.
@@ -56,14 +229,262 @@ enum Command {
 
         /// Connection certificate.
         cert_filename: Option<String>,
+
+        /// Whether the folder sends changes, receives them, or both.
+        #[arg(long, value_enum, default_value_t = FolderMode::default())]
+        mode: FolderMode,
+
+        /// How the folder keeps the files peers replace or remove: off, trash[:DAYS], simple[:COUNT] or staggered[:DAYS].
+        #[arg(long, default_value = "off")]
+        versioning: Versioning,
+
+        /// Unicode form used to store file names in the folder.
+        #[arg(long, value_enum, default_value_t = UnicodeNormalization::default())]
+        unicode_normalization: UnicodeNormalization,
+
+        /// Whether names that only differ in case refer to the same file in the folder.
+        #[arg(long, value_enum, default_value_t = CaseSensitivity::default())]
+        case_sensitivity: CaseSensitivity,
+
+        #[command(flatten)]
+        node: NodeArgs,
+    },
+
+    /// Listen for peers and connect to others, forwarding changes between all of them.
+    Run {
+        /// Configuration file describing the node, its peers and folders, reloaded when it changes.
+        #[arg(long, conflicts_with_all = [
+            "folders",
+            "shares",
+            "folder_modes",
+            "folder_versionings",
+            "listen",
+            "peers",
+            "cert_filename",
+            "private_key_filename",
+            "hash_algorithm",
+            "compression",
+            "compression_level",
+            "folder_names",
+            "parallel_transfers",
+            "priority_patterns",
+            "ignore_patterns",
+            "device_id_filename",
+            "control_socket",
+            "RateLimitArgs",
+        ])]
+        config: Option<PathBuf>,
+
+        /// Folder to synchronize, as ID=PATH where peers must use the same id. Can be repeated.
+        #[arg(long = "folder", required_unless_present = "config")]
+        folders: Vec<FolderConfig>,
+
+        /// Device to share a folder with, as FOLDER_ID=DEVICE_ID. Folders not shared with any device are shared with every peer. Can be repeated.
+        #[arg(long = "share")]
+        shares: Vec<FolderShare>,
+
+        /// Mode of a folder, as FOLDER_ID=MODE with send-receive, send-only, receive-only or mirror. Can be repeated.
+        #[arg(long = "folder-mode")]
+        folder_modes: Vec<FolderModeSetting>,
+
+        /// How a folder keeps the files peers replace or remove, as FOLDER_ID=VERSIONING with off, trash[:DAYS], simple[:COUNT] or staggered[:DAYS]. Can be repeated.
+        #[arg(long = "folder-versioning")]
+        folder_versionings: Vec<FolderVersioningSetting>,
+
+        /// How a folder stores file names, as FOLDER_ID=NORMALIZATION[:CASE] with none, nfc or nfd and sensitive or insensitive. Can be repeated.
+        #[arg(long = "folder-names")]
+        folder_names: Vec<FolderNamesSetting>,
+
+        /// Address to listen on for peers. Can be repeated.
+        #[arg(long)]
+        listen: Vec<SocketAddr>,
+
+        /// Peer to stay connected to, as NAME@ADDRESS where the name must match its certificate. Can be repeated.
+        #[arg(long = "peer")]
+        peers: Vec<PeerAddress>,
+
+        /// Connection certificate.
+        #[arg(long)]
+        cert_filename: Option<String>,
+
+        /// Private certificate key, needed to listen.
+        #[arg(long)]
+        private_key_filename: Option<String>,
+
+        #[command(flatten)]
+        node: NodeArgs,
+    },
+
+    /// Sync once with a peer and exit, with status 2 when some files did not sync.
+    Sync {
+        /// Peer to sync with, as NAME@ADDRESS where the name must match its certificate.
+        peer: PeerAddress,
+
+        /// Folder to sync, as ID=PATH where the peer must use the same id. Can be repeated.
+        #[arg(long = "folder", required = true)]
+        folders: Vec<FolderConfig>,
+
+        /// Mode of a folder, as FOLDER_ID=MODE with send-receive, send-only, receive-only or mirror. Can be repeated.
+        #[arg(long = "folder-mode")]
+        folder_modes: Vec<FolderModeSetting>,
+
+        /// How a folder keeps the files the peer replaces or removes, as FOLDER_ID=VERSIONING with off, trash[:DAYS], simple[:COUNT] or staggered[:DAYS]. Can be repeated.
+        #[arg(long = "folder-versioning")]
+        folder_versionings: Vec<FolderVersioningSetting>,
+
+        /// How a folder stores file names, as FOLDER_ID=NORMALIZATION[:CASE] with none, nfc or nfd and sensitive or insensitive. Can be repeated.
+        #[arg(long = "folder-names")]
+        folder_names: Vec<FolderNamesSetting>,
+
+        /// Connection certificate.
+        #[arg(long)]
+        cert_filename: Option<String>,
+
+        #[command(flatten)]
+        node: NodeArgs,
+    },
+
+    /// Show what syncing with a peer would change, without changing anything.
+    Diff {
+        /// Peer to compare with, as NAME@ADDRESS where the name must match its certificate.
+        peer: PeerAddress,
+
+        /// Folder to compare, as ID=PATH where the peer must use the same id. Can be repeated.
+        #[arg(long = "folder", required = true)]
+        folders: Vec<FolderConfig>,
+
+        /// Mode of a folder, as FOLDER_ID=MODE with send-receive, send-only, receive-only or mirror. Can be repeated.
+        #[arg(long = "folder-mode")]
+        folder_modes: Vec<FolderModeSetting>,
+
+        /// How a folder stores file names, as FOLDER_ID=NORMALIZATION[:CASE] with none, nfc or nfd and sensitive or insensitive. Can be repeated.
+        #[arg(long = "folder-names")]
+        folder_names: Vec<FolderNamesSetting>,
+
+        /// Connection certificate.
+        #[arg(long)]
+        cert_filename: Option<String>,
+
+        #[command(flatten)]
+        node: NodeArgs,
+    },
+
+    /// Show how far the folders of a running daemon are in sync with its peers.
+    Status {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Stop synchronizing a folder on a running daemon until it is resumed.
+    Pause {
+        /// Folder to pause.
+        folder: String,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Synchronize a paused folder again, catching up with the changes made in the meantime.
+    Resume {
+        /// Folder to resume.
+        folder: String,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Look for changes the file watcher missed on a running daemon.
+    Rescan {
+        /// Folder to rescan, every folder when missing.
+        folder: Option<String>,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Rehash the files of a running daemon and report the ones that no longer match its index.
+    Verify {
+        /// Folder to verify, every folder when missing.
+        folder: Option<String>,
+
+        /// Also compare the index with the connected peers.
+        #[arg(long)]
+        peers: bool,
+
+        /// Rewrite corrupted blocks with intact copies from the folder or the peers.
+        #[arg(long)]
+        repair: bool,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Send the files of a send only folder over whatever its peers changed, removing what only they have.
+    Override {
+        /// Folder to override the peers of.
+        folder: String,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Bring back the files of the peers over the local changes of a receive only folder.
+    Revert {
+        /// Folder to revert the local changes of.
+        folder: String,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// List the versions a running daemon keeps of a file that peers replaced or removed.
+    Versions {
+        /// File to list the versions of.
+        path: PathBuf,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Bring back a version of a file on a running daemon, which syncs it to the peers as a new change.
+    Restore {
+        /// File to restore.
+        path: PathBuf,
+
+        /// Time the file had the content to bring back, like "2026-10-18 14:30". The latest version when missing.
+        #[arg(long, value_parser = parse_time)]
+        at: Option<SystemTime>,
+
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// List the peers of a running daemon.
+    Peers {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// List the files a running daemon is transferring.
+    Transfers {
+        #[command(flatten)]
+        control: ControlArgs,
+    },
+
+    /// Let a running daemon finish its transfers and exit.
+    Shutdown {
+        #[command(flatten)]
+        control: ControlArgs,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging.
-    tracing_subscriber::fmt()
-        // .with_max_level(LevelFilter::TRACE)
+    // Initialize logging, the configuration file may change the level while running.
+    let (log_level, log_level_handle) = reload::Layer::new(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(log_level)
+        .with(tracing_subscriber::fmt::layer())
         .init();
 
     // Initialize error report.
@@ -80,18 +501,229 @@ async fn main() -> Result<()> {
 
         Command::Listen {
             address,
+            source_path,
             cert_filename,
             private_key_filename,
-            source_path,
-        } => listen(&address, cert_filename, private_key_filename, source_path).await?,
+            mode,
+            versioning,
+            unicode_normalization,
+            case_sensitivity,
+            node,
+        } => {
+            let control_socket = node.control_socket.clone();
+            let folder = FolderConfig::new(FolderId::default(), source_path)
+                .with_mode(mode)
+                .with_versioning(versioning)
+                .with_name_policy(NamePolicy::new(unicode_normalization, case_sensitivity));
+            node.node(vec![folder])
+                .await?
+                .run(
+                    vec![address],
+                    Vec::new(),
+                    cert_filename,
+                    private_key_filename,
+                    control_socket,
+                )
+                .await?
+        }
 
         Command::Connect {
             server_name,
             address,
-            cert_filename,
             source_path,
-        } => connect(&server_name, &address, cert_filename, source_path).await?,
+            cert_filename,
+            mode,
+            versioning,
+            unicode_normalization,
+            case_sensitivity,
+            node,
+        } => {
+            let control_socket = node.control_socket.clone();
+            let folder = FolderConfig::new(FolderId::default(), source_path)
+                .with_mode(mode)
+                .with_versioning(versioning)
+                .with_name_policy(NamePolicy::new(unicode_normalization, case_sensitivity));
+            node.node(vec![folder])
+                .await?
+                .run(
+                    Vec::new(),
+                    vec![PeerAddress::new(server_name, address)],
+                    cert_filename,
+                    None,
+                    control_socket,
+                )
+                .await?
+        }
+
+        Command::Run {
+            config: Some(config_path),
+            ..
+        } => run_with_config(config_path, log_level_handle).await?,
+
+        Command::Run {
+            config: None,
+            mut folders,
+            shares,
+            folder_modes,
+            folder_versionings,
+            folder_names,
+            listen,
+            peers,
+            cert_filename,
+            private_key_filename,
+            node,
+        } => {
+            for share in shares {
+                share.apply(&mut folders)?;
+            }
+            for folder_mode in folder_modes {
+                folder_mode.apply(&mut folders)?;
+            }
+            for folder_versioning in folder_versionings {
+                folder_versioning.apply(&mut folders)?;
+            }
+            for folder_names in folder_names {
+                folder_names.apply(&mut folders)?;
+            }
+
+            let control_socket = node.control_socket.clone();
+            node.node(folders)
+                .await?
+                .run(
+                    listen,
+                    peers,
+                    cert_filename,
+                    private_key_filename,
+                    control_socket,
+                )
+                .await?
+        }
+
+        Command::Sync {
+            peer,
+            mut folders,
+            folder_modes,
+            folder_versionings,
+            folder_names,
+            cert_filename,
+            node,
+        } => {
+            for folder_mode in folder_modes {
+                folder_mode.apply(&mut folders)?;
+            }
+            for folder_versioning in folder_versionings {
+                folder_versioning.apply(&mut folders)?;
+            }
+            for folder_names in folder_names {
+                folder_names.apply(&mut folders)?;
+            }
+
+            let summary = node.node(folders).await?.sync(&peer, cert_filename).await?;
+            summary.print();
+            if !summary.is_complete() {
+                std::process::exit(INCOMPLETE_SYNC_EXIT_CODE);
+            }
+        }
+
+        Command::Diff {
+            peer,
+            mut folders,
+            folder_modes,
+            folder_names,
+            cert_filename,
+            node,
+        } => {
+            for folder_mode in folder_modes {
+                folder_mode.apply(&mut folders)?;
+            }
+            for folder_names in folder_names {
+                folder_names.apply(&mut folders)?;
+            }
+
+            for folder_diff in node.node(folders).await?.diff(&peer, cert_filename).await? {
+                print_folder_diff(&folder_diff);
+            }
+        }
+
+        Command::Status { control } => control.send(Request::Status).await?,
+        Command::Pause { folder, control } => control.send(Request::Pause { folder }).await?,
+        Command::Resume { folder, control } => control.send(Request::Resume { folder }).await?,
+        Command::Rescan { folder, control } => control.send(Request::Rescan { folder }).await?,
+        Command::Verify {
+            folder,
+            peers,
+            repair,
+            control,
+        } => {
+            control
+                .send(Request::Verify {
+                    folder,
+                    peers,
+                    repair,
+                })
+                .await?
+        }
+        Command::Override { folder, control } => control.send(Request::Override { folder }).await?,
+        Command::Revert { folder, control } => control.send(Request::Revert { folder }).await?,
+        Command::Versions { path, control } => {
+            let path = absolute_path(&path)?;
+            control.send(Request::Versions { path }).await?
+        }
+        Command::Restore { path, at, control } => {
+            let path = absolute_path(&path)?;
+            control.send(Request::Restore { path, at }).await?
+        }
+        Command::Peers { control } => control.send(Request::Peers).await?,
+        Command::Transfers { control } => control.send(Request::Transfers).await?,
+        Command::Shutdown { control } => control.send(Request::Shutdown).await?,
     }
 
     Ok(())
 }
+
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    // The daemon names files by their real path, which the removed file itself no longer has.
+    let path = std::path::absolute(path)?;
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(path);
+    };
+
+    Ok(parent
+        .canonicalize()
+        .map_or_else(|_| path.clone(), |parent| parent.join(name)))
+}
+
+async fn run_with_config(
+    config_path: PathBuf,
+    log_level: reload::Handle<LevelFilter, Registry>,
+) -> Result<()> {
+    let config = Config::load(&config_path)?;
+    log_level.modify(|level| *level = config.log_level())?;
+
+    let node = Node::new(
+        config.folders(),
+        config.session_options(),
+        config.device_id_filename(),
+    )
+    .await?;
+
+    // Apply changes to the configuration while running.
+    tokio::spawn({
+        let config = config.clone();
+        let node = node.clone();
+        async move {
+            if let Err(e) = watch_config(config_path, config, node, log_level).await {
+                error!("Configuration is no longer reloaded: {e:?}");
+            }
+        }
+    });
+
+    node.run(
+        config.listen().to_vec(),
+        config.peers().to_vec(),
+        config.cert_filename(),
+        config.private_key_filename(),
+        config.control_socket(),
+    )
+    .await
+}
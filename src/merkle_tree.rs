@@ -0,0 +1,152 @@
+use crate::messages::{BlockHash, HashAlgorithm};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    hash_algorithm: HashAlgorithm,
+    levels: Vec<Vec<BlockHash>>,
+}
+
+impl MerkleTree {
+    pub fn new(hash_algorithm: HashAlgorithm, leaves: Vec<BlockHash>) -> Self {
+        // An empty file still needs a root.
+        let leaves = if leaves.is_empty() {
+            vec![hash_algorithm.hash(&[])]
+        } else {
+            leaves
+        };
+
+        // Leaves and inner nodes are hashed apart, so a block never passes for a pair of blocks.
+        let leaves: Vec<_> = leaves
+            .iter()
+            .map(|leaf| hash_algorithm.hash(&[&[LEAF_PREFIX], &leaf[..]].concat()))
+            .collect();
+
+        // Hash pairs of nodes until a single root is left. Odd nodes are promoted as is.
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|nodes| match nodes {
+                    [left, right] => {
+                        hash_algorithm.hash(&[&[NODE_PREFIX], &left[..], &right[..]].concat())
+                    }
+                    [node] => *node,
+
+                    _ => unreachable!(),
+                })
+                .collect();
+
+            levels.push(level);
+        }
+
+        Self {
+            hash_algorithm,
+            levels,
+        }
+    }
+
+    pub fn root(&self) -> &BlockHash {
+        &self.levels.last().unwrap()[0]
+    }
+
+    pub fn differing_leaves(&self, other: &MerkleTree) -> Vec<usize> {
+        // Hashes from different algorithms can't be compared.
+        if self.hash_algorithm != other.hash_algorithm {
+            return (0..self.levels[0].len()).collect();
+        }
+
+        // Descend from the root, only following the nodes that differ.
+        let mut candidates = vec![0];
+        for level in (0..self.levels.len()).rev() {
+            candidates.retain(|index| self.node(level, *index) != other.node(level, *index));
+            if level == 0 {
+                break;
+            }
+
+            let children_len = self.levels[level - 1].len();
+            candidates = candidates
+                .into_iter()
+                .flat_map(|index| [index * 2, index * 2 + 1])
+                .filter(|index| *index < children_len)
+                .collect();
+        }
+
+        candidates
+    }
+
+    fn node(&self, level: usize, index: usize) -> Option<&BlockHash> {
+        self.levels.get(level)?.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MerkleTree;
+    use crate::messages::{FileInfo, HashAlgorithm, WirePath};
+    use std::time::SystemTime;
+
+    #[test]
+    fn differing_leaves() {
+        // Create two trees that only differ on a couple of leaves.
+        let leaves: Vec<_> = (0..11).map(|leaf| [leaf; 32]).collect();
+        let mut other_leaves = leaves.clone();
+        other_leaves[3] = [100; 32];
+        other_leaves[10] = [101; 32];
+
+        let tree = MerkleTree::new(HashAlgorithm::Blake3, leaves.clone());
+        let other_tree = MerkleTree::new(HashAlgorithm::Blake3, other_leaves);
+
+        // Make sure equal trees have equal roots and no differences.
+        assert_eq!(
+            tree.root(),
+            MerkleTree::new(HashAlgorithm::Blake3, leaves).root()
+        );
+        assert!(tree.differing_leaves(&tree).is_empty());
+
+        // Make sure only the changed leaves are reported.
+        assert_ne!(tree.root(), other_tree.root());
+        assert_eq!(tree.differing_leaves(&other_tree), vec![3, 10]);
+    }
+
+    #[test]
+    fn leaves_never_pass_for_nodes() {
+        // A single block holding the hashes of two blocks used to have the root of those two blocks.
+        let hash_algorithm = HashAlgorithm::Blake3;
+        let leaves = vec![hash_algorithm.hash(b"x"), hash_algorithm.hash(b"y")];
+        let forged_block = leaves.concat();
+        let tree = MerkleTree::new(hash_algorithm, leaves);
+        let forged_tree = MerkleTree::new(hash_algorithm, vec![hash_algorithm.hash(&forged_block)]);
+        assert_ne!(tree.root(), forged_tree.root());
+
+        // Make sure files of different shapes never have the same content, whatever their roots.
+        let path = WirePath::from_relative("notes.txt".as_ref()).unwrap();
+        let file_info = FileInfo::new(
+            path.clone(),
+            2,
+            2,
+            1,
+            SystemTime::UNIX_EPOCH,
+            hash_algorithm,
+            *tree.root(),
+        );
+        let forged_file_info = FileInfo::new(
+            path,
+            forged_block.len() as u64,
+            1,
+            forged_block.len() as u32,
+            SystemTime::UNIX_EPOCH,
+            hash_algorithm,
+            *tree.root(),
+        );
+        assert!(file_info.has_same_content(&file_info));
+        assert!(!file_info.has_same_content(&forged_file_info));
+
+        // Make sure directories don't take them for the same file either.
+        assert_ne!(file_info.content_hash(), forged_file_info.content_hash());
+    }
+}
@@ -0,0 +1,61 @@
+use super::PathId;
+use bytes::BufMut;
+use std::io::ErrorKind;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Ack {
+    path_id: PathId,
+}
+
+impl Ack {
+    pub fn new(path_id: PathId) -> Self {
+        Self { path_id }
+    }
+
+    pub fn path_id(&self) -> &PathId {
+        &self.path_id
+    }
+}
+
+pub struct AckEncoder;
+
+impl Encoder<&Ack> for AckEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &Ack, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        // Write path identifier.
+        dst.put_slice(item.path_id());
+
+        Ok(())
+    }
+}
+
+pub struct AckDecoder;
+
+impl Decoder for AckDecoder {
+    type Item = Ack;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read path identifier.
+        const PATH_ID_SIZE: usize = std::mem::size_of::<PathId>();
+        if src.len() < PATH_ID_SIZE {
+            src.reserve(PATH_ID_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let path_id = src.split_to(PATH_ID_SIZE);
+        let path_id = path_id.to_vec();
+        let path_id = path_id.try_into().map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Fail to parse path id: {e:?}"),
+            )
+        })?;
+
+        // Return object.
+        Ok(Some(Ack { path_id }))
+    }
+}
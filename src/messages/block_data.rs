@@ -0,0 +1,149 @@
+use super::{
+    varint::{decode_bytes, put_bytes},
+    Compression, PathId,
+};
+use bytes::{Buf, BufMut, Bytes};
+use std::io::ErrorKind;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockData {
+    path_id: PathId,
+    offset: u64,
+    compression: Compression,
+    data: Bytes,
+}
+
+impl BlockData {
+    pub fn new(path_id: PathId, offset: u64, data: Bytes) -> Self {
+        Self {
+            path_id,
+            offset,
+            compression: Compression::None,
+            data,
+        }
+    }
+
+    pub fn compressed(
+        path_id: PathId,
+        offset: u64,
+        data: &[u8],
+        compression: Compression,
+        level: i32,
+    ) -> Result<Self, std::io::Error> {
+        // Send the block as is when compressing doesn't make it any smaller.
+        let compressed_data = compression.compress(data, level)?;
+        if compressed_data.len() >= data.len() {
+            return Ok(Self::new(path_id, offset, data.to_vec().into()));
+        }
+
+        Ok(Self {
+            path_id,
+            offset,
+            compression,
+            data: compressed_data.into(),
+        })
+    }
+
+    pub fn path_id(&self) -> &PathId {
+        &self.path_id
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn decompress(&self, size: usize) -> Result<Bytes, std::io::Error> {
+        match self.compression {
+            Compression::None => Ok(self.data.clone()),
+            compression => Ok(compression.decompress(&self.data, size)?.into()),
+        }
+    }
+}
+
+pub struct BlockDataEncoder;
+
+impl Encoder<&BlockData> for BlockDataEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &BlockData, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        // Write file identifier.
+        dst.put_slice(item.path_id());
+
+        // Write offset.
+        dst.put_u64_le(item.offset);
+
+        // Write compression.
+        dst.put_u8(item.compression.id());
+
+        // Write block data.
+        put_bytes(dst, &item.data);
+
+        Ok(())
+    }
+}
+
+pub struct BlockDataDecoder;
+
+impl Decoder for BlockDataDecoder {
+    type Item = BlockData;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read path identifier.
+        const PATH_ID_SIZE: usize = std::mem::size_of::<PathId>();
+        if src.len() < PATH_ID_SIZE {
+            src.reserve(PATH_ID_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let path_id = src.split_to(PATH_ID_SIZE);
+        let path_id = path_id.to_vec();
+        let path_id = path_id.try_into().map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Fail to parse path id: {e:?}"),
+            )
+        })?;
+
+        // Read offset and compression.
+        if src.len() < 9 {
+            src.reserve(9_usize.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let offset = src.get_u64_le();
+
+        let compression = src.get_u8();
+        let compression = Compression::from_id(compression).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown compression: {compression}"),
+            )
+        })?;
+
+        // Read block data.
+        let Some(data) = decode_bytes(src)? else {
+            return Ok(None);
+        };
+        let data = data.freeze();
+
+        // Return object.
+        Ok(Some(BlockData {
+            path_id,
+            offset,
+            compression,
+            data,
+        }))
+    }
+}
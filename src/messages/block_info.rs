@@ -1,42 +1,45 @@
-use super::PathId;
+use super::{BlockHash, HashAlgorithm, PathId};
 use bytes::{Buf, BufMut};
-use md5::{Digest, Md5};
 use std::io::ErrorKind;
 use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct BlockInfo {
     path_id: PathId,
     offset: u64,
     block_size: u32,
-    hash: u128,
+    hash_algorithm: HashAlgorithm,
+    hash: BlockHash,
 }
 
 impl BlockInfo {
-    pub fn new(path_id: PathId, offset: u64, block_size: u32, hash: u128) -> Self {
+    pub fn new(
+        path_id: PathId,
+        offset: u64,
+        block_size: u32,
+        hash_algorithm: HashAlgorithm,
+        hash: BlockHash,
+    ) -> Self {
         Self {
             path_id,
             offset,
             block_size,
+            hash_algorithm,
             hash,
         }
     }
 
-    pub fn from_buffer(buffer: &[u8], path_id: PathId, offset: u64) -> Self {
+    pub fn from_buffer(
+        buffer: &[u8],
+        path_id: PathId,
+        offset: u64,
+        hash_algorithm: HashAlgorithm,
+    ) -> Self {
         // Calculate block hash.
-        let mut hasher = Md5::new();
-        hasher.update(buffer);
-
-        let hash = hasher.finalize();
-        let hash = u128::from_le_bytes(hash.try_into().unwrap());
+        let hash = hash_algorithm.hash(buffer);
 
         // Return object.
-        Self {
-            path_id,
-            offset,
-            block_size: buffer.len() as u32,
-            hash,
-        }
+        Self::new(path_id, offset, buffer.len() as u32, hash_algorithm, hash)
     }
 
     pub fn path_id(&self) -> &PathId {
@@ -51,8 +54,12 @@ impl BlockInfo {
         self.block_size
     }
 
-    pub fn hash(&self) -> u128 {
-        self.hash
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn hash(&self) -> &BlockHash {
+        &self.hash
     }
 }
 
@@ -72,7 +79,8 @@ impl Encoder<&BlockInfo> for BlockInfoEncoder {
         dst.put_u32_le(item.block_size);
 
         // Write hash.
-        dst.put_u128_le(item.hash);
+        dst.put_u8(item.hash_algorithm.id());
+        dst.put_slice(&item.hash);
 
         Ok(())
     }
@@ -121,19 +129,30 @@ impl Decoder for BlockInfoDecoder {
         let block_size = src.get_u32_le();
 
         // Read hash.
-        if src.len() < 16 {
-            src.reserve(16_usize.saturating_sub(src.len()));
+        const HASH_SIZE: usize = 1 + std::mem::size_of::<BlockHash>();
+        if src.len() < HASH_SIZE {
+            src.reserve(HASH_SIZE.saturating_sub(src.len()));
 
             return Ok(None);
         }
 
-        let hash = src.get_u128_le();
+        let hash_algorithm = src.get_u8();
+        let hash_algorithm = HashAlgorithm::from_id(hash_algorithm).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid hash algorithm: {hash_algorithm}"),
+            )
+        })?;
+
+        let mut hash = BlockHash::default();
+        src.copy_to_slice(&mut hash);
 
         // Return object.
         Ok(Some(BlockInfo {
             path_id,
             offset,
             block_size,
+            hash_algorithm,
             hash,
         }))
     }
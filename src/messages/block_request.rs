@@ -0,0 +1,97 @@
+use super::{
+    varint::{decode_len, put_varint},
+    PathId,
+};
+use bytes::{Buf, BufMut};
+use std::io::ErrorKind;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockRequest {
+    path_id: PathId,
+    offsets: Vec<u64>,
+}
+
+impl BlockRequest {
+    pub fn new(path_id: PathId, offsets: Vec<u64>) -> Self {
+        Self { path_id, offsets }
+    }
+
+    pub fn path_id(&self) -> &PathId {
+        &self.path_id
+    }
+
+    pub fn offsets(&self) -> &[u64] {
+        &self.offsets
+    }
+}
+
+pub struct BlockRequestEncoder;
+
+impl Encoder<&BlockRequest> for BlockRequestEncoder {
+    type Error = std::io::Error;
+
+    fn encode(
+        &mut self,
+        item: &BlockRequest,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        // Write file identifier.
+        dst.put_slice(item.path_id());
+
+        // Write offsets.
+        put_varint(dst, item.offsets.len() as u64);
+        for offset in &item.offsets {
+            dst.put_u64_le(*offset);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct BlockRequestDecoder;
+
+impl Decoder for BlockRequestDecoder {
+    type Item = BlockRequest;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read path identifier.
+        const PATH_ID_SIZE: usize = std::mem::size_of::<PathId>();
+        if src.len() < PATH_ID_SIZE {
+            src.reserve(PATH_ID_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let path_id = src.split_to(PATH_ID_SIZE);
+        let path_id = path_id.to_vec();
+        let path_id = path_id.try_into().map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Fail to parse path id: {e:?}"),
+            )
+        })?;
+
+        // Read offsets.
+        let Some(number_offsets) = decode_len(src)? else {
+            return Ok(None);
+        };
+        let offsets_len = number_offsets.checked_mul(8).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Too many offsets: {number_offsets}"),
+            )
+        })?;
+        if src.len() < offsets_len {
+            src.reserve(offsets_len.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let offsets = (0..number_offsets).map(|_| src.get_u64_le()).collect();
+
+        // Return object.
+        Ok(Some(BlockRequest { path_id, offsets }))
+    }
+}
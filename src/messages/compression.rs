@@ -0,0 +1,50 @@
+use clap::ValueEnum;
+use std::io::ErrorKind;
+
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::None),
+            1 => Some(Self::Zstd),
+            2 => Some(Self::Lz4),
+
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zstd => 1,
+            Self::Lz4 => 2,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Zstd => zstd::bulk::compress(data, level),
+            Self::Lz4 => Ok(lz4_flex::compress(data)),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8], size: usize) -> Result<Vec<u8>, std::io::Error> {
+        // Never inflate past the size of the block, a peer could otherwise exhaust our memory.
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Zstd => zstd::bulk::decompress(data, size),
+            Self::Lz4 => lz4_flex::decompress(data, size)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use super::{
+    path_encoding::{decode_os_string, put_os_str},
+    varint::{decode_len, put_varint},
+    wire_path::{check_name, decode_wire_path, put_wire_path},
+    BlockHash, HashAlgorithm, WirePath,
+};
+use bytes::{Buf, BufMut};
+use std::{
+    ffi::{OsStr, OsString},
+    io::ErrorKind,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DirectoryEntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DirectoryEntry {
+    name: OsString,
+    kind: DirectoryEntryKind,
+    hash: BlockHash,
+}
+
+impl DirectoryEntry {
+    pub fn new(name: OsString, kind: DirectoryEntryKind, hash: BlockHash) -> Self {
+        Self { name, kind, hash }
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    pub fn kind(&self) -> DirectoryEntryKind {
+        self.kind
+    }
+
+    pub fn hash(&self) -> &BlockHash {
+        &self.hash
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DirectoryInfo {
+    path: WirePath,
+    hash_algorithm: HashAlgorithm,
+    hash: BlockHash,
+    entries: Vec<DirectoryEntry>,
+}
+
+impl DirectoryInfo {
+    pub fn new(
+        path: WirePath,
+        hash_algorithm: HashAlgorithm,
+        hash: BlockHash,
+        entries: Vec<DirectoryEntry>,
+    ) -> Self {
+        Self {
+            path,
+            hash_algorithm,
+            hash,
+            entries,
+        }
+    }
+
+    pub fn path(&self) -> &WirePath {
+        &self.path
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn entries(&self) -> &[DirectoryEntry] {
+        &self.entries
+    }
+
+    pub fn has_same_content(&self, other: &DirectoryInfo) -> bool {
+        self.hash_algorithm == other.hash_algorithm && self.hash == other.hash
+    }
+}
+
+pub struct DirectoryInfoEncoder;
+
+impl Encoder<&DirectoryInfo> for DirectoryInfoEncoder {
+    type Error = std::io::Error;
+
+    fn encode(
+        &mut self,
+        item: &DirectoryInfo,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        // Write directory path.
+        put_wire_path(dst, &item.path)?;
+
+        // Write directory hash.
+        dst.put_u8(item.hash_algorithm.id());
+        dst.put_slice(&item.hash);
+
+        // Write entries.
+        put_varint(dst, item.entries.len() as u64);
+        for entry in &item.entries {
+            put_os_str(dst, &entry.name)?;
+
+            match entry.kind {
+                DirectoryEntryKind::File => dst.put_u8(0),
+                DirectoryEntryKind::Directory => dst.put_u8(1),
+            }
+
+            dst.put_slice(&entry.hash);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct DirectoryInfoDecoder;
+
+impl Decoder for DirectoryInfoDecoder {
+    type Item = DirectoryInfo;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read directory path.
+        let Some(path) = decode_wire_path(src)? else {
+            return Ok(None);
+        };
+
+        // Read directory hash.
+        const HASH_SIZE: usize = 1 + std::mem::size_of::<BlockHash>();
+        if src.len() < HASH_SIZE {
+            src.reserve(HASH_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let hash_algorithm = src.get_u8();
+        let hash_algorithm = HashAlgorithm::from_id(hash_algorithm).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid hash algorithm: {hash_algorithm}"),
+            )
+        })?;
+
+        let mut hash = BlockHash::default();
+        src.copy_to_slice(&mut hash);
+
+        // Read entries.
+        let Some(number_entries) = decode_len(src)? else {
+            return Ok(None);
+        };
+        let mut entries = Vec::new();
+        for _ in 0..number_entries {
+            let Some(name) = decode_os_string(src)? else {
+                return Ok(None);
+            };
+
+            // Entries are joined to the directory path, they must not lead out of it.
+            check_name(&name)?;
+
+            const ENTRY_SIZE: usize = 1 + std::mem::size_of::<BlockHash>();
+            if src.len() < ENTRY_SIZE {
+                src.reserve(ENTRY_SIZE.saturating_sub(src.len()));
+
+                return Ok(None);
+            }
+
+            let kind = src.get_u8();
+            let kind = match kind {
+                0 => DirectoryEntryKind::File,
+                1 => DirectoryEntryKind::Directory,
+
+                _ => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid directory entry kind: {kind}"),
+                    ))
+                }
+            };
+
+            let mut hash = BlockHash::default();
+            src.copy_to_slice(&mut hash);
+
+            entries.push(DirectoryEntry { name, kind, hash });
+        }
+
+        // Return object.
+        Ok(Some(DirectoryInfo {
+            path,
+            hash_algorithm,
+            hash,
+            entries,
+        }))
+    }
+}
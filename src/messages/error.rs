@@ -0,0 +1,172 @@
+use super::{
+    varint::{decode_string, put_bytes},
+    PathId,
+};
+use bytes::{Buf, BufMut};
+use color_eyre::Report;
+use std::{fmt::Display, io::ErrorKind};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    #[default]
+    Unknown,
+    Io,
+    NotFound,
+    PermissionDenied,
+    Corrupted,
+    Protocol,
+    Disconnected,
+    Conflict,
+}
+
+impl ErrorCode {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Unknown),
+            1 => Some(Self::Io),
+            2 => Some(Self::NotFound),
+            3 => Some(Self::PermissionDenied),
+            4 => Some(Self::Corrupted),
+            5 => Some(Self::Protocol),
+            6 => Some(Self::Disconnected),
+            7 => Some(Self::Conflict),
+
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Unknown => 0,
+            Self::Io => 1,
+            Self::NotFound => 2,
+            Self::PermissionDenied => 3,
+            Self::Corrupted => 4,
+            Self::Protocol => 5,
+            Self::Disconnected => 6,
+            Self::Conflict => 7,
+        }
+    }
+
+    pub fn from_report(report: &Report) -> Self {
+        // Errors reported by the peer keep their code.
+        if let Some(error) = report.downcast_ref::<Error>() {
+            return error.code;
+        }
+
+        let Some(error) = report.downcast_ref::<std::io::Error>() else {
+            return Self::Unknown;
+        };
+
+        match error.kind() {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::PermissionDenied => Self::PermissionDenied,
+            ErrorKind::InvalidData => Self::Corrupted,
+            ErrorKind::InvalidInput => Self::Protocol,
+            ErrorKind::AlreadyExists => Self::Conflict,
+            ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof => Self::Disconnected,
+
+            _ => Self::Io,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Unknown | Self::Io | Self::Corrupted)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Error {
+    code: ErrorCode,
+    path_id: PathId,
+    message: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, path_id: PathId, message: String) -> Self {
+        Self {
+            code,
+            path_id,
+            message,
+        }
+    }
+
+    pub fn from_report(path_id: PathId, report: &Report) -> Self {
+        Self::new(
+            ErrorCode::from_report(report),
+            path_id,
+            format!("{report:#}"),
+        )
+    }
+
+    pub fn path_id(&self) -> &PathId {
+        &self.path_id
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer failed with {:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub struct ErrorEncoder;
+
+impl Encoder<&Error> for ErrorEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &Error, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        // Write error code.
+        dst.put_u8(item.code.id());
+
+        // Write path identifier.
+        dst.put_slice(item.path_id());
+
+        // Write message.
+        put_bytes(dst, item.message.as_bytes());
+
+        Ok(())
+    }
+}
+
+pub struct ErrorDecoder;
+
+impl Decoder for ErrorDecoder {
+    type Item = Error;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read error code and path identifier.
+        const HEADER_SIZE: usize = 1 + std::mem::size_of::<PathId>();
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        // Codes unknown to this version are still failures.
+        let code = ErrorCode::from_id(src.get_u8()).unwrap_or_default();
+
+        let mut path_id = PathId::default();
+        src.copy_to_slice(&mut path_id);
+
+        // Read message.
+        let Some(message) = decode_string(src)? else {
+            return Ok(None);
+        };
+
+        // Return object.
+        Ok(Some(Error {
+            code,
+            path_id,
+            message,
+        }))
+    }
+}
@@ -1,31 +1,44 @@
+use super::{
+    varint::{decode_varint, put_varint},
+    wire_path::{decode_wire_path, put_wire_path},
+    BlockHash, BlockInfo, HashAlgorithm, WirePath,
+};
+use crate::{merkle_tree::MerkleTree, path_id_cache::PathIdCache};
 use bytes::{Buf, BufMut};
 use color_eyre::Result;
-use md5::Digest;
 use std::{
-    io::ErrorKind,
-    path::{Path, PathBuf},
+    fs::File,
+    io::{ErrorKind, Read},
+    path::Path,
     time::{Duration, SystemTime},
 };
 use tokio_util::codec::{Decoder, Encoder};
 
 pub type PathId = [u8; 32];
 
+pub const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct FileInfo {
-    path: PathBuf,
+    path: WirePath,
     size: u64,
-    number_blocks: u32,
+    number_blocks: u64,
     block_size: u32,
     last_modified: SystemTime,
+    hash_algorithm: HashAlgorithm,
+    root_hash: BlockHash,
+    version: u64,
 }
 
 impl FileInfo {
     pub fn new(
-        path: PathBuf,
+        path: WirePath,
         size: u64,
-        number_blocks: u32,
+        number_blocks: u64,
         block_size: u32,
         last_modified: SystemTime,
+        hash_algorithm: HashAlgorithm,
+        root_hash: BlockHash,
     ) -> Self {
         Self {
             path,
@@ -33,22 +46,78 @@ impl FileInfo {
             number_blocks,
             block_size,
             last_modified,
+            hash_algorithm,
+            root_hash,
+            version: 0,
         }
     }
 
-    pub fn with_file(path: impl AsRef<Path>) -> Result<Self> {
-        let path = path.as_ref();
-        let metadata = path.metadata()?;
+    pub fn removed(path: WirePath, removed: SystemTime, hash_algorithm: HashAlgorithm) -> Self {
+        Self::new(path, 0, 0, 0, removed, hash_algorithm, BlockHash::default())
+    }
+
+    pub fn with_blocks(
+        local_path: &Path,
+        path: WirePath,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<(Self, Vec<BlockInfo>)> {
+        let metadata = local_path.metadata()?;
         let size = metadata.len();
         let last_modified = metadata.modified()?;
-        let block_size: u32 = if size < 250 * 1024 * 1024 {
+        let block_size = Self::block_size_for(size);
+
+        // Hash every block of the file.
+        let path_id = PathIdCache::calculate_path_id(&path);
+        let mut file = File::open(local_path)?;
+        let mut buffer = vec![0u8; block_size as usize];
+        let mut block_infos = Vec::new();
+        let mut offset = 0;
+        loop {
+            let bytes_read = read_block(&mut file, &mut buffer)?;
+
+            // Check if we reached the end of the file.
+            if bytes_read == 0 {
+                break;
+            }
+
+            let block_info =
+                BlockInfo::from_buffer(&buffer[0..bytes_read], path_id, offset, hash_algorithm);
+            block_infos.push(block_info);
+
+            offset += bytes_read as u64;
+        }
+
+        // Summarize the blocks on a merkle tree.
+        let merkle_tree = MerkleTree::new(
+            hash_algorithm,
+            block_infos
+                .iter()
+                .map(|block_info| *block_info.hash())
+                .collect(),
+        );
+
+        let file_info = FileInfo::new(
+            path,
+            size,
+            block_infos.len() as u64,
+            block_size,
+            last_modified,
+            hash_algorithm,
+            *merkle_tree.root(),
+        );
+
+        Ok((file_info, block_infos))
+    }
+
+    pub fn block_size_for(size: u64) -> u32 {
+        if size < 250 * 1024 * 1024 {
             128 * 1024
         } else if size < 500 * 1024 * 1024 {
             256 * 1024
-        } else if size < 1 * 1024 * 1024 * 1024 {
+        } else if size < 1024 * 1024 * 1024 {
             512 * 1024
         } else if size < 2 * 1024 * 1024 * 1024 {
-            1 * 1024 * 1024
+            1024 * 1024
         } else if size < 4 * 1024 * 1024 * 1024 {
             2 * 1024 * 1024
         } else if size < 8 * 1024 * 1024 * 1024 {
@@ -56,21 +125,57 @@ impl FileInfo {
         } else if size < 16 * 1024 * 1024 * 1024 {
             8 * 1024 * 1024
         } else {
-            16 * 1024 * 1024
-        };
+            MAX_BLOCK_SIZE
+        }
+    }
 
-        let number_blocks = (size as f32 / block_size as f32).ceil() as u32;
+    pub fn check_blocks(&self) -> std::io::Result<()> {
+        // The file must split into whole blocks of a size we are willing to hold.
+        let is_valid = self.block_size > 0
+            && self.block_size <= MAX_BLOCK_SIZE
+            && self.number_blocks == self.size.div_ceil(self.block_size as u64);
+        if !is_valid {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "File {:?} of {} bytes can't have {} blocks of {} bytes.",
+                    self.path, self.size, self.number_blocks, self.block_size
+                ),
+            ));
+        }
 
-        Ok(FileInfo::new(
-            path.to_owned(),
-            size,
-            number_blocks,
-            block_size,
-            last_modified,
-        ))
+        Ok(())
+    }
+
+    pub fn check_block(&self, block_index: u64, block_info: &BlockInfo) -> std::io::Result<()> {
+        // Every block starts where the previous one ended and only the last may be shorter.
+        let offset = block_index * self.block_size as u64;
+        let block_size = (self.size - offset).min(self.block_size as u64);
+        if block_info.offset() != offset
+            || block_info.block_size() as u64 != block_size
+            || block_info.hash_algorithm() != self.hash_algorithm
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Block {block_index} of file {:?} does not match the file size.",
+                    self.path
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn with_path(self, path: WirePath) -> Self {
+        Self { path, ..self }
+    }
+
+    pub fn with_version(self, version: u64) -> Self {
+        Self { version, ..self }
     }
 
-    pub fn path(&self) -> &Path {
+    pub fn path(&self) -> &WirePath {
         &self.path
     }
 
@@ -78,7 +183,7 @@ impl FileInfo {
         self.size
     }
 
-    pub fn number_blocks(&self) -> u32 {
+    pub fn number_blocks(&self) -> u64 {
         self.number_blocks
     }
 
@@ -89,6 +194,53 @@ impl FileInfo {
     pub fn last_modified(&self) -> &SystemTime {
         &self.last_modified
     }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn is_removed(&self) -> bool {
+        // Existing files are always cut in blocks, even empty ones.
+        self.block_size == 0
+    }
+
+    pub fn content_hash(&self) -> BlockHash {
+        // Directories summarize their files with the shape of the blocks as well as their root.
+        let mut buffer = self.root_hash.to_vec();
+        buffer.extend_from_slice(&self.size.to_le_bytes());
+        buffer.extend_from_slice(&self.block_size.to_le_bytes());
+        buffer.extend_from_slice(&self.number_blocks.to_le_bytes());
+
+        self.hash_algorithm.hash(&buffer)
+    }
+
+    pub fn has_same_content(&self, other: &FileInfo) -> bool {
+        // The root only speaks for blocks cut the same way.
+        self.hash_algorithm == other.hash_algorithm
+            && self.root_hash == other.root_hash
+            && self.size == other.size
+            && self.block_size == other.block_size
+            && self.number_blocks == other.number_blocks
+    }
+}
+
+fn read_block(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    // Fill the buffer unless the end of the file is reached first.
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        let read = file.read(&mut buffer[bytes_read..])?;
+        if read == 0 {
+            break;
+        }
+
+        bytes_read += read;
+    }
+
+    Ok(bytes_read)
 }
 
 pub struct FileInfoEncoder;
@@ -98,17 +250,13 @@ impl Encoder<&FileInfo> for FileInfoEncoder {
 
     fn encode(&mut self, item: &FileInfo, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
         // Write file path.
-        let path = item.path.to_str().ok_or_else(|| {
-            std::io::Error::new(ErrorKind::Other, "Fail to convert path to string.")
-        })?;
-        dst.put_u16_le(path.len() as u16);
-        dst.put(path.as_bytes());
+        put_wire_path(dst, &item.path)?;
 
         // Write file size.
         dst.put_u64_le(item.size);
 
         // Write number of blocks.
-        dst.put_u32_le(item.number_blocks);
+        put_varint(dst, item.number_blocks);
 
         // Write block size.
         dst.put_u32_le(item.block_size);
@@ -121,6 +269,13 @@ impl Encoder<&FileInfo> for FileInfoEncoder {
         let last_modified = last_modified.as_nanos() as u64;
         dst.put_u64_le(last_modified);
 
+        // Write root hash.
+        dst.put_u8(item.hash_algorithm.id());
+        dst.put_slice(&item.root_hash);
+
+        // Write version.
+        put_varint(dst, item.version);
+
         Ok(())
     }
 }
@@ -133,28 +288,9 @@ impl Decoder for FileInfoDecoder {
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // Read file path.
-        if src.len() < 2 {
-            src.reserve(2_usize.saturating_sub(src.len()));
-
-            return Ok(None);
-        }
-
-        let path_len = src.get_u16_le() as usize;
-        if src.len() < path_len {
-            src.reserve(path_len.saturating_sub(src.len()));
-
+        let Some(path) = decode_wire_path(src)? else {
             return Ok(None);
-        }
-
-        let path = src.split_to(path_len);
-        let path = path.to_vec();
-        let path = String::from_utf8(path).map_err(|e| {
-            std::io::Error::new(
-                ErrorKind::Other,
-                format!("Unable to encode path string: {e:?}"),
-            )
-        })?;
-        let path = PathBuf::from(path);
+        };
 
         // Read file size.
         if src.len() < 8 {
@@ -166,13 +302,9 @@ impl Decoder for FileInfoDecoder {
         let size = src.get_u64_le();
 
         // Read number of blocks.
-        if src.len() < 4 {
-            src.reserve(4_usize.saturating_sub(src.len()));
-
+        let Some(number_blocks) = decode_varint(src)? else {
             return Ok(None);
-        }
-
-        let number_blocks = src.get_u32_le();
+        };
 
         // Read block size.
         if src.len() < 4 {
@@ -194,6 +326,30 @@ impl Decoder for FileInfoDecoder {
         let last_modified = Duration::from_nanos(last_modified);
         let last_modified = SystemTime::UNIX_EPOCH.checked_add(last_modified).unwrap();
 
+        // Read root hash.
+        const HASH_SIZE: usize = 1 + std::mem::size_of::<BlockHash>();
+        if src.len() < HASH_SIZE {
+            src.reserve(HASH_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let hash_algorithm = src.get_u8();
+        let hash_algorithm = HashAlgorithm::from_id(hash_algorithm).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid hash algorithm: {hash_algorithm}"),
+            )
+        })?;
+
+        let mut root_hash = BlockHash::default();
+        src.copy_to_slice(&mut root_hash);
+
+        // Read version.
+        let Some(version) = decode_varint(src)? else {
+            return Ok(None);
+        };
+
         // Return object.
         Ok(Some(FileInfo {
             path,
@@ -201,6 +357,9 @@ impl Decoder for FileInfoDecoder {
             number_blocks,
             block_size,
             last_modified,
+            hash_algorithm,
+            root_hash,
+            version,
         }))
     }
 }
@@ -0,0 +1,85 @@
+use super::{
+    wire_path::{decode_wire_path, put_wire_path},
+    HashAlgorithm, WirePath,
+};
+use bytes::{Buf, BufMut};
+use std::io::ErrorKind;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileInfoRequest {
+    path: WirePath,
+    hash_algorithm: HashAlgorithm,
+}
+
+impl FileInfoRequest {
+    pub fn new(path: WirePath, hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            path,
+            hash_algorithm,
+        }
+    }
+
+    pub fn path(&self) -> &WirePath {
+        &self.path
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+}
+
+pub struct FileInfoRequestEncoder;
+
+impl Encoder<&FileInfoRequest> for FileInfoRequestEncoder {
+    type Error = std::io::Error;
+
+    fn encode(
+        &mut self,
+        item: &FileInfoRequest,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        // Write file path.
+        put_wire_path(dst, &item.path)?;
+
+        // Write hash algorithm.
+        dst.put_u8(item.hash_algorithm.id());
+
+        Ok(())
+    }
+}
+
+pub struct FileInfoRequestDecoder;
+
+impl Decoder for FileInfoRequestDecoder {
+    type Item = FileInfoRequest;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read file path.
+        let Some(path) = decode_wire_path(src)? else {
+            return Ok(None);
+        };
+
+        // Read hash algorithm.
+        if src.is_empty() {
+            src.reserve(1);
+
+            return Ok(None);
+        }
+
+        let hash_algorithm = src.get_u8();
+        let hash_algorithm = HashAlgorithm::from_id(hash_algorithm).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid hash algorithm: {hash_algorithm}"),
+            )
+        })?;
+
+        // Return object.
+        Ok(Some(FileInfoRequest {
+            path,
+            hash_algorithm,
+        }))
+    }
+}
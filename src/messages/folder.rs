@@ -0,0 +1,229 @@
+use super::varint::{decode_len, decode_string, decode_varint, put_bytes, put_varint};
+use bytes::BytesMut;
+use clap::ValueEnum;
+use color_eyre::{eyre::eyre, Report};
+use std::{fmt, io::ErrorKind, str::FromStr};
+use tokio_util::codec::{Decoder, Encoder};
+
+const MAX_FOLDER_ID_LEN: usize = 64;
+const DEFAULT_FOLDER_ID: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FolderId(String);
+
+impl FolderId {
+    fn check(id: &str) -> Result<(), String> {
+        // Keep ids short and plain so they read well in configuration, logs and paths.
+        if id.is_empty() || id.len() > MAX_FOLDER_ID_LEN {
+            return Err(format!(
+                "Invalid folder id {id:?}, it must have 1 to {MAX_FOLDER_ID_LEN} characters."
+            ));
+        }
+
+        if !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(format!(
+                "Invalid folder id {id:?}, only letters, digits, '-', '_' and '.' are allowed."
+            ));
+        }
+
+        if id.chars().all(|c| c == '.') {
+            return Err(format!(
+                "Invalid folder id {id:?}, it can't be made of dots only."
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for FolderId {
+    fn default() -> Self {
+        // The folder synchronized when only a path is given.
+        Self(DEFAULT_FOLDER_ID.to_owned())
+    }
+}
+
+impl FromStr for FolderId {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::check(s).map_err(|e| eyre!(e))?;
+
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl fmt::Display for FolderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum FolderMode {
+    #[default]
+    SendReceive,
+    SendOnly,
+    ReceiveOnly,
+    Mirror,
+}
+
+impl FolderMode {
+    pub fn from_id(id: u64) -> Option<Self> {
+        match id {
+            0 => Some(Self::SendReceive),
+            1 => Some(Self::SendOnly),
+            2 => Some(Self::ReceiveOnly),
+            3 => Some(Self::Mirror),
+
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::SendReceive => 0,
+            Self::SendOnly => 1,
+            Self::ReceiveOnly => 2,
+            Self::Mirror => 3,
+        }
+    }
+
+    pub fn sends(&self) -> bool {
+        *self != Self::ReceiveOnly
+    }
+
+    pub fn receives(&self) -> bool {
+        matches!(self, Self::SendReceive | Self::ReceiveOnly)
+    }
+}
+
+fn put_folder_id(dst: &mut BytesMut, folder_id: &FolderId) {
+    put_bytes(dst, folder_id.0.as_bytes());
+}
+
+fn decode_folder_id(src: &mut BytesMut) -> Result<Option<FolderId>, std::io::Error> {
+    let Some(folder_id) = decode_string(src)? else {
+        return Ok(None);
+    };
+
+    FolderId::check(&folder_id).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(Some(FolderId(folder_id)))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SharedFolders {
+    folders: Vec<(FolderId, FolderMode)>,
+}
+
+impl SharedFolders {
+    pub fn new(folders: Vec<(FolderId, FolderMode)>) -> Self {
+        Self { folders }
+    }
+
+    pub fn folders(&self) -> &[(FolderId, FolderMode)] {
+        &self.folders
+    }
+}
+
+pub struct SharedFoldersEncoder;
+
+impl Encoder<&SharedFolders> for SharedFoldersEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &SharedFolders, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // Write folder ids and modes.
+        put_varint(dst, item.folders.len() as u64);
+        for (folder_id, mode) in &item.folders {
+            put_folder_id(dst, folder_id);
+            put_varint(dst, mode.id());
+        }
+
+        Ok(())
+    }
+}
+
+pub struct SharedFoldersDecoder;
+
+impl Decoder for SharedFoldersDecoder {
+    type Item = SharedFolders;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read folder ids and modes.
+        let Some(len) = decode_len(src)? else {
+            return Ok(None);
+        };
+
+        let mut folders = Vec::new();
+        for _ in 0..len {
+            let Some(folder_id) = decode_folder_id(src)? else {
+                return Ok(None);
+            };
+
+            let Some(mode) = decode_varint(src)? else {
+                return Ok(None);
+            };
+            let mode = FolderMode::from_id(mode).ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown folder mode {mode}."),
+                )
+            })?;
+
+            folders.push((folder_id, mode));
+        }
+
+        // Return object.
+        Ok(Some(SharedFolders { folders }))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SelectFolder {
+    folder_id: FolderId,
+}
+
+impl SelectFolder {
+    pub fn new(folder_id: FolderId) -> Self {
+        Self { folder_id }
+    }
+
+    pub fn folder_id(&self) -> &FolderId {
+        &self.folder_id
+    }
+}
+
+pub struct SelectFolderEncoder;
+
+impl Encoder<&SelectFolder> for SelectFolderEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &SelectFolder, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // Write folder id.
+        put_folder_id(dst, &item.folder_id);
+
+        Ok(())
+    }
+}
+
+pub struct SelectFolderDecoder;
+
+impl Decoder for SelectFolderDecoder {
+    type Item = SelectFolder;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read folder id.
+        let Some(folder_id) = decode_folder_id(src)? else {
+            return Ok(None);
+        };
+
+        // Return object.
+        Ok(Some(SelectFolder { folder_id }))
+    }
+}
@@ -0,0 +1,36 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+pub type BlockHash = [u8; 32];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Blake3),
+            1 => Some(Self::Sha256),
+
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Blake3 => 0,
+            Self::Sha256 => 1,
+        }
+    }
+
+    pub fn hash(&self, buffer: &[u8]) -> BlockHash {
+        match self {
+            Self::Blake3 => blake3::hash(buffer).into(),
+            Self::Sha256 => Sha256::digest(buffer).into(),
+        }
+    }
+}
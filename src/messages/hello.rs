@@ -0,0 +1,171 @@
+use super::{
+    varint::{decode_bytes, put_varint},
+    Compression, HashAlgorithm,
+};
+use bytes::{Buf, BufMut};
+use std::io::ErrorKind;
+use tokio_util::codec::{Decoder, Encoder};
+
+pub type DeviceId = [u8; 32];
+
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"ENTG";
+pub const PROTOCOL_VERSION: u16 = 3;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Hello {
+    protocol_version: u16,
+    device_id: DeviceId,
+    hash_algorithms: Vec<HashAlgorithm>,
+    compressions: Vec<Compression>,
+    features: u64,
+}
+
+impl Hello {
+    pub const FEATURE_DIRECTORY_SYNC: u64 = 1 << 0;
+    pub const FEATURE_RAW_PATHS: u64 = 1 << 1;
+    pub const FEATURE_FILE_INFO_REQUEST: u64 = 1 << 2;
+    pub const FEATURE_BLOCK_REQUEST: u64 = 1 << 3;
+    /// The sender connects for a single command, it is neither registered as a peer nor sent changes.
+    pub const FEATURE_ONE_SHOT: u64 = 1 << 4;
+
+    pub fn new(
+        device_id: DeviceId,
+        hash_algorithms: Vec<HashAlgorithm>,
+        compressions: Vec<Compression>,
+        features: u64,
+    ) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            device_id,
+            hash_algorithms,
+            compressions,
+            features,
+        }
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    pub fn hash_algorithms(&self) -> &[HashAlgorithm] {
+        &self.hash_algorithms
+    }
+
+    pub fn compressions(&self) -> &[Compression] {
+        &self.compressions
+    }
+
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    pub fn with_features(self, features: u64) -> Self {
+        Self {
+            features: self.features | features,
+            ..self
+        }
+    }
+}
+
+pub struct HelloEncoder;
+
+impl Encoder<&Hello> for HelloEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &Hello, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        // Write protocol identification.
+        dst.put_slice(&PROTOCOL_MAGIC);
+        dst.put_u16_le(item.protocol_version);
+
+        // Write device identifier.
+        dst.put_slice(&item.device_id);
+
+        // Write supported hash algorithms.
+        put_varint(dst, item.hash_algorithms.len() as u64);
+        for hash_algorithm in &item.hash_algorithms {
+            dst.put_u8(hash_algorithm.id());
+        }
+
+        // Write supported compressions.
+        put_varint(dst, item.compressions.len() as u64);
+        for compression in &item.compressions {
+            dst.put_u8(compression.id());
+        }
+
+        // Write optional features.
+        dst.put_u64_le(item.features);
+
+        Ok(())
+    }
+}
+
+pub struct HelloDecoder;
+
+impl Decoder for HelloDecoder {
+    type Item = Hello;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // Read protocol identification.
+        const HEADER_SIZE: usize = PROTOCOL_MAGIC.len() + 2 + std::mem::size_of::<DeviceId>();
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let magic = src.split_to(PROTOCOL_MAGIC.len());
+        if magic[..] != PROTOCOL_MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Peer is not speaking the entangler protocol.",
+            ));
+        }
+
+        let protocol_version = src.get_u16_le();
+
+        // Read device identifier.
+        let mut device_id = DeviceId::default();
+        src.copy_to_slice(&mut device_id);
+
+        // Read supported hash algorithms. Unknown ones are skipped since the peer may be newer.
+        let Some(hash_algorithms) = decode_bytes(src)? else {
+            return Ok(None);
+        };
+        let hash_algorithms = hash_algorithms
+            .into_iter()
+            .filter_map(HashAlgorithm::from_id)
+            .collect();
+
+        // Read supported compressions.
+        let Some(compressions) = decode_bytes(src)? else {
+            return Ok(None);
+        };
+        let compressions = compressions
+            .into_iter()
+            .filter_map(Compression::from_id)
+            .collect();
+
+        // Read optional features.
+        if src.len() < 8 {
+            src.reserve(8_usize.saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        let features = src.get_u64_le();
+
+        // Return object.
+        Ok(Some(Hello {
+            protocol_version,
+            device_id,
+            hash_algorithms,
+            compressions,
+            features,
+        }))
+    }
+}
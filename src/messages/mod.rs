@@ -1,20 +1,61 @@
+mod ack;
+mod block_data;
 mod block_info;
+mod block_request;
+mod compression;
+mod directory_info;
+mod error;
 mod file_info;
+mod file_info_request;
+mod folder;
+mod hash_algorithm;
+mod hello;
+mod path_encoding;
+mod varint;
 mod watcher;
+mod wire_path;
 
+pub use ack::{Ack, AckDecoder, AckEncoder};
+pub use block_data::{BlockData, BlockDataDecoder, BlockDataEncoder};
 pub use block_info::{BlockInfo, BlockInfoDecoder, BlockInfoEncoder};
+pub use block_request::{BlockRequest, BlockRequestDecoder, BlockRequestEncoder};
+pub use compression::{Compression, DEFAULT_COMPRESSION_LEVEL};
+pub use directory_info::{
+    DirectoryEntry, DirectoryEntryKind, DirectoryInfo, DirectoryInfoDecoder, DirectoryInfoEncoder,
+};
+pub use error::{Error, ErrorCode, ErrorDecoder, ErrorEncoder};
 pub use file_info::{FileInfo, FileInfoDecoder, FileInfoEncoder, PathId};
+pub use file_info_request::{FileInfoRequest, FileInfoRequestDecoder, FileInfoRequestEncoder};
+pub use folder::{
+    FolderId, FolderMode, SelectFolder, SelectFolderDecoder, SelectFolderEncoder, SharedFolders,
+    SharedFoldersDecoder, SharedFoldersEncoder,
+};
+pub use hash_algorithm::{BlockHash, HashAlgorithm};
+pub use hello::{DeviceId, Hello, HelloDecoder, HelloEncoder, PROTOCOL_VERSION};
+pub use path_encoding::name_bytes;
+pub use wire_path::WirePath;
 
 use self::watcher::{WatcherEventDecoder, WatcherEventEncoder};
 use bytes::{Buf, BufMut};
 use std::io::ErrorKind;
 use tokio_util::codec::{Decoder, Encoder};
 
+pub const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+
 #[derive(Debug)]
 pub enum Message {
     WatcherEvent(notify::Event),
     FileInfo(FileInfo),
     BlockInfo(BlockInfo),
+    BlockRequest(BlockRequest),
+    BlockData(BlockData),
+    DirectoryInfo(DirectoryInfo),
+    Hello(Hello),
+    Error(Error),
+    Ack(Ack),
+    SharedFolders(SharedFolders),
+    SelectFolder(SelectFolder),
+    FileInfoRequest(FileInfoRequest),
 }
 
 pub struct MessageEncoder;
@@ -23,6 +64,11 @@ impl Encoder<&Message> for MessageEncoder {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: &Message, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        // Reserve room for the frame length, it is only known once the message is written.
+        let start = dst.len();
+        dst.put_u32_le(0);
+
+        // Write the message type and the message itself.
         match item {
             Message::WatcherEvent(event) => {
                 dst.put_u8(0);
@@ -42,8 +88,77 @@ impl Encoder<&Message> for MessageEncoder {
                 let mut block_info_encoder = BlockInfoEncoder;
                 block_info_encoder.encode(block_info, dst)?;
             }
+            Message::BlockRequest(block_request) => {
+                dst.put_u8(3);
+
+                let mut block_request_encoder = BlockRequestEncoder;
+                block_request_encoder.encode(block_request, dst)?;
+            }
+            Message::BlockData(block_data) => {
+                dst.put_u8(4);
+
+                let mut block_data_encoder = BlockDataEncoder;
+                block_data_encoder.encode(block_data, dst)?;
+            }
+            Message::DirectoryInfo(directory_info) => {
+                dst.put_u8(5);
+
+                let mut directory_info_encoder = DirectoryInfoEncoder;
+                directory_info_encoder.encode(directory_info, dst)?;
+            }
+            Message::Hello(hello) => {
+                dst.put_u8(6);
+
+                let mut hello_encoder = HelloEncoder;
+                hello_encoder.encode(hello, dst)?;
+            }
+            Message::Error(error) => {
+                dst.put_u8(7);
+
+                let mut error_encoder = ErrorEncoder;
+                error_encoder.encode(error, dst)?;
+            }
+            Message::Ack(ack) => {
+                dst.put_u8(8);
+
+                let mut ack_encoder = AckEncoder;
+                ack_encoder.encode(ack, dst)?;
+            }
+            Message::SharedFolders(shared_folders) => {
+                dst.put_u8(9);
+
+                let mut shared_folders_encoder = SharedFoldersEncoder;
+                shared_folders_encoder.encode(shared_folders, dst)?;
+            }
+            Message::SelectFolder(select_folder) => {
+                dst.put_u8(10);
+
+                let mut select_folder_encoder = SelectFolderEncoder;
+                select_folder_encoder.encode(select_folder, dst)?;
+            }
+            Message::FileInfoRequest(file_info_request) => {
+                dst.put_u8(11);
+
+                let mut file_info_request_encoder = FileInfoRequestEncoder;
+                file_info_request_encoder.encode(file_info_request, dst)?;
+            }
+        }
+
+        // Write the frame length.
+        let frame_len = dst.len() - start - 4;
+        if frame_len > MAX_FRAME_SIZE {
+            dst.truncate(start);
+
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Message of {frame_len} bytes exceeds the maximum frame size of {MAX_FRAME_SIZE} bytes."
+                ),
+            ));
         }
 
+        dst[start..start + 4].copy_from_slice(&(frame_len as u32).to_le_bytes());
+
         Ok(())
     }
 }
@@ -55,44 +170,143 @@ impl Decoder for MessageDecoder {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Read the message type.
-        if src.is_empty() {
-            src.reserve(1_usize.saturating_sub(src.len()));
+        // Wait for the whole frame before consuming anything so partial reads leave the buffer untouched.
+        if src.len() < 4 {
+            src.reserve(4_usize.saturating_sub(src.len()));
 
             return Ok(None);
         }
 
-        let message = src.get_u8();
-        let message = match message {
+        let frame_len = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if frame_len > MAX_FRAME_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Frame of {frame_len} bytes exceeds the maximum frame size of {MAX_FRAME_SIZE} bytes."
+                ),
+            ));
+        }
+
+        if src.len() < 4 + frame_len {
+            src.reserve((4 + frame_len).saturating_sub(src.len()));
+
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let mut frame = src.split_to(frame_len);
+
+        // Read the message type.
+        if frame.is_empty() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Received an empty frame.",
+            ));
+        }
+
+        let message_type = frame.get_u8();
+        let message = match message_type {
             0 => {
                 let mut watcher_event_decoder = WatcherEventDecoder;
-                let Some(watcher_event) = watcher_event_decoder.decode(src)? else {
-                    return Ok(None);
+                let Some(watcher_event) = watcher_event_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
                 };
 
                 Message::WatcherEvent(watcher_event)
             }
             1 => {
                 let mut file_info_decoder = FileInfoDecoder;
-                let Some(file_info) = file_info_decoder.decode(src)? else {
-                    return Ok(None);
+                let Some(file_info) = file_info_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
                 };
 
                 Message::FileInfo(file_info)
             }
             2 => {
                 let mut block_info_decoder = BlockInfoDecoder;
-                let Some(block_info)=block_info_decoder.decode(src)? else {
-                    return Ok(None);
+                let Some(block_info) = block_info_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
                 };
 
                 Message::BlockInfo(block_info)
             }
+            3 => {
+                let mut block_request_decoder = BlockRequestDecoder;
+                let Some(block_request) = block_request_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::BlockRequest(block_request)
+            }
+            4 => {
+                let mut block_data_decoder = BlockDataDecoder;
+                let Some(block_data) = block_data_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::BlockData(block_data)
+            }
+            5 => {
+                let mut directory_info_decoder = DirectoryInfoDecoder;
+                let Some(directory_info) = directory_info_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::DirectoryInfo(directory_info)
+            }
+            6 => {
+                let mut hello_decoder = HelloDecoder;
+                let Some(hello) = hello_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::Hello(hello)
+            }
+            7 => {
+                let mut error_decoder = ErrorDecoder;
+                let Some(error) = error_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::Error(error)
+            }
+            8 => {
+                let mut ack_decoder = AckDecoder;
+                let Some(ack) = ack_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::Ack(ack)
+            }
+            9 => {
+                let mut shared_folders_decoder = SharedFoldersDecoder;
+                let Some(shared_folders) = shared_folders_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::SharedFolders(shared_folders)
+            }
+            10 => {
+                let mut select_folder_decoder = SelectFolderDecoder;
+                let Some(select_folder) = select_folder_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::SelectFolder(select_folder)
+            }
+            11 => {
+                let mut file_info_request_decoder = FileInfoRequestDecoder;
+                let Some(file_info_request) = file_info_request_decoder.decode(&mut frame)? else {
+                    return Err(truncated_frame(message_type));
+                };
+
+                Message::FileInfoRequest(file_info_request)
+            }
 
             _ => {
                 return Err(std::io::Error::new(
                     ErrorKind::InvalidData,
-                    "Invalid message type deceived: {message}",
+                    format!("Invalid message type received: {message_type}"),
                 ))
             }
         };
@@ -101,26 +315,69 @@ impl Decoder for MessageDecoder {
     }
 }
 
+fn truncated_frame(message_type: u8) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Frame of message type {message_type} ended before the message was complete."),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
+        ack::{Ack, AckDecoder, AckEncoder},
+        block_data::{BlockData, BlockDataDecoder, BlockDataEncoder},
         block_info::{BlockInfoDecoder, BlockInfoEncoder},
+        block_request::{BlockRequest, BlockRequestDecoder, BlockRequestEncoder},
+        directory_info::{
+            DirectoryEntry, DirectoryEntryKind, DirectoryInfo, DirectoryInfoDecoder,
+            DirectoryInfoEncoder,
+        },
+        error::{Error, ErrorCode, ErrorDecoder, ErrorEncoder},
         file_info::{FileInfo, FileInfoDecoder, FileInfoEncoder},
+        file_info_request::{FileInfoRequest, FileInfoRequestDecoder, FileInfoRequestEncoder},
+        folder::{
+            FolderId, FolderMode, SelectFolder, SelectFolderDecoder, SelectFolderEncoder,
+            SharedFolders, SharedFoldersDecoder, SharedFoldersEncoder,
+        },
+        hello::{Hello, HelloDecoder, HelloEncoder},
         watcher::{WatcherEventDecoder, WatcherEventEncoder},
-        BlockInfo,
+        BlockInfo, Compression, HashAlgorithm, Message, MessageDecoder, MessageEncoder, WirePath,
+        MAX_FRAME_SIZE,
     };
-    use bytes::BytesMut;
+    use bytes::{BufMut, Bytes, BytesMut};
     use notify::{
         event::{CreateKind, EventAttributes},
         Event, EventKind,
     };
-    use std::time::SystemTime;
+    use std::{path::Path, time::SystemTime};
     use tokio_util::codec::{Decoder, Encoder};
 
+    #[test]
+    fn ack() {
+        // Create object.
+        let ack = Ack::new([3; 32]);
+
+        // Encode object.
+        let mut ack_encoder = AckEncoder;
+        let mut buffer = BytesMut::new();
+        ack_encoder.encode(&ack, &mut buffer).unwrap();
+
+        // Decode object.
+        let mut ack_decoder = AckDecoder;
+        let decoded_ack = ack_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_ack, ack);
+    }
+
     #[test]
     fn block_info() {
         // Create object.
-        let block_info = BlockInfo::new([3; 32], 123, 321, 121212);
+        let block_info = BlockInfo::new([3; 32], 123, 321, HashAlgorithm::Blake3, [7; 32]);
 
         // Encode object.
         let mut block_info_encoder = BlockInfoEncoder;
@@ -138,10 +395,176 @@ mod tests {
         assert_eq!(decoded_block_info, block_info);
     }
 
+    #[test]
+    fn block_request() {
+        // Create object.
+        let block_request = BlockRequest::new([3; 32], vec![0, 131072, 393216]);
+
+        // Encode object.
+        let mut block_request_encoder = BlockRequestEncoder;
+        let mut buffer = BytesMut::new();
+        block_request_encoder
+            .encode(&block_request, &mut buffer)
+            .unwrap();
+
+        // Decode object.
+        let mut block_request_decoder = BlockRequestDecoder;
+        let decoded_block_request = block_request_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_block_request, block_request);
+    }
+
+    #[test]
+    fn block_data() {
+        // Create object.
+        let block_data = BlockData::new([3; 32], 131072, Bytes::from_static(b"entangled"));
+
+        // Encode object.
+        let mut block_data_encoder = BlockDataEncoder;
+        let mut buffer = BytesMut::new();
+        block_data_encoder.encode(&block_data, &mut buffer).unwrap();
+
+        // Decode object.
+        let mut block_data_decoder = BlockDataDecoder;
+        let decoded_block_data = block_data_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_block_data, block_data);
+    }
+
+    #[test]
+    fn block_data_compressed() {
+        let data = b"entangled ".repeat(1000);
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            // Create object.
+            let block_data = BlockData::compressed([3; 32], 131072, &data, compression, 3).unwrap();
+            assert_eq!(block_data.compression(), compression);
+            assert!(block_data.data().len() < data.len());
+
+            // Encode object.
+            let mut block_data_encoder = BlockDataEncoder;
+            let mut buffer = BytesMut::new();
+            block_data_encoder.encode(&block_data, &mut buffer).unwrap();
+
+            // Decode object.
+            let mut block_data_decoder = BlockDataDecoder;
+            let decoded_block_data = block_data_decoder.decode(&mut buffer).unwrap().unwrap();
+
+            // Make sure that we don't have unused bytes on the buffer.
+            assert!(buffer.is_empty());
+
+            // Make sure the original block comes back.
+            assert_eq!(decoded_block_data, block_data);
+            assert_eq!(decoded_block_data.decompress(data.len()).unwrap(), data);
+        }
+
+        // Make sure blocks that don't shrink are sent as they are.
+        let data: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        let block_data = BlockData::compressed([3; 32], 0, &data, Compression::Zstd, 3).unwrap();
+        assert_eq!(block_data.compression(), Compression::None);
+        assert_eq!(block_data.data().as_ref(), data.as_slice());
+    }
+
+    #[test]
+    fn directory_info() {
+        // Create object.
+        let entries = vec![
+            DirectoryEntry::new("bar".into(), DirectoryEntryKind::File, [1; 32]),
+            DirectoryEntry::new("baz".into(), DirectoryEntryKind::Directory, [2; 32]),
+        ];
+        let directory_info = DirectoryInfo::new(
+            WirePath::from_relative(Path::new("foo")).unwrap(),
+            HashAlgorithm::Blake3,
+            [3; 32],
+            entries,
+        );
+
+        // Encode object.
+        let mut directory_info_encoder = DirectoryInfoEncoder;
+        let mut buffer = BytesMut::new();
+        directory_info_encoder
+            .encode(&directory_info, &mut buffer)
+            .unwrap();
+
+        // Decode object.
+        let mut directory_info_decoder = DirectoryInfoDecoder;
+        let decoded_directory_info = directory_info_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_directory_info, directory_info);
+
+        // Make sure entries that would leave the directory are refused.
+        for name in ["..", "a/b"] {
+            let directory_info = DirectoryInfo::new(
+                WirePath::default(),
+                HashAlgorithm::Blake3,
+                [3; 32],
+                vec![DirectoryEntry::new(
+                    name.into(),
+                    DirectoryEntryKind::File,
+                    [1; 32],
+                )],
+            );
+            let mut buffer = BytesMut::new();
+            directory_info_encoder
+                .encode(&directory_info, &mut buffer)
+                .unwrap();
+
+            assert!(
+                directory_info_decoder.decode(&mut buffer).is_err(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn error() {
+        // Create object.
+        let error = Error::new(
+            ErrorCode::PermissionDenied,
+            [3; 32],
+            "Permission denied (os error 13)".into(),
+        );
+
+        // Encode object.
+        let mut error_encoder = ErrorEncoder;
+        let mut buffer = BytesMut::new();
+        error_encoder.encode(&error, &mut buffer).unwrap();
+
+        // Decode object.
+        let mut error_decoder = ErrorDecoder;
+        let decoded_error = error_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_error, error);
+    }
+
     #[test]
     fn file_info() {
         // Create object.
-        let file_info = FileInfo::new("/home/bob/foo/bar".into(), 123, 321, 111, SystemTime::now());
+        let file_info = FileInfo::new(
+            WirePath::from_relative(Path::new("foo/bar")).unwrap(),
+            123,
+            5_000_000_000,
+            111,
+            SystemTime::now(),
+            HashAlgorithm::Sha256,
+            [5; 32],
+        )
+        .with_version(300);
 
         // Encode object.
         let mut file_info_encoder = FileInfoEncoder;
@@ -159,11 +582,166 @@ mod tests {
         assert_eq!(decoded_file_info, file_info);
     }
 
+    #[test]
+    fn file_info_blocks() {
+        let path = WirePath::from_relative(Path::new("foo/bar")).unwrap();
+        let file_info = |size, number_blocks, block_size| {
+            FileInfo::new(
+                path.clone(),
+                size,
+                number_blocks,
+                block_size,
+                SystemTime::now(),
+                HashAlgorithm::Sha256,
+                [5; 32],
+            )
+        };
+        let block_info = |offset, block_size| {
+            BlockInfo::new([1; 32], offset, block_size, HashAlgorithm::Sha256, [2; 32])
+        };
+
+        // Blocks must cover the whole file.
+        let valid = file_info(300, 3, 128);
+        assert!(valid.check_blocks().is_ok());
+        assert!(file_info(0, 0, 128).check_blocks().is_ok());
+        assert!(file_info(300, 2, 128).check_blocks().is_err());
+        assert!(file_info(300, 1, 0).check_blocks().is_err());
+        assert!(file_info(1 << 40, 1, u32::MAX).check_blocks().is_err());
+
+        // Only the last block may be shorter.
+        assert!(valid.check_block(1, &block_info(128, 128)).is_ok());
+        assert!(valid.check_block(2, &block_info(256, 44)).is_ok());
+        assert!(valid.check_block(1, &block_info(100, 128)).is_err());
+        assert!(valid.check_block(2, &block_info(256, 128)).is_err());
+    }
+
+    #[test]
+    fn file_info_request() {
+        // Create object.
+        let file_info_request = FileInfoRequest::new(
+            WirePath::from_relative(Path::new("foo/bar")).unwrap(),
+            HashAlgorithm::Sha256,
+        );
+
+        // Encode object.
+        let mut file_info_request_encoder = FileInfoRequestEncoder;
+        let mut buffer = BytesMut::new();
+        file_info_request_encoder
+            .encode(&file_info_request, &mut buffer)
+            .unwrap();
+
+        // Decode object.
+        let mut file_info_request_decoder = FileInfoRequestDecoder;
+        let decoded_file_info_request = file_info_request_decoder
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_file_info_request, file_info_request);
+    }
+
+    #[test]
+    fn hello() {
+        // Create object.
+        let hello = Hello::new(
+            [9; 32],
+            vec![HashAlgorithm::Sha256, HashAlgorithm::Blake3],
+            vec![Compression::None],
+            Hello::FEATURE_DIRECTORY_SYNC,
+        );
+
+        // Encode object.
+        let mut hello_encoder = HelloEncoder;
+        let mut buffer = BytesMut::new();
+        hello_encoder.encode(&hello, &mut buffer).unwrap();
+
+        // Decode object.
+        let mut hello_decoder = HelloDecoder;
+        let decoded_hello = hello_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_hello, hello);
+    }
+
+    #[test]
+    fn shared_folders() {
+        // Create object.
+        let shared_folders = SharedFolders::new(vec![
+            ("projects".parse().unwrap(), FolderMode::SendReceive),
+            ("photos-2024".parse().unwrap(), FolderMode::Mirror),
+        ]);
+
+        // Encode object.
+        let mut shared_folders_encoder = SharedFoldersEncoder;
+        let mut buffer = BytesMut::new();
+        shared_folders_encoder
+            .encode(&shared_folders, &mut buffer)
+            .unwrap();
+
+        // Decode object.
+        let mut shared_folders_decoder = SharedFoldersDecoder;
+        let decoded_shared_folders = shared_folders_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_shared_folders, shared_folders);
+        // Make sure unknown folder modes are refused.
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(1);
+        buffer.put_u8(1);
+        buffer.put_slice(b"a");
+        buffer.put_u8(9);
+        assert!(shared_folders_decoder.decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn select_folder() {
+        // Create object.
+        let select_folder = SelectFolder::new("projects".parse().unwrap());
+
+        // Encode object.
+        let mut select_folder_encoder = SelectFolderEncoder;
+        let mut buffer = BytesMut::new();
+        select_folder_encoder
+            .encode(&select_folder, &mut buffer)
+            .unwrap();
+
+        // Decode object.
+        let mut select_folder_decoder = SelectFolderDecoder;
+        let decoded_select_folder = select_folder_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_select_folder, select_folder);
+
+        // Make sure folder ids stay plain.
+        assert!("".parse::<FolderId>().is_err());
+        assert!("../etc".parse::<FolderId>().is_err());
+        assert!(".".parse::<FolderId>().is_err());
+        assert!("..".parse::<FolderId>().is_err());
+
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(6);
+        buffer.put_slice(b"a/../b");
+        assert!(select_folder_decoder.decode(&mut buffer).is_err());
+    }
+
     #[test]
     fn watcher() {
         // Create object.
         let kind = EventKind::Create(CreateKind::File);
-        let paths = vec!["/home/bob/foo/bar".into()];
+        let paths = vec!["foo/bar".into()];
         let event = Event {
             kind,
             paths,
@@ -185,4 +763,126 @@ mod tests {
         // Make sure both objects are equal.
         assert_eq!(decoded_event, event);
     }
+
+    #[test]
+    fn message_partial_reads() {
+        // Encode two messages back to back.
+        let block_data = BlockData::new([3; 32], 0, Bytes::from(vec![7; 1000]));
+        let block_request = BlockRequest::new([3; 32], vec![0, 131072]);
+
+        let mut message_encoder = MessageEncoder;
+        let mut encoded = BytesMut::new();
+        message_encoder
+            .encode(&Message::BlockData(block_data.clone()), &mut encoded)
+            .unwrap();
+        message_encoder
+            .encode(&Message::BlockRequest(block_request.clone()), &mut encoded)
+            .unwrap();
+
+        // Feed the decoder one byte at a time.
+        let mut message_decoder = MessageDecoder;
+        let mut buffer = BytesMut::new();
+        let mut messages = Vec::new();
+        for byte in encoded {
+            buffer.put_u8(byte);
+
+            if let Some(message) = message_decoder.decode(&mut buffer).unwrap() {
+                messages.push(message);
+            }
+        }
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both messages survived the fragmentation.
+        match &messages[..] {
+            [Message::BlockData(decoded_block_data), Message::BlockRequest(decoded_block_request)] =>
+            {
+                assert_eq!(decoded_block_data, &block_data);
+                assert_eq!(decoded_block_request, &block_request);
+            }
+
+            _ => panic!("Unexpected messages: {messages:?}"),
+        }
+    }
+
+    #[test]
+    fn message_frame_too_large() {
+        // Announce a frame bigger than allowed.
+        let mut buffer = BytesMut::new();
+        buffer.put_u32_le(MAX_FRAME_SIZE as u32 + 1);
+
+        // Make sure it is rejected before waiting for the data.
+        let mut message_decoder = MessageDecoder;
+        assert!(message_decoder.decode(&mut buffer).is_err());
+
+        // Make sure oversized messages are not sent either.
+        let block_data = BlockData::new([3; 32], 0, Bytes::from(vec![0; MAX_FRAME_SIZE]));
+        let mut message_encoder = MessageEncoder;
+        let mut buffer = BytesMut::new();
+        assert!(message_encoder
+            .encode(&Message::BlockData(block_data), &mut buffer)
+            .is_err());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn watcher_long_paths() {
+        // Create an object with more paths and longer paths than fit in a byte or a u16.
+        let kind = EventKind::Create(CreateKind::File);
+        let deep_path = "node_modules/".repeat(6000);
+        let paths = (0..300).map(|i| format!("{deep_path}{i}").into()).collect();
+        let event = Event {
+            kind,
+            paths,
+            attrs: EventAttributes::new(),
+        };
+
+        // Encode object.
+        let mut watcher_event_encoder = WatcherEventEncoder;
+        let mut buffer = BytesMut::new();
+        watcher_event_encoder.encode(&event, &mut buffer).unwrap();
+
+        // Decode object.
+        let mut watcher_event_decoder = WatcherEventDecoder;
+        let decoded_event = watcher_event_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure both objects are equal.
+        assert_eq!(decoded_event, event);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_info_non_unicode_path() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        // Create object with a latin-1 file name.
+        let file_info = FileInfo::new(
+            WirePath::from_relative(Path::new(OsStr::from_bytes(b"archive/caf\xe9.txt"))).unwrap(),
+            123,
+            1,
+            111,
+            SystemTime::now(),
+            HashAlgorithm::Blake3,
+            [5; 32],
+        );
+
+        // Encode object.
+        let mut file_info_encoder = FileInfoEncoder;
+        let mut buffer = BytesMut::new();
+        file_info_encoder.encode(&file_info, &mut buffer).unwrap();
+
+        // Decode object.
+        let mut file_info_decoder = FileInfoDecoder;
+        let decoded_file_info = file_info_decoder.decode(&mut buffer).unwrap().unwrap();
+
+        // Make sure that we don't have unused bytes on the buffer.
+        assert!(buffer.is_empty());
+
+        // Make sure the name survived byte for byte.
+        assert_eq!(decoded_file_info, file_info);
+    }
 }
@@ -0,0 +1,121 @@
+use super::varint::{decode_bytes, put_bytes};
+use bytes::{Buf, BufMut, BytesMut};
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    io::ErrorKind,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathEncoding {
+    Utf8,
+    Bytes,
+}
+
+impl PathEncoding {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Self::Utf8),
+            1 => Some(Self::Bytes),
+
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Utf8 => 0,
+            Self::Bytes => 1,
+        }
+    }
+}
+
+pub fn put_os_str(dst: &mut BytesMut, name: &OsStr) -> Result<(), std::io::Error> {
+    // Names are sent as they are stored, the flag tells the peer whether they are unicode.
+    let (encoding, bytes) = match name.to_str() {
+        Some(name) => (PathEncoding::Utf8, name.as_bytes()),
+        None => (PathEncoding::Bytes, raw_bytes(name)?),
+    };
+
+    dst.put_u8(encoding.id());
+    put_bytes(dst, bytes);
+
+    Ok(())
+}
+
+pub fn decode_os_string(src: &mut BytesMut) -> Result<Option<OsString>, std::io::Error> {
+    if src.is_empty() {
+        src.reserve(1_usize.saturating_sub(src.len()));
+
+        return Ok(None);
+    }
+
+    let encoding = src.get_u8();
+    let encoding = PathEncoding::from_id(encoding).ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid path encoding: {encoding}"),
+        )
+    })?;
+
+    let Some(bytes) = decode_bytes(src)? else {
+        return Ok(None);
+    };
+
+    let name = match encoding {
+        PathEncoding::Utf8 => String::from_utf8(bytes.to_vec())
+            .map_err(|e| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Fail to parse string: {e:?}"),
+                )
+            })?
+            .into(),
+        PathEncoding::Bytes => from_raw_bytes(bytes.to_vec())?,
+    };
+
+    Ok(Some(name))
+}
+
+pub fn name_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    match name.to_str() {
+        Some(name) => Cow::Borrowed(name.as_bytes()),
+        None => match raw_bytes(name) {
+            Ok(bytes) => Cow::Borrowed(bytes),
+            Err(_) => Cow::Owned(name.to_string_lossy().into_owned().into_bytes()),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn raw_bytes(name: &OsStr) -> Result<&[u8], std::io::Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn raw_bytes(name: &OsStr) -> Result<&[u8], std::io::Error> {
+    Err(std::io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Name {name:?} is not valid unicode."),
+    ))
+}
+
+#[cfg(unix)]
+fn from_raw_bytes(bytes: Vec<u8>) -> Result<OsString, std::io::Error> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn from_raw_bytes(bytes: Vec<u8>) -> Result<OsString, std::io::Error> {
+    Err(std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "Name {:?} is not valid unicode and can't be stored on this platform.",
+            String::from_utf8_lossy(&bytes)
+        ),
+    ))
+}
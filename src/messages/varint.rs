@@ -0,0 +1,132 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::ErrorKind;
+
+const MAX_VARINT_LEN: usize = 10;
+
+pub fn put_varint(dst: &mut BytesMut, mut value: u64) {
+    // Write seven bits at a time, the high bit flags that more bytes follow.
+    while value >= 0x80 {
+        dst.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    dst.put_u8(value as u8);
+}
+
+pub fn put_bytes(dst: &mut BytesMut, bytes: &[u8]) {
+    put_varint(dst, bytes.len() as u64);
+    dst.put_slice(bytes);
+}
+
+pub fn decode_varint(src: &mut BytesMut) -> Result<Option<u64>, std::io::Error> {
+    let Some((value, len)) = peek_varint(src)? else {
+        src.reserve(1);
+
+        return Ok(None);
+    };
+
+    src.advance(len);
+
+    Ok(Some(value))
+}
+
+pub fn decode_len(src: &mut BytesMut) -> Result<Option<usize>, std::io::Error> {
+    let Some(len) = decode_varint(src)? else {
+        return Ok(None);
+    };
+
+    let len = usize::try_from(len).map_err(|_| {
+        std::io::Error::new(ErrorKind::InvalidData, format!("Length is too big: {len}"))
+    })?;
+
+    Ok(Some(len))
+}
+
+pub fn decode_bytes(src: &mut BytesMut) -> Result<Option<BytesMut>, std::io::Error> {
+    // Only consume the length once the bytes it announces are available.
+    let Some((len, len_size)) = peek_varint(src)? else {
+        src.reserve(1);
+
+        return Ok(None);
+    };
+
+    let len = usize::try_from(len).map_err(|_| {
+        std::io::Error::new(ErrorKind::InvalidData, format!("Length is too big: {len}"))
+    })?;
+    if src.len() - len_size < len {
+        src.reserve(len.saturating_sub(src.len() - len_size));
+
+        return Ok(None);
+    }
+
+    src.advance(len_size);
+
+    Ok(Some(src.split_to(len)))
+}
+
+pub fn decode_string(src: &mut BytesMut) -> Result<Option<String>, std::io::Error> {
+    let Some(string) = decode_bytes(src)? else {
+        return Ok(None);
+    };
+
+    let string = String::from_utf8(string.to_vec()).map_err(|e| {
+        std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Fail to parse string: {e:?}"),
+        )
+    })?;
+
+    Ok(Some(string))
+}
+
+fn peek_varint(src: &[u8]) -> Result<Option<(u64, usize)>, std::io::Error> {
+    let mut value = 0u64;
+    for (index, byte) in src.iter().take(MAX_VARINT_LEN).enumerate() {
+        // The last byte can only hold the topmost bit of a u64.
+        let bits = (byte & 0x7f) as u64;
+        if index == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Varint does not fit in 64 bits.",
+            ));
+        }
+
+        value |= bits << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, index + 1)));
+        }
+    }
+
+    if src.len() >= MAX_VARINT_LEN {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "Varint is longer than 10 bytes.",
+        ));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_varint, put_varint};
+    use bytes::BytesMut;
+
+    #[test]
+    fn varint() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            // Encode value.
+            let mut buffer = BytesMut::new();
+            put_varint(&mut buffer, value);
+
+            // Make sure a partial varint is not consumed.
+            let mut partial = BytesMut::from(&buffer[..buffer.len() - 1]);
+            assert_eq!(decode_varint(&mut partial).unwrap(), None);
+            assert_eq!(partial.len(), buffer.len() - 1);
+
+            // Decode value.
+            assert_eq!(decode_varint(&mut buffer).unwrap(), Some(value));
+            assert!(buffer.is_empty());
+        }
+    }
+}
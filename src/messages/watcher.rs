@@ -1,3 +1,8 @@
+use super::{
+    varint::{decode_len, put_varint},
+    wire_path::{decode_wire_path, put_wire_path},
+    WirePath,
+};
 use bytes::{Buf, BufMut, BytesMut};
 use notify::{
     event::{
@@ -6,7 +11,7 @@ use notify::{
     },
     EventKind,
 };
-use std::{io::ErrorKind, path::PathBuf};
+use std::io::ErrorKind;
 use tokio_util::codec::{Decoder, Encoder};
 
 pub struct WatcherEventEncoder;
@@ -115,17 +120,10 @@ impl Encoder<&Event> for WatcherEventEncoder {
         }
 
         // Write paths.
-        let number_of_paths = item.paths.len() as u8;
-        dst.put_u8(number_of_paths);
+        put_varint(dst, item.paths.len() as u64);
 
         for path in &item.paths {
-            let path = path.to_str().ok_or_else(|| {
-                std::io::Error::new(ErrorKind::Other, format!("Invalid path: {path:?}"))
-            })?;
-            let path_len = path.len() as u16;
-
-            dst.put_u16_le(path_len);
-            dst.put(path.as_bytes());
+            put_wire_path(dst, &WirePath::from_relative(path)?)?;
         }
 
         // TODO: Maybe write attributes.
@@ -141,9 +139,9 @@ impl Decoder for WatcherEventDecoder {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Read event kind.
-        if src.len() < 3 {
-            src.reserve(3_usize.saturating_sub(src.len()));
+        // Read event kind. Its length depends on the kind itself, missing inner bytes mean a truncated frame.
+        if src.is_empty() {
+            src.reserve(1_usize.saturating_sub(src.len()));
 
             return Ok(None);
         }
@@ -152,12 +150,12 @@ impl Decoder for WatcherEventDecoder {
         let kind = match kind {
             0 => EventKind::Any,
             1 => {
-                let access_kind = src.get_u8();
+                let access_kind = src.try_get_u8()?;
                 let access_kind = match access_kind {
                     0 => AccessKind::Any,
                     1 => AccessKind::Read,
                     2 => {
-                        let access_mode = src.get_u8();
+                        let access_mode = src.try_get_u8()?;
                         let access_mode = match access_mode {
                             0 => AccessMode::Any,
                             1 => AccessMode::Execute,
@@ -176,7 +174,7 @@ impl Decoder for WatcherEventDecoder {
                         AccessKind::Open(access_mode)
                     }
                     3 => {
-                        let access_mode = src.get_u8();
+                        let access_mode = src.try_get_u8()?;
                         let access_mode = match access_mode {
                             0 => AccessMode::Any,
                             1 => AccessMode::Execute,
@@ -207,7 +205,7 @@ impl Decoder for WatcherEventDecoder {
                 EventKind::Access(access_kind)
             }
             2 => {
-                let create_kind = src.get_u8();
+                let create_kind = src.try_get_u8()?;
                 let create_kind = match create_kind {
                     0 => CreateKind::Any,
                     1 => CreateKind::File,
@@ -225,11 +223,11 @@ impl Decoder for WatcherEventDecoder {
                 EventKind::Create(create_kind)
             }
             3 => {
-                let modify_kind = src.get_u8();
+                let modify_kind = src.try_get_u8()?;
                 let modify_kind = match modify_kind {
                     0 => ModifyKind::Any,
                     1 => {
-                        let data_change = src.get_u8();
+                        let data_change = src.try_get_u8()?;
                         let data_change = match data_change {
                             0 => DataChange::Any,
                             1 => DataChange::Size,
@@ -247,7 +245,7 @@ impl Decoder for WatcherEventDecoder {
                         ModifyKind::Data(data_change)
                     }
                     2 => {
-                        let metadata_kind = src.get_u8();
+                        let metadata_kind = src.try_get_u8()?;
                         let metadata_kind = match metadata_kind {
                             0 => MetadataKind::Any,
                             1 => MetadataKind::AccessTime,
@@ -268,7 +266,7 @@ impl Decoder for WatcherEventDecoder {
                         ModifyKind::Metadata(metadata_kind)
                     }
                     3 => {
-                        let rename_mode = src.get_u8();
+                        let rename_mode = src.try_get_u8()?;
                         let rename_mode = match rename_mode {
                             0 => RenameMode::Any,
                             1 => RenameMode::To,
@@ -299,7 +297,7 @@ impl Decoder for WatcherEventDecoder {
                 EventKind::Modify(modify_kind)
             }
             4 => {
-                let remove_kind = src.get_u8();
+                let remove_kind = src.try_get_u8()?;
                 let remove_kind = match remove_kind {
                     0 => RemoveKind::Any,
                     1 => RemoveKind::File,
@@ -327,37 +325,15 @@ impl Decoder for WatcherEventDecoder {
         };
 
         // Read paths.
-        if src.is_empty() {
-            src.reserve(1_usize.saturating_sub(src.len()));
-
+        let Some(number_of_paths) = decode_len(src)? else {
             return Ok(None);
-        }
-
-        let number_of_paths = src.get_u8();
-        let mut paths = Vec::with_capacity(number_of_paths as usize);
+        };
+        let mut paths = Vec::new();
         for _ in 0..number_of_paths {
-            if src.len() < 2 {
-                src.reserve(2_usize.saturating_sub(src.len()));
-
+            let Some(path) = decode_wire_path(src)? else {
                 return Ok(None);
-            }
-
-            let path_len = src.get_u16_le() as usize;
-            if src.len() < path_len {
-                src.reserve(path_len.saturating_sub(src.len()));
-
-                return Ok(None);
-            }
-
-            let path = src.split_to(path_len);
-            let path = path.to_vec();
-            let path = String::from_utf8(path).map_err(|e| {
-                std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!("Fail to parse string: {e:?}"),
-                )
-            })?;
-            let path = PathBuf::from(path);
+            };
+            let path = path.to_relative();
 
             paths.push(path);
         }
@@ -0,0 +1,233 @@
+use super::path_encoding::{decode_os_string, put_os_str};
+use bytes::BytesMut;
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::{Debug, Display},
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WirePath {
+    components: Vec<OsString>,
+}
+
+impl WirePath {
+    pub fn from_relative(path: &Path) -> Result<Self, std::io::Error> {
+        // Keep only the names, anything that could leave the folder root is refused.
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(name.to_owned()),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Path {path:?} is not relative to the folder root."),
+                    ))
+                }
+            }
+        }
+
+        Ok(Self { components })
+    }
+
+    pub fn from_local(root: &Path, path: &Path) -> Result<Self, std::io::Error> {
+        let relative_path = path.strip_prefix(root).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Path {path:?} is outside of folder {root:?}."),
+            )
+        })?;
+
+        Self::from_relative(relative_path)
+    }
+
+    pub fn to_local(&self, root: &Path) -> PathBuf {
+        let mut path = root.to_owned();
+        path.extend(&self.components);
+
+        path
+    }
+
+    pub fn to_relative(&self) -> PathBuf {
+        self.components.iter().collect()
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &OsStr> {
+        self.components.iter().map(OsString::as_os_str)
+    }
+
+    pub fn join(&self, name: &OsStr) -> Result<Self, std::io::Error> {
+        // A name never reaches outside of its directory.
+        check_name(name)?;
+
+        let mut components = self.components.clone();
+        components.push(name.to_owned());
+
+        Ok(Self { components })
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        let (_, components) = self.components.split_last()?;
+
+        Some(Self {
+            components: components.to_vec(),
+        })
+    }
+
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.components.last().map(OsString::as_os_str)
+    }
+
+    pub fn starts_with(&self, base: &WirePath) -> bool {
+        self.components.starts_with(&base.components)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.components.len()
+    }
+
+    pub fn to_os_string(&self) -> OsString {
+        let mut path = OsString::new();
+        for (index, component) in self.components.iter().enumerate() {
+            if index > 0 {
+                path.push("/");
+            }
+
+            path.push(component);
+        }
+
+        path
+    }
+}
+
+impl Display for WirePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_os_string().to_string_lossy())
+    }
+}
+
+impl Debug for WirePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_os_string())
+    }
+}
+
+pub fn put_wire_path(dst: &mut BytesMut, path: &WirePath) -> Result<(), std::io::Error> {
+    put_os_str(dst, &path.to_os_string())
+}
+
+pub fn decode_wire_path(src: &mut BytesMut) -> Result<Option<WirePath>, std::io::Error> {
+    let Some(path) = decode_os_string(src)? else {
+        return Ok(None);
+    };
+
+    // The folder root is the empty path.
+    if path.is_empty() {
+        return Ok(Some(WirePath::default()));
+    }
+
+    // Only accept canonical paths so a peer can never reach outside of the folder.
+    let components = split_components(&path);
+    if !components.iter().all(|component| is_name(component)) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Path {path:?} is not canonical."),
+        ));
+    }
+
+    Ok(Some(WirePath { components }))
+}
+
+pub fn check_name(name: &OsStr) -> Result<(), std::io::Error> {
+    if !is_name(name) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Name {name:?} is not the name of a file in a folder."),
+        ));
+    }
+
+    Ok(())
+}
+
+fn is_name(name: &OsStr) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !has_separator(name)
+}
+
+#[cfg(unix)]
+fn has_separator(name: &OsStr) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    name.as_bytes().contains(&b'/')
+}
+
+#[cfg(not(unix))]
+fn has_separator(name: &OsStr) -> bool {
+    name.to_string_lossy().contains(['/', '\\'])
+}
+
+#[cfg(unix)]
+fn split_components(path: &OsStr) -> Vec<OsString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_bytes()
+        .split(|byte| *byte == b'/')
+        .map(|component| OsStr::from_bytes(component).to_owned())
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn split_components(path: &OsStr) -> Vec<OsString> {
+    path.to_string_lossy()
+        .split('/')
+        .map(OsString::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_wire_path, put_wire_path, WirePath};
+    use bytes::BytesMut;
+    use std::{ffi::OsStr, path::Path};
+
+    #[test]
+    fn wire_path() {
+        // Make sure local paths are normalized relative to the folder root.
+        let path =
+            WirePath::from_local(Path::new("/mnt/data"), Path::new("/mnt/data/./a/b.txt")).unwrap();
+        assert_eq!(path.to_string(), "a/b.txt");
+        assert_eq!(
+            path.to_local(Path::new("/media/other")),
+            Path::new("/media/other/a/b.txt")
+        );
+
+        // Make sure paths leaving the folder are refused.
+        assert!(WirePath::from_relative(Path::new("a/../../b")).is_err());
+        assert!(WirePath::from_relative(Path::new("/etc/passwd")).is_err());
+        assert!(WirePath::from_local(Path::new("/mnt/data"), Path::new("/mnt/other")).is_err());
+
+        // Make sure the root survives the wire.
+        let mut buffer = BytesMut::new();
+        put_wire_path(&mut buffer, &WirePath::default()).unwrap();
+        assert_eq!(
+            decode_wire_path(&mut buffer).unwrap(),
+            Some(WirePath::default())
+        );
+
+        // Make sure peers can't send paths that are not canonical.
+        for path in ["a/../b", "a//b", "./a", "/a"] {
+            let mut buffer = BytesMut::new();
+            buffer.extend_from_slice(&[0, path.len() as u8]);
+            buffer.extend_from_slice(path.as_bytes());
+
+            assert!(decode_wire_path(&mut buffer).is_err(), "{path}");
+        }
+
+        // Make sure names can't be joined to leave their directory.
+        assert_eq!(path.join(OsStr::new("c")).unwrap().to_string(), "a/b.txt/c");
+        for name in ["", ".", "..", "a/b"] {
+            assert!(path.join(OsStr::new(name)).is_err(), "{name}");
+        }
+    }
+}
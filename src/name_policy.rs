@@ -0,0 +1,151 @@
+use crate::messages::WirePath;
+use clap::ValueEnum;
+use color_eyre::{eyre::eyre, Report, Result};
+use std::{
+    ffi::{OsStr, OsString},
+    str::FromStr,
+};
+use unicode_normalization::UnicodeNormalization as _;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnicodeNormalization {
+    #[default]
+    None,
+    Nfc,
+    Nfd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CaseSensitivity {
+    Sensitive,
+    Insensitive,
+}
+
+impl Default for CaseSensitivity {
+    fn default() -> Self {
+        // Follow the default file systems of each platform.
+        if cfg!(any(windows, target_os = "macos")) {
+            Self::Insensitive
+        } else {
+            Self::Sensitive
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NamePolicy {
+    normalization: UnicodeNormalization,
+    case_sensitivity: CaseSensitivity,
+}
+
+impl NamePolicy {
+    pub fn new(normalization: UnicodeNormalization, case_sensitivity: CaseSensitivity) -> Self {
+        Self {
+            normalization,
+            case_sensitivity,
+        }
+    }
+
+    pub fn local_name(&self, name: &OsStr) -> OsString {
+        // Names that are not unicode can't be normalized.
+        let Some(name) = name.to_str() else {
+            return name.to_owned();
+        };
+
+        match self.normalization {
+            UnicodeNormalization::None => name.into(),
+            UnicodeNormalization::Nfc => name.nfc().collect::<String>().into(),
+            UnicodeNormalization::Nfd => name.nfd().collect::<String>().into(),
+        }
+    }
+
+    pub fn local_path(&self, path: &WirePath) -> Result<WirePath, std::io::Error> {
+        path.components()
+            .try_fold(WirePath::default(), |local_path, name| {
+                local_path.join(&self.local_name(name))
+            })
+    }
+
+    pub fn collides(&self, name: &OsStr, other: &OsStr) -> bool {
+        name != other && self.collision_key(name) == self.collision_key(other)
+    }
+
+    fn collision_key(&self, name: &OsStr) -> OsString {
+        let Some(name) = name.to_str() else {
+            return name.to_owned();
+        };
+
+        // Every unicode form names the same file once the folder normalizes names.
+        let name = match self.normalization {
+            UnicodeNormalization::None => name.to_owned(),
+            UnicodeNormalization::Nfc | UnicodeNormalization::Nfd => name.nfc().collect(),
+        };
+
+        match self.case_sensitivity {
+            CaseSensitivity::Sensitive => name.into(),
+            CaseSensitivity::Insensitive => name.to_lowercase().into(),
+        }
+    }
+}
+
+impl FromStr for NamePolicy {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (normalization, case_sensitivity) = match s.split_once(':') {
+            Some((normalization, case_sensitivity)) => (normalization, Some(case_sensitivity)),
+            None => (s, None),
+        };
+        let invalid = || {
+            eyre!("Invalid names {s:?}, expected NORMALIZATION[:CASE] with none, nfc or nfd and sensitive or insensitive, like nfc:insensitive.")
+        };
+
+        Ok(Self {
+            normalization: UnicodeNormalization::from_str(normalization, true)
+                .map_err(|_| invalid())?,
+            case_sensitivity: match case_sensitivity {
+                Some(case_sensitivity) => {
+                    CaseSensitivity::from_str(case_sensitivity, true).map_err(|_| invalid())?
+                }
+                None => CaseSensitivity::default(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaseSensitivity, NamePolicy, UnicodeNormalization};
+    use std::ffi::OsStr;
+
+    #[test]
+    fn name_policy() {
+        let composed = OsStr::new("caf\u{e9}.txt");
+        let decomposed = OsStr::new("cafe\u{301}.txt");
+
+        // Make sure names are stored in the unicode form of the folder.
+        let policy = NamePolicy::new(UnicodeNormalization::Nfc, CaseSensitivity::Sensitive);
+        assert_eq!(policy.local_name(decomposed), composed);
+        assert!(policy.collides(composed, decomposed));
+        assert!(!policy.collides(OsStr::new("Readme.md"), OsStr::new("README.md")));
+
+        // Make sure names that only differ in case collide on case insensitive folders.
+        let policy = NamePolicy::new(UnicodeNormalization::None, CaseSensitivity::Insensitive);
+        assert_eq!(policy.local_name(decomposed), decomposed);
+        assert!(!policy.collides(composed, decomposed));
+        assert!(policy.collides(OsStr::new("Readme.md"), OsStr::new("README.md")));
+        assert!(!policy.collides(OsStr::new("Readme.md"), OsStr::new("Readme.md")));
+
+        // Make sure policies parse like the command line takes them.
+        assert_eq!(
+            "nfc:insensitive".parse::<NamePolicy>().unwrap(),
+            NamePolicy::new(UnicodeNormalization::Nfc, CaseSensitivity::Insensitive)
+        );
+        assert_eq!(
+            "nfd".parse::<NamePolicy>().unwrap(),
+            NamePolicy::new(UnicodeNormalization::Nfd, CaseSensitivity::default())
+        );
+        assert!("nfc:upper".parse::<NamePolicy>().is_err());
+        assert!("nfkc".parse::<NamePolicy>().is_err());
+    }
+}
@@ -0,0 +1,1917 @@
+use crate::{
+    client::{client_config, connect, dial, PeerAddress},
+    config::Config,
+    control::{
+        direction_name, serve_control, value_name, FileError, FolderStatus, Mismatch, PeerStatus,
+        Status, TransferStatus, VersionStatus,
+    },
+    device_id::{
+        device_id_filename_or_default, device_id_to_string, peer_device_id,
+        read_or_generate_device_identity, DeviceIdentity,
+    },
+    diff::{diff_folder, FolderDiff},
+    directory_sync::{handle_directory_sync, start_directory_sync, Differences},
+    file_sync::{
+        handle_block_request, handle_file_info_request, handle_file_sync, is_temporary_path,
+        request_file_info, transfer_file,
+    },
+    folder::FolderConfig,
+    ignore::IgnorePatterns,
+    index::Index,
+    messages::{
+        DeviceId, Error, ErrorCode, FileInfo, FolderId, FolderMode, Hello, Message, MessageDecoder,
+        MessageEncoder, PathId, SelectFolder, SharedFolders, WirePath,
+    },
+    outcome::{receive_message, send_outcome},
+    path_id_cache::PathIdCache,
+    rate_limit::Direction,
+    removal::{apply_removal, send_removal},
+    scheduler::TransferScheduler,
+    scraper::list_files,
+    server::{listen, server_config},
+    session::{Session, SessionOptions},
+    sync_summary::SyncSummary,
+    transfers::Transfers,
+    verify::{compare_with_peer, repair_file, verify_file, Problem},
+    versioning::{clean_versions, list_versions, restore_version, version_at},
+};
+use color_eyre::{eyre::eyre, Result};
+use futures::{stream::FuturesUnordered, SinkExt, StreamExt, TryStreamExt};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use quinn::{Connection, Endpoint, RecvStream, SendStream, VarInt};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, watch, RwLock,
+};
+use tokio::task::JoinSet;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::*;
+
+const CHANGES_CAPACITY: usize = 1024;
+const DUPLICATE_CONNECTION: VarInt = VarInt::from_u32(1);
+const PEER_DISCONNECTED: VarInt = VarInt::from_u32(2);
+const PEER_REMOVED: VarInt = VarInt::from_u32(3);
+const NODE_SHUTDOWN: VarInt = VarInt::from_u32(4);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const VERSIONS_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Modified,
+    Removed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    origin: Option<DeviceId>,
+    folder_id: FolderId,
+    kind: ChangeKind,
+    path: WirePath,
+}
+
+impl Change {
+    pub fn new(
+        origin: Option<DeviceId>,
+        folder_id: FolderId,
+        kind: ChangeKind,
+        path: WirePath,
+    ) -> Self {
+        Self {
+            origin,
+            folder_id,
+            kind,
+            path,
+        }
+    }
+
+    pub fn origin(&self) -> Option<&DeviceId> {
+        self.origin.as_ref()
+    }
+
+    pub fn folder_id(&self) -> &FolderId {
+        &self.folder_id
+    }
+
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> &WirePath {
+        &self.path
+    }
+}
+
+pub struct Peer {
+    connection: Connection,
+    session: Arc<Session>,
+    initiator: bool,
+    folders: Vec<FolderId>,
+    write_framed: FramedWrite<SendStream, MessageEncoder>,
+    read_framed: FramedRead<RecvStream, MessageDecoder>,
+}
+
+impl Peer {
+    pub fn device_id(&self) -> &DeviceId {
+        self.session.device_id()
+    }
+}
+
+struct PeerConnection {
+    connection: Connection,
+    session: Arc<Session>,
+    preferred: bool,
+    folders: Vec<FolderId>,
+    since: SystemTime,
+}
+
+struct Dialer {
+    endpoint: Endpoint,
+    cert_filename: Option<String>,
+}
+
+struct Folder {
+    index: Arc<RwLock<Index>>,
+    devices: Vec<DeviceId>,
+}
+
+impl Folder {
+    fn is_shared_with(&self, device_id: &DeviceId) -> bool {
+        // Folders that name no device are shared with every peer.
+        self.devices.is_empty() || self.devices.contains(device_id)
+    }
+}
+
+pub struct Node {
+    device_id: DeviceId,
+    identity: DeviceIdentity,
+    folders: BTreeMap<FolderId, Folder>,
+    options: SessionOptions,
+    hello: Hello,
+    changes: broadcast::Sender<Change>,
+    peers: Mutex<HashMap<DeviceId, PeerConnection>>,
+    dialer: Mutex<Option<Dialer>>,
+    dialed_peers: Mutex<HashMap<PeerAddress, Option<DeviceId>>>,
+    transfers: Arc<Transfers>,
+    last_seen: Mutex<HashMap<DeviceId, (SocketAddr, SystemTime)>>,
+    resumed: broadcast::Sender<FolderId>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Node {
+    pub async fn new(
+        folder_configs: Vec<FolderConfig>,
+        options: SessionOptions,
+        device_id_filename: Option<String>,
+    ) -> Result<Arc<Self>> {
+        if folder_configs.is_empty() {
+            return Err(eyre!("No folder to synchronize."));
+        }
+
+        // Prepare the hello sent to every peer.
+        let device_id_filename = device_id_filename_or_default(device_id_filename);
+        let identity = read_or_generate_device_identity(device_id_filename).await?;
+        let device_id = identity.device_id();
+        let hello = Session::local_hello(device_id, &options);
+        info!("This device is {}.", device_id_to_string(&device_id));
+
+        // Index every folder to summarize it for peers and reuse blocks from any local file.
+        let mut folders = BTreeMap::new();
+        for folder_config in folder_configs {
+            let folder_id = folder_config.id().clone();
+            if folders.contains_key(&folder_id) {
+                return Err(eyre!("Folder {folder_id} is configured more than once."));
+            }
+
+            // Try to resolve relative source paths.
+            let source_path = folder_config.path().canonicalize()?;
+            let index = Index::with_folder(
+                folder_id.clone(),
+                source_path,
+                options.hash_algorithm(),
+                folder_config.name_policy(),
+                IgnorePatterns::new(folder_config.ignore_patterns().to_vec())?,
+            )
+            .await?
+            .with_mode(folder_config.mode())
+            .with_versioning(folder_config.versioning());
+
+            folders.insert(
+                folder_id,
+                Folder {
+                    index: Arc::new(RwLock::new(index)),
+                    devices: folder_config.devices().to_vec(),
+                },
+            );
+        }
+
+        let (changes, _) = broadcast::channel(CHANGES_CAPACITY);
+        let (resumed, _) = broadcast::channel(CHANGES_CAPACITY);
+        let (shutdown, _) = watch::channel(false);
+
+        Ok(Arc::new(Self {
+            device_id,
+            identity,
+            folders,
+            options,
+            hello,
+            changes,
+            peers: Mutex::default(),
+            dialer: Mutex::default(),
+            dialed_peers: Mutex::default(),
+            transfers: Arc::default(),
+            last_seen: Mutex::default(),
+            resumed,
+            shutdown,
+        }))
+    }
+
+    pub async fn run(
+        self: Arc<Self>,
+        listen_addresses: Vec<SocketAddr>,
+        peers: Vec<PeerAddress>,
+        cert_filename: Option<String>,
+        private_key_filename: Option<String>,
+        control_socket: Option<PathBuf>,
+    ) -> Result<()> {
+        // Watch every folder, the changes of all of them are handled in order.
+        let (watcher_tx, mut watcher_rx) = mpsc::channel(16);
+        let mut watchers = Vec::new();
+        for (folder_id, folder) in &self.folders {
+            let root = folder.index.read().await.root().to_owned();
+            watchers.push(watch_folder(folder_id.clone(), root, watcher_tx.clone())?);
+        }
+
+        // Connect to peers from the same endpoint that listens for them.
+        let mut endpoints = Vec::new();
+        for address in listen_addresses {
+            let server_config = server_config(cert_filename.clone(), private_key_filename.clone())?;
+            let listener = Endpoint::server(server_config, address)?;
+            tokio::spawn(listen(listener.clone(), self.clone()));
+            endpoints.push(listener);
+        }
+
+        if endpoints.is_empty() {
+            endpoints.push(Endpoint::client("0.0.0.0:0".parse()?)?);
+        }
+
+        // Keep what it takes to dial peers added while running.
+        *self.dialer.lock().unwrap() = Some(Dialer {
+            endpoint: endpoints[0].clone(),
+            cert_filename,
+        });
+        self.set_peers(peers)?;
+
+        // Take requests from local tools.
+        if let Some(control_socket) = &control_socket {
+            let control_server = serve_control(control_socket, self.clone())?;
+            tokio::spawn(control_server);
+        }
+
+        // Tell every peer about local changes until asked to shut down.
+        let mut shutdown = self.shutdown.subscribe();
+        let mut cleanup = tokio::time::interval(VERSIONS_CLEANUP_INTERVAL);
+        loop {
+            let (folder_id, kind, path) = tokio::select! {
+                Some(change) = watcher_rx.recv() => change,
+                _ = cleanup.tick() => {
+                    if let Err(e) = self.clean_versions().await {
+                        error!("Fail to remove expired versions: {e:?}");
+                    }
+
+                    continue;
+                }
+                _ = shutdown.changed() => break,
+            };
+
+            if let Err(e) = self
+                .handle_local_change(&folder_id, kind, path.clone())
+                .await
+            {
+                error!("Fail to handle local change to {path:?} in folder {folder_id}: {e:?}");
+            }
+        }
+
+        // Let the peers finish their transfers before closing the connections.
+        let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+            while !self.peers.lock().unwrap().is_empty() {
+                tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Closing the connections before every transfer finished.");
+        }
+
+        for endpoint in &endpoints {
+            endpoint.close(NODE_SHUTDOWN, b"Shutting down.");
+            endpoint.wait_idle().await;
+        }
+
+        if let Some(control_socket) = &control_socket {
+            std::fs::remove_file(control_socket)?;
+        }
+
+        info!("Shut down.");
+
+        Ok(())
+    }
+
+    pub fn shutdown(&self) {
+        info!("Shutting down...");
+
+        // Stop dialing peers, the peers connected finish what they are doing.
+        self.dialed_peers.lock().unwrap().clear();
+        self.shutdown.send_replace(true);
+    }
+
+    pub async fn pause(&self, folder_id: &FolderId) -> Result<()> {
+        let mut index = self.folder(folder_id)?.index.write().await;
+        if !index.is_paused() {
+            index.set_paused(true);
+            info!("Paused folder {folder_id}.");
+        }
+
+        Ok(())
+    }
+
+    pub async fn resume(&self, folder_id: &FolderId) -> Result<()> {
+        {
+            let mut index = self.folder(folder_id)?.index.write().await;
+            if !index.is_paused() {
+                return Ok(());
+            }
+
+            index.set_paused(false);
+            info!("Resumed folder {folder_id}.");
+        }
+
+        // Catch up with what changed here and on the peers in the meantime.
+        self.rescan(folder_id).await?;
+        let _ = self.resumed.send(folder_id.clone());
+
+        Ok(())
+    }
+
+    pub async fn rescan(&self, folder_id: &FolderId) -> Result<()> {
+        let index = &self.folder(folder_id)?.index;
+        let (root, ignore_patterns, mut paths) = {
+            let index = index.read().await;
+            if index.is_paused() {
+                return Err(eyre!("Folder {folder_id} is paused."));
+            }
+
+            let paths: BTreeSet<_> = index
+                .files()
+                .map(|file_info| file_info.path().clone())
+                .collect();
+            (
+                index.root().to_owned(),
+                index.ignore_patterns().clone(),
+                paths,
+            )
+        };
+
+        // Nothing on disk says nothing about the files while the root is missing.
+        if !root.is_dir() {
+            self.pause(folder_id).await?;
+
+            return Err(eyre!(
+                "Folder {folder_id} is missing its root {root:?}, it stays paused."
+            ));
+        }
+
+        // Look at every file on disk and every indexed one, only changed files get hashed.
+        info!("Rescanning folder {folder_id}...");
+        paths.extend(
+            tokio::task::spawn_blocking(move || list_files(&root, &ignore_patterns)).await?,
+        );
+        for path in paths {
+            if let Err(e) = self
+                .handle_local_change(folder_id, ChangeKind::Modified, path.clone())
+                .await
+            {
+                error!("Fail to rescan {path:?} in folder {folder_id}: {e:?}");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn verify(
+        &self,
+        folder_id: &FolderId,
+        with_peers: bool,
+        repair: bool,
+    ) -> Result<Vec<Mismatch>> {
+        let index = &self.folder(folder_id)?.index;
+        let paths: Vec<_> = index
+            .read()
+            .await
+            .files()
+            .map(|file_info| file_info.path().clone())
+            .collect();
+        let peers = self.folder_peers(folder_id);
+
+        // Rehash every indexed file, repairing corrupted blocks when asked to.
+        info!("Verifying folder {folder_id}...");
+        let mut mismatches = Vec::new();
+        for path in &paths {
+            let problem = match verify_file(path, index).await {
+                Ok(Some(problem)) => problem,
+                Ok(None) => continue,
+                Err(e) => Problem::Unreadable(format!("{e:#}")),
+            };
+            warn!("File {path:?} in folder {folder_id} is {problem}.");
+
+            let repaired = match &problem {
+                Problem::Corrupted(block_infos) if repair => {
+                    match repair_file(path, block_infos, index, &peers).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            error!("Fail to repair {path:?} in folder {folder_id}: {e:#}");
+
+                            false
+                        }
+                    }
+                }
+                _ => false,
+            };
+            mismatches.push(Mismatch::new(folder_id, path, &problem, repaired));
+        }
+
+        // Compare what this folder believes with what the peers have.
+        if with_peers {
+            for (connection, session) in &peers {
+                for (path, problem) in
+                    compare_with_peer(folder_id, &paths, index, connection, session).await?
+                {
+                    mismatches.push(Mismatch::new(folder_id, &path, &problem, false));
+                }
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    pub async fn override_peers(&self, folder_id: &FolderId) -> Result<()> {
+        let index = &self.folder(folder_id)?.index;
+        check_folder_mode(folder_id, index, FolderMode::SendOnly).await?;
+
+        // Make every file that differs newer than the copy of the peer and send it, remove what only the peer has.
+        info!("Overriding the peers of folder {folder_id}...");
+        for (connection, session) in &self.folder_peers(folder_id) {
+            let device_id = device_id_to_string(session.device_id());
+            if !session.has_feature(Hello::FEATURE_DIRECTORY_SYNC)
+                || !session.has_feature(Hello::FEATURE_FILE_INFO_REQUEST)
+            {
+                warn!("Device {device_id} is unable to compare folders, it is not overridden.");
+
+                continue;
+            }
+
+            let (mut write_framed, mut read_framed) =
+                open_folder_stream(connection, folder_id).await?;
+            let mut differences = Differences::default();
+            start_directory_sync(
+                &WirePath::default(),
+                &mut write_framed,
+                &mut read_framed,
+                index,
+                session,
+                &mut differences,
+            )
+            .await?;
+            write_framed.close().await?;
+
+            let (mut write_framed, mut read_framed) =
+                open_folder_stream(connection, folder_id).await?;
+            for (path, direction) in differences.files() {
+                if *direction == Some(Direction::Download) {
+                    self.remove_on_peer(folder_id, path.clone(), connection, session)
+                        .await?;
+
+                    continue;
+                }
+
+                let (file_info, _) = request_file_info(
+                    path,
+                    session.hash_algorithm(),
+                    &mut write_framed,
+                    &mut read_framed,
+                )
+                .await?;
+                {
+                    let mut index = index.write().await;
+                    let version = index.file_info(path).map(FileInfo::version);
+                    if version.is_some_and(|version| version <= file_info.version()) {
+                        index.set_version(path, file_info.version() + 1);
+                    }
+                }
+
+                if let Err(e) = transfer_file(path, connection, index, session).await {
+                    error!("Fail to override {path:?} on device {device_id}: {e:#}");
+                }
+            }
+            write_framed.close().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn revert_local_changes(&self, folder_id: &FolderId) -> Result<()> {
+        let index = &self.folder(folder_id)?.index;
+        check_folder_mode(folder_id, index, FolderMode::ReceiveOnly).await?;
+
+        // Bring back what a peer has, remove what none of them has.
+        info!("Reverting the local changes of folder {folder_id}...");
+        for (connection, session) in &self.folder_peers(folder_id) {
+            let device_id = device_id_to_string(session.device_id());
+            if !session.has_feature(Hello::FEATURE_FILE_INFO_REQUEST) {
+                warn!("Device {device_id} is unable to describe files, no changes are reverted from it.");
+
+                continue;
+            }
+
+            let paths: Vec<_> = index.read().await.local_changes().cloned().collect();
+            let (mut write_framed, mut read_framed) =
+                open_folder_stream(connection, folder_id).await?;
+            for path in &paths {
+                let (file_info, _) = request_file_info(
+                    path,
+                    session.hash_algorithm(),
+                    &mut write_framed,
+                    &mut read_framed,
+                )
+                .await?;
+
+                // A version lower than any the peer has makes its copy come back.
+                let same = index
+                    .read()
+                    .await
+                    .file_info(path)
+                    .is_some_and(|known| known.has_same_content(&file_info));
+                let result = if same {
+                    Ok(true)
+                } else if file_info.is_removed() {
+                    apply_removal(path, index, session).await.map(|()| true)
+                } else {
+                    index.write().await.set_version(path, 0);
+                    transfer_file(path, connection, index, session)
+                        .await
+                        .map(|direction| direction == Some(Direction::Download))
+                };
+                match result {
+                    Ok(true) => index.write().await.forget_local_change(path),
+                    Ok(false) => {}
+                    Err(e) => error!("Fail to revert {path:?} from device {device_id}: {e:#}"),
+                }
+            }
+            write_framed.close().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn versions(&self, path: &Path) -> Result<Vec<VersionStatus>> {
+        let (folder_id, index, path) = self.locate(path).await?;
+        let root = index.read().await.root().to_owned();
+        let versions = list_versions(&root, &path)?;
+
+        Ok(versions
+            .iter()
+            .map(|version| VersionStatus::new(folder_id, &path, version))
+            .collect())
+    }
+
+    pub async fn restore(&self, path: &Path, at: Option<SystemTime>) -> Result<VersionStatus> {
+        let (folder_id, index, path) = self.locate(path).await?;
+
+        // Copy and hash the old content without holding the index, peers keep syncing meanwhile.
+        let (root, versioning) = {
+            let index = index.read().await;
+            (index.root().to_owned(), index.versioning())
+        };
+        let versions = list_versions(&root, &path)?;
+        let version = version_at(&versions, at)
+            .ok_or_else(|| match at {
+                Some(_) => eyre!("File {path:?} has not been replaced since then."),
+                None => eyre!("No versions of file {path:?} are kept."),
+            })?
+            .clone();
+        let (temporary_file, file_info, block_infos) = {
+            let (path, version, device_id) = (path.clone(), version.clone(), self.device_id);
+            let hash_algorithm = self.options.hash_algorithm();
+            tokio::task::spawn_blocking(move || -> Result<_> {
+                let temporary_file =
+                    restore_version(&root, &path, &version, versioning, &device_id)?;
+                let (file_info, block_infos) =
+                    FileInfo::with_blocks(temporary_file.path(), path, hash_algorithm)?;
+
+                Ok((temporary_file, file_info, block_infos))
+            })
+            .await??
+        };
+
+        // Move it in place as a new version, holding the index so the watcher sees it indexed.
+        let mut index = index.write().await;
+        tokio::fs::rename(temporary_file.path(), path.to_local(index.root())).await?;
+        if index.update_file(file_info, block_infos) {
+            self.announce_local_change(folder_id, &mut index, ChangeKind::Modified, path.clone());
+        }
+        drop(index);
+        info!("Restored {path:?} in folder {folder_id}.");
+
+        Ok(VersionStatus::new(folder_id, &path, &version))
+    }
+
+    async fn locate(&self, path: &Path) -> Result<(&FolderId, &RwLock<Index>, WirePath)> {
+        // Files are named by their path on this device, whichever folder holds them.
+        for (folder_id, folder) in &self.folders {
+            let index = folder.index.read().await;
+            let Ok(relative_path) = path.strip_prefix(index.root()) else {
+                continue;
+            };
+
+            let path = WirePath::from_relative(relative_path)?;
+            if path == WirePath::default() || index.is_ignored(&path) {
+                break;
+            }
+
+            return Ok((folder_id, &folder.index, path));
+        }
+
+        Err(eyre!(
+            "Path {path:?} is not a file of a synchronized folder."
+        ))
+    }
+
+    fn folder_peers(&self, folder_id: &FolderId) -> Vec<(Connection, Arc<Session>)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.folders.contains(folder_id))
+            .map(|peer| (peer.connection.clone(), peer.session.clone()))
+            .collect()
+    }
+
+    pub fn folder_ids(&self) -> impl Iterator<Item = &FolderId> {
+        self.folders.keys()
+    }
+
+    pub async fn status(&self) -> Status {
+        let mut folders = Vec::new();
+        for (folder_id, folder) in &self.folders {
+            // Files waiting for any peer are not in sync, whichever way they go.
+            let pending = self.transfers.pending(folder_id);
+            let pending_count = |direction| {
+                pending
+                    .iter()
+                    .filter(|(_, pending_direction)| *pending_direction == direction)
+                    .count()
+            };
+            let pending_paths: HashSet<_> = pending.iter().map(|(path, _)| path).collect();
+
+            let index = folder.index.read().await;
+            let in_sync: Vec<_> = index
+                .files()
+                .filter(|file_info| !pending_paths.contains(file_info.path()))
+                .collect();
+            folders.push(FolderStatus {
+                id: folder_id.to_string(),
+                path: index.root().to_owned(),
+                mode: value_name(index.mode()),
+                paused: index.is_paused(),
+                files: index.files().count(),
+                bytes: index.files().map(FileInfo::size).sum(),
+                in_sync_files: in_sync.len(),
+                in_sync_bytes: in_sync.iter().map(|file_info| file_info.size()).sum(),
+                pending_uploads: pending_count(Some(Direction::Upload)),
+                pending_downloads: pending_count(Some(Direction::Download)),
+                pending_comparisons: pending_count(None),
+                conflicts: index.conflicts().map(WirePath::to_string).collect(),
+                local_changes: index.local_changes().map(WirePath::to_string).collect(),
+                errors: index
+                    .errors()
+                    .map(|(path, error)| FileError {
+                        path: path.to_string(),
+                        error: error.clone(),
+                    })
+                    .collect(),
+            });
+        }
+
+        Status {
+            device_id: device_id_to_string(&self.device_id),
+            folders,
+            peers: self.peer_statuses(),
+        }
+    }
+
+    pub fn peer_statuses(&self) -> Vec<PeerStatus> {
+        let now = SystemTime::now();
+        let peers = self.peers.lock().unwrap();
+        let mut peer_statuses: Vec<_> = peers
+            .iter()
+            .map(|(device_id, peer)| {
+                let (upload_rate, download_rate) = self.transfers.throughput(device_id);
+
+                PeerStatus {
+                    device_id: Some(device_id_to_string(device_id)),
+                    address: peer.connection.remote_address().to_string(),
+                    connected: true,
+                    since: Some(peer.since),
+                    last_seen: Some(now),
+                    folders: peer.folders.iter().map(FolderId::to_string).collect(),
+                    upload_rate,
+                    download_rate,
+                }
+            })
+            .collect();
+
+        // Peers dialed without a connection yet are listed too, then the ones that left.
+        let last_seen = self.last_seen.lock().unwrap();
+        let mut listed: HashSet<_> = peers.keys().copied().collect();
+        for (peer, device_id) in self.dialed_peers.lock().unwrap().iter() {
+            if device_id.is_some_and(|device_id| !listed.insert(device_id)) {
+                continue;
+            }
+
+            peer_statuses.push(PeerStatus {
+                device_id: device_id.as_ref().map(device_id_to_string),
+                address: peer.to_string(),
+                connected: false,
+                since: None,
+                last_seen: device_id
+                    .and_then(|device_id| last_seen.get(&device_id))
+                    .map(|(_, last_seen)| *last_seen),
+                folders: Vec::new(),
+                upload_rate: 0,
+                download_rate: 0,
+            });
+        }
+
+        for (device_id, (address, last_seen)) in last_seen.iter() {
+            if listed.contains(device_id) {
+                continue;
+            }
+
+            peer_statuses.push(PeerStatus {
+                device_id: Some(device_id_to_string(device_id)),
+                address: address.to_string(),
+                connected: false,
+                since: None,
+                last_seen: Some(*last_seen),
+                folders: Vec::new(),
+                upload_rate: 0,
+                download_rate: 0,
+            });
+        }
+
+        peer_statuses
+    }
+
+    pub fn transfer_statuses(&self) -> Vec<TransferStatus> {
+        self.transfers
+            .list()
+            .into_iter()
+            .map(|transfer| TransferStatus {
+                device_id: device_id_to_string(transfer.device_id()),
+                folder_id: transfer.folder_id().to_string(),
+                path: transfer.path().to_string(),
+                direction: transfer.direction().map(direction_name),
+                started: Some(transfer.started()),
+            })
+            .collect()
+    }
+
+    async fn clean_versions(&self) -> Result<()> {
+        // Versions also expire while no new one is kept.
+        for (folder_id, folder) in &self.folders {
+            let (root, versioning) = {
+                let index = folder.index.read().await;
+                (index.root().to_owned(), index.versioning())
+            };
+            let removed =
+                tokio::task::spawn_blocking(move || clean_versions(&root, versioning)).await??;
+            if removed > 0 {
+                info!("Removed {removed} expired versions in folder {folder_id}.");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_local_change(
+        &self,
+        folder_id: &FolderId,
+        kind: ChangeKind,
+        path: WirePath,
+    ) -> Result<()> {
+        // Paused folders pick up what changed with a rescan once resumed.
+        let index = &self.folder(folder_id)?.index;
+        let skipped = {
+            let index = index.read().await;
+            index.is_ignored(&path) || index.is_paused()
+        };
+        if skipped {
+            return Ok(());
+        }
+
+        // A file that is gone by now was removed or renamed away.
+        let (local_path, root_exists) = {
+            let index = index.read().await;
+            (path.to_local(index.root()), index.root().is_dir())
+        };
+        let kind = match kind {
+            ChangeKind::Modified if !local_path.exists() => ChangeKind::Removed,
+            kind => kind,
+        };
+
+        // A missing root was moved or unmounted, peers must not take it for the removal of every file.
+        if kind == ChangeKind::Removed && (path.depth() == 0 || !root_exists) {
+            if !root_exists {
+                error!("Folder {folder_id} is missing its root, pausing it until it is back and resumed.");
+                self.pause(folder_id).await?;
+            }
+
+            return Ok(());
+        }
+
+        let changed = match kind {
+            ChangeKind::Modified => self.update_local_file(&path, index).await?,
+            ChangeKind::Removed => index.write().await.remove_path(&path),
+        };
+
+        // What peers wrote here is already indexed, so it never goes back around.
+        if changed {
+            debug!("Local change {kind:?} to {path:?}.");
+            self.announce_local_change(folder_id, &mut *index.write().await, kind, path);
+        }
+
+        Ok(())
+    }
+
+    fn announce_local_change(
+        &self,
+        folder_id: &FolderId,
+        index: &mut Index,
+        kind: ChangeKind,
+        path: WirePath,
+    ) {
+        // Receive only folders keep local changes to themselves, until they are reverted.
+        if index.mode() == FolderMode::ReceiveOnly {
+            warn!(
+                "Local change {kind:?} to {path:?} is not sent, folder {folder_id} only receives."
+            );
+            index.add_local_change(path.clone());
+        }
+
+        let _ = self
+            .changes
+            .send(Change::new(None, folder_id.clone(), kind, path));
+    }
+
+    async fn update_local_file(&self, path: &WirePath, index: &RwLock<Index>) -> Result<bool> {
+        let (local_path, known) = {
+            let index = index.read().await;
+            (path.to_local(index.root()), index.file_info(path).cloned())
+        };
+
+        // Folders only change through their files.
+        let metadata = local_path.metadata()?;
+        if !metadata.is_file() {
+            return Ok(false);
+        }
+
+        // Files received from a peer were indexed with the size and date they were written with.
+        let last_modified = metadata.modified()?;
+        if known.is_some_and(|known| {
+            known.size() == metadata.len() && *known.last_modified() == last_modified
+        }) {
+            return Ok(false);
+        }
+
+        // Hash the new content.
+        let hash_algorithm = self.options.hash_algorithm();
+        let path = path.clone();
+        let (file_info, block_infos) = tokio::task::spawn_blocking(move || {
+            FileInfo::with_blocks(&local_path, path, hash_algorithm)
+        })
+        .await??;
+
+        Ok(index.write().await.update_file(file_info, block_infos))
+    }
+
+    pub async fn handshake(&self, connection: Connection, initiator: bool) -> Result<Peer> {
+        self.handshake_with(connection, initiator, &self.hello)
+            .await
+    }
+
+    async fn one_shot_handshake(&self, connection: Connection) -> Result<Peer> {
+        // Commands connect on their own, the peer keeps its connection to this device.
+        let hello = self.hello.clone().with_features(Hello::FEATURE_ONE_SHOT);
+        self.handshake_with(connection, true, &hello).await
+    }
+
+    async fn handshake_with(
+        &self,
+        connection: Connection,
+        initiator: bool,
+        hello: &Hello,
+    ) -> Result<Peer> {
+        // The side that connected opens the control stream.
+        let (send, recv) = if initiator {
+            connection.open_bi().await?
+        } else {
+            connection.accept_bi().await?
+        };
+
+        let mut write_framed = FramedWrite::new(send, MessageEncoder);
+        let mut read_framed = FramedRead::new(recv, MessageDecoder);
+
+        // Agree on the protocol with the peer.
+        let session = Session::handshake(
+            hello,
+            &self.options,
+            &self.changes,
+            initiator,
+            &mut write_framed,
+            &mut read_framed,
+        )
+        .await?;
+
+        // A peer is only the device whose certificate it connected with.
+        if !initiator {
+            let certified_device_id = peer_device_id(&connection)?;
+            if certified_device_id != *session.device_id() {
+                return Err(eyre!(
+                    "Peer claims to be device {} but its certificate belongs to device {}.",
+                    device_id_to_string(session.device_id()),
+                    device_id_to_string(&certified_device_id)
+                ));
+            }
+        }
+
+        // Offer the folders shared with the peer, only those both sides offer are synchronized.
+        let mut offered_folders = Vec::new();
+        for (folder_id, folder) in &self.folders {
+            if folder.is_shared_with(session.device_id()) {
+                let mode = folder.index.read().await.mode();
+                offered_folders.push((folder_id.clone(), mode));
+            }
+        }
+
+        write_framed
+            .send(&Message::SharedFolders(SharedFolders::new(
+                offered_folders.clone(),
+            )))
+            .await?;
+
+        let Message::SharedFolders(shared_folders) = receive_message(&mut read_framed).await?
+        else {
+            return Err(eyre!("Did not receive shared folders."));
+        };
+
+        // Both sides follow the mode of each other to agree on what goes where.
+        let mut folders = Vec::new();
+        let mut folder_modes = HashMap::new();
+        for (folder_id, mode) in offered_folders {
+            let Some((_, remote_mode)) = shared_folders
+                .folders()
+                .iter()
+                .find(|(remote_folder_id, _)| *remote_folder_id == folder_id)
+            else {
+                continue;
+            };
+
+            let sends = mode.sends() && remote_mode.receives();
+            let receives = mode.receives() && remote_mode.sends();
+            if !sends && !receives {
+                warn!(
+                    "Folder {folder_id} is {mode:?} here and {remote_mode:?} on device {}, nothing will sync.",
+                    device_id_to_string(session.device_id())
+                );
+            }
+
+            folders.push(folder_id.clone());
+            folder_modes.insert(folder_id, *remote_mode);
+        }
+        let session = session
+            .with_folder_modes(folder_modes)
+            .with_transfers(self.transfers.clone());
+
+        if folders.is_empty() {
+            warn!(
+                "No folder is shared with device {}.",
+                device_id_to_string(session.device_id())
+            );
+        } else {
+            info!(
+                "Sharing folders {} with device {}.",
+                folders
+                    .iter()
+                    .map(FolderId::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                device_id_to_string(session.device_id())
+            );
+        }
+
+        Ok(Peer {
+            connection,
+            session: Arc::new(session),
+            initiator,
+            folders,
+            write_framed,
+            read_framed,
+        })
+    }
+
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    pub async fn sync(
+        &self,
+        peer_address: &PeerAddress,
+        cert_filename: Option<String>,
+    ) -> Result<SyncSummary> {
+        // Reconcile every folder once and stop when the last transfer is over.
+        let endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+        let connection = dial(
+            &endpoint,
+            &client_config(cert_filename, &self.identity)?,
+            peer_address,
+        )
+        .await?;
+        let peer = self.one_shot_handshake(connection).await?;
+        if !peer.session.has_feature(Hello::FEATURE_DIRECTORY_SYNC) {
+            return Err(eyre!(
+                "Device {} does not support directory synchronization.",
+                device_id_to_string(peer.device_id())
+            ));
+        }
+
+        let summary = self.serve_peer(peer, true).await?;
+        endpoint.wait_idle().await;
+
+        Ok(summary)
+    }
+
+    pub async fn diff(
+        &self,
+        peer_address: &PeerAddress,
+        cert_filename: Option<String>,
+    ) -> Result<Vec<FolderDiff>> {
+        // Only look at the peer, changes are neither sent nor applied.
+        let endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+        let connection = dial(
+            &endpoint,
+            &client_config(cert_filename, &self.identity)?,
+            peer_address,
+        )
+        .await?;
+        let peer = self.one_shot_handshake(connection).await?;
+        if *peer.device_id() == self.device_id {
+            return Err(eyre!("Peer {peer_address} is this device."));
+        }
+
+        if !peer.session.has_feature(Hello::FEATURE_DIRECTORY_SYNC)
+            || !peer.session.has_feature(Hello::FEATURE_FILE_INFO_REQUEST)
+        {
+            return Err(eyre!(
+                "Device {} is unable to compare folders without syncing them.",
+                device_id_to_string(peer.device_id())
+            ));
+        }
+
+        let mut folder_diffs = Vec::new();
+        for folder_id in &peer.folders {
+            let index = &self.folder(folder_id)?.index;
+            folder_diffs
+                .push(diff_folder(folder_id, index, &peer.connection, &peer.session).await?);
+        }
+
+        peer.connection.close(PEER_DISCONNECTED, b"Disconnected.");
+        endpoint.wait_idle().await;
+
+        Ok(folder_diffs)
+    }
+
+    pub async fn reload(self: &Arc<Self>, config: &Config) -> Result<()> {
+        // Check everything that can fail first, so a refused configuration changes nothing.
+        let folder_configs = config.folders();
+        let mut ignore_patterns = Vec::new();
+        for folder_config in &folder_configs {
+            ignore_patterns.push(IgnorePatterns::new(
+                folder_config.ignore_patterns().to_vec(),
+            )?);
+        }
+        self.set_peers(config.peers().to_vec())?;
+
+        config.apply_limits(self.options.rate_limiter());
+
+        // Folders can change what they ignore and how they keep versions, anything else needs a restart.
+        for (folder_config, ignore_patterns) in folder_configs.iter().zip(ignore_patterns) {
+            let Some(folder) = self.folders.get(folder_config.id()) else {
+                warn!(
+                    "Folder {} is synchronized after a restart.",
+                    folder_config.id()
+                );
+
+                continue;
+            };
+
+            let mut index = folder.index.write().await;
+            if folder_config.path().canonicalize().ok().as_deref() != Some(index.root())
+                || folder_config.mode() != index.mode()
+                || folder_config.name_policy() != index.name_policy()
+                || folder_config.devices() != folder.devices
+            {
+                warn!(
+                    "Changes to the path, mode, names or devices of folder {} take effect after a restart.",
+                    folder_config.id()
+                );
+            }
+
+            index.set_ignore_patterns(ignore_patterns);
+            index.set_versioning(folder_config.versioning());
+        }
+
+        for folder_id in self.folders.keys() {
+            if !folder_configs
+                .iter()
+                .any(|folder_config| folder_config.id() == folder_id)
+            {
+                warn!("Folder {folder_id} is synchronized until a restart.");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_peers(self: &Arc<Self>, peers: Vec<PeerAddress>) -> Result<()> {
+        let dialer = self.dialer.lock().unwrap();
+        let Some(dialer) = dialer.as_ref() else {
+            return Ok(());
+        };
+
+        // Fail before changing anything, peers are kept as they are.
+        let mut dialed_peers = self.dialed_peers.lock().unwrap();
+        let added_peers: Vec<_> = peers
+            .iter()
+            .filter(|peer| !dialed_peers.contains_key(peer))
+            .cloned()
+            .collect();
+        let client_config = if added_peers.is_empty() {
+            None
+        } else {
+            Some(client_config(dialer.cert_filename.clone(), &self.identity)?)
+        };
+
+        // Stop dialing removed peers, closing the connection to them.
+        let removed_peers: Vec<_> = dialed_peers
+            .keys()
+            .filter(|peer| !peers.contains(peer))
+            .cloned()
+            .collect();
+        for peer in removed_peers {
+            info!("No longer connecting to {peer}.");
+
+            if let Some(Some(device_id)) = dialed_peers.remove(&peer) {
+                self.disconnect(&device_id);
+            }
+        }
+
+        let Some(client_config) = client_config else {
+            return Ok(());
+        };
+
+        for peer in added_peers {
+            dialed_peers.insert(peer.clone(), None);
+            tokio::spawn(connect(
+                dialer.endpoint.clone(),
+                client_config.clone(),
+                peer,
+                self.clone(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_dialing(&self, peer: &PeerAddress) -> bool {
+        self.dialed_peers.lock().unwrap().contains_key(peer)
+    }
+
+    pub fn dialed(&self, peer: &PeerAddress, device_id: DeviceId) -> bool {
+        // The peer may have been removed while connecting to it.
+        match self.dialed_peers.lock().unwrap().get_mut(peer) {
+            Some(dialed_device_id) => {
+                *dialed_device_id = Some(device_id);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn disconnect(&self, device_id: &DeviceId) {
+        if let Some(peer) = self.peers.lock().unwrap().get(device_id) {
+            peer.connection
+                .close(PEER_REMOVED, b"Removed from the configuration.");
+        }
+    }
+
+    fn folder(&self, folder_id: &FolderId) -> Result<&Folder> {
+        self.folders
+            .get(folder_id)
+            .ok_or_else(|| eyre!("Unknown folder {folder_id}."))
+    }
+
+    pub fn is_connected(&self, device_id: &DeviceId) -> bool {
+        self.peers.lock().unwrap().contains_key(device_id)
+    }
+
+    pub async fn run_peer(&self, peer: Peer) -> Result<()> {
+        self.serve_peer(peer, false).await?;
+
+        Ok(())
+    }
+
+    async fn serve_peer(&self, peer: Peer, once: bool) -> Result<SyncSummary> {
+        let device_id = *peer.device_id();
+        let connection = peer.connection.clone();
+        if device_id == self.device_id {
+            reject_peer(peer, b"Connected to itself.").await;
+
+            return Err(eyre!(
+                "Peer {} is this device.",
+                connection.remote_address()
+            ));
+        }
+
+        if *self.shutdown.borrow() {
+            reject_peer(peer, b"Shutting down.").await;
+
+            return Ok(SyncSummary::default());
+        }
+
+        // Peers connecting to each other end up with two connections, both sides keep the same one.
+        let one_shot = peer.session.is_one_shot();
+        let (initiating, accepting) = if peer.initiator {
+            (&self.device_id, &device_id)
+        } else {
+            (&device_id, &self.device_id)
+        };
+        if !one_shot
+            && !self.register_peer(
+                device_id,
+                &connection,
+                &peer.session,
+                &peer.folders,
+                initiating < accepting,
+            )
+        {
+            info!(
+                "Already connected to device {}, closing the new connection.",
+                device_id_to_string(&device_id)
+            );
+            reject_peer(peer, b"Already connected.").await;
+
+            return Ok(SyncSummary::default());
+        }
+
+        // Serve the transfers and removals the peer runs on streams of their own.
+        let folders = peer.folders.clone();
+        let (stop_streams, mut streams_stopped) = watch::channel(false);
+        let streams = {
+            let connection = connection.clone();
+            let session = peer.session.clone();
+            let indexes: Arc<BTreeMap<FolderId, Arc<RwLock<Index>>>> = Arc::new(
+                peer.folders
+                    .iter()
+                    .map(|folder_id| (folder_id.clone(), self.folders[folder_id].index.clone()))
+                    .collect(),
+            );
+            tokio::spawn(async move {
+                let mut handlers = JoinSet::new();
+                loop {
+                    let (send, recv) = tokio::select! {
+                        Some(_) = handlers.join_next(), if !handlers.is_empty() => continue,
+                        _ = streams_stopped.changed() => break,
+                        stream = connection.accept_bi() => match stream {
+                            Ok(stream) => stream,
+                            Err(_) => break,
+                        },
+                    };
+
+                    let indexes = indexes.clone();
+                    let session = session.clone();
+                    handlers.spawn(async move {
+                        if let Err(e) = handle_folder_stream(send, recv, &indexes, &session).await {
+                            error!("Fail to handle folder stream: {e:?}");
+                        }
+                    });
+                }
+
+                // Let the streams already started finish.
+                while handlers.join_next().await.is_some() {}
+            })
+        };
+
+        // A single command only asks, the peer stays connected to the device it runs on.
+        let result = if one_shot {
+            info!(
+                "Serving a single command of device {}.",
+                device_id_to_string(&device_id)
+            );
+            let mut shutdown = self.shutdown.subscribe();
+            tokio::select! {
+                _ = connection.closed() => {}
+                _ = shutdown.changed() => {}
+            }
+
+            Ok(SyncSummary::default())
+        } else {
+            self.exchange_changes(peer, once).await
+        };
+
+        // A single sync also waits for what the peer was sending, otherwise it is cut short.
+        if once && result.is_ok() {
+            let _ = stop_streams.send(true);
+            let _ = streams.await;
+        } else {
+            streams.abort();
+        }
+        connection.close(PEER_DISCONNECTED, b"Disconnected.");
+        self.unregister_peer(&device_id, &connection);
+
+        // What is left once every stream is over is what didn't sync.
+        let mut summary = result?;
+        for folder_id in &folders {
+            summary.add_folder(&*self.folder(folder_id)?.index.read().await);
+        }
+
+        Ok(summary)
+    }
+
+    async fn exchange_changes(&self, peer: Peer, once: bool) -> Result<SyncSummary> {
+        // The control stream stays open for as long as the peer is connected.
+        let Peer {
+            connection,
+            session,
+            initiator,
+            folders,
+            write_framed: _write_framed,
+            read_framed: _read_framed,
+        } = peer;
+        let device_id = device_id_to_string(session.device_id());
+        let mut summary = SyncSummary::new(device_id.clone());
+
+        // Listen for changes before the initial synchronization so none goes missing.
+        let mut changes = self.changes.subscribe();
+        let mut resumed = self.resumed.subscribe();
+        let mut shutdown = self.shutdown.subscribe();
+
+        // Every shared folder queues its own transfers.
+        let mut schedulers = Vec::new();
+        let mut _queues = Vec::new();
+        for folder_id in &folders {
+            let root = self.folder(folder_id)?.index.read().await.root().to_owned();
+            let scheduler = TransferScheduler::new(root, self.options.priority_patterns());
+            _queues.push(self.transfers.add_queue(
+                *session.device_id(),
+                folder_id.clone(),
+                scheduler.queue(),
+            ));
+            schedulers.push((folder_id.clone(), scheduler));
+        }
+
+        // The side that connected reconciles the folders, the other one answers.
+        if initiator && session.has_feature(Hello::FEATURE_DIRECTORY_SYNC) {
+            for (folder_id, scheduler) in &mut schedulers {
+                // Paused folders reconcile once resumed.
+                if self.folder(folder_id)?.index.read().await.is_paused() {
+                    continue;
+                }
+
+                info!("Starting initial synchronization of folder {folder_id} with device {device_id}...");
+                self.reconcile_folder(folder_id, scheduler, &connection, &session, &mut summary)
+                    .await?;
+            }
+        } else if initiator {
+            warn!("Device {device_id} does not support directory synchronization, skipping initial synchronization.");
+        }
+
+        // Forward changes to the peer while transferring files in the background.
+        let mut transfers = FuturesUnordered::new();
+        let mut finished = Vec::new();
+        let mut next_scheduler = 0;
+        let mut stopping = false;
+        loop {
+            // Paused folders stay queued.
+            let mut paused = BTreeSet::new();
+            for (folder_id, _) in &schedulers {
+                let index = &self.folder(folder_id)?.index;
+                if alongside(&mut transfers, &mut finished, index.read())
+                    .await
+                    .is_paused()
+                {
+                    paused.insert(folder_id.clone());
+                }
+            }
+
+            // Account for the transfers that finished, also while other work was awaited.
+            for (folder_id, path, result) in finished.drain(..) {
+                if let Some((_, scheduler)) = schedulers.iter_mut().find(|(id, _)| *id == folder_id)
+                {
+                    scheduler.finish(&path);
+                }
+                match result {
+                    Ok(direction) => {
+                        if let Some(direction) = direction {
+                            summary.add_transfer(direction);
+                        }
+                    }
+                    Err(e) if ErrorCode::from_report(&e) == ErrorCode::Disconnected => {
+                        return Err(e)
+                    }
+                    Err(e) => error!("File {path:?} did not sync with device {device_id}: {e:#}"),
+                }
+            }
+
+            // Finish the running transfers before shutting down.
+            if stopping && transfers.is_empty() {
+                break;
+            }
+
+            // Start the most urgent transfers while there is room.
+            while !stopping && transfers.len() < self.options.parallel_transfers() {
+                let Some((folder_id, path)) =
+                    pop_transfer(&mut schedulers, &mut next_scheduler, &paused)
+                else {
+                    break;
+                };
+
+                let index = &self.folder(&folder_id)?.index;
+                let (session, connection) = (&session, &connection);
+                transfers.push(async move {
+                    let result = transfer_file(&path, connection, index, session).await;
+
+                    // A disconnect is no fault of the file, the peer reconnects and syncs it.
+                    let error = match &result {
+                        Ok(_) => Some(None),
+                        Err(e) if ErrorCode::from_report(e) == ErrorCode::Disconnected => None,
+                        Err(e) => Some(Some(format!(
+                            "Did not sync with device {}: {e:#}",
+                            device_id_to_string(session.device_id())
+                        ))),
+                    };
+                    if let Some(error) = error {
+                        index.write().await.set_error(path.clone(), error);
+                    }
+
+                    (folder_id, path, result)
+                });
+            }
+
+            // A single sync is over once nothing is left to transfer.
+            if once && transfers.is_empty() {
+                break;
+            }
+
+            let change = tokio::select! {
+                Some(transfer) = transfers.next(), if !transfers.is_empty() => {
+                    finished.push(transfer);
+
+                    continue;
+                }
+                change = changes.recv() => match change {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Missed {skipped} changes for device {device_id}, they will sync when it reconnects.");
+
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                folder_id = resumed.recv() => {
+                    // Catch up with the peer on what changed while the folder was paused.
+                    let Ok(folder_id) = folder_id else {
+                        continue;
+                    };
+                    let Some((folder_id, scheduler)) = schedulers.iter_mut().find(|(id, _)| *id == folder_id) else {
+                        continue;
+                    };
+                    if !stopping && session.has_feature(Hello::FEATURE_DIRECTORY_SYNC) {
+                        let reconcile = self.reconcile_folder(folder_id, scheduler, &connection, &session, &mut summary);
+                        alongside(&mut transfers, &mut finished, reconcile).await?;
+                    }
+
+                    continue;
+                }
+                _ = shutdown.changed(), if !stopping => {
+                    stopping = true;
+
+                    continue;
+                }
+                reason = connection.closed() => {
+                    info!("Connection to device {device_id} closed: {reason}");
+
+                    break;
+                }
+            };
+
+            // Never send a change back to the peer it came from, nor one of a folder it doesn't share.
+            if change.origin() == Some(session.device_id()) {
+                continue;
+            }
+
+            let Some((folder_id, scheduler)) = schedulers
+                .iter_mut()
+                .find(|(folder_id, _)| folder_id == change.folder_id())
+            else {
+                continue;
+            };
+
+            // Only pass on changes this folder may send and the peer may receive.
+            let index = &self.folder(folder_id)?.index;
+            let mode = alongside(&mut transfers, &mut finished, index.read())
+                .await
+                .mode();
+            if !mode.sends() || !session.folder_mode(folder_id).receives() {
+                continue;
+            }
+
+            let path = change.path().clone();
+            match change.kind() {
+                ChangeKind::Modified => scheduler.push(path, Some(Direction::Upload)),
+                ChangeKind::Removed => {
+                    if !session.can_represent(&path) {
+                        warn!("Skipping {path:?}, device {device_id} can't represent names that are not unicode.");
+
+                        continue;
+                    }
+
+                    let removal = self.remove_on_peer(folder_id, path, &connection, &session);
+                    if alongside(&mut transfers, &mut finished, removal).await? {
+                        summary.add_removal(Direction::Upload);
+                    }
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    async fn reconcile_folder(
+        &self,
+        folder_id: &FolderId,
+        scheduler: &mut TransferScheduler,
+        connection: &Connection,
+        session: &Session,
+        summary: &mut SyncSummary,
+    ) -> Result<()> {
+        let index = &self.folder(folder_id)?.index;
+        let (mut write_framed, mut read_framed) = open_folder_stream(connection, folder_id).await?;
+        let mut differences = Differences::default();
+        start_directory_sync(
+            &WirePath::default(),
+            &mut write_framed,
+            &mut read_framed,
+            index,
+            session,
+            &mut differences,
+        )
+        .await?;
+        write_framed.close().await?;
+
+        // Files are transferred later, most urgent first.
+        for (path, direction) in differences.files() {
+            scheduler.push(path.clone(), *direction);
+        }
+
+        // A mirror leaves nothing behind that the mirrored folder doesn't have.
+        for path in differences.local_removals() {
+            match apply_removal(path, index, session).await {
+                Ok(()) => summary.add_removal(Direction::Download),
+                Err(e) => error!("Fail to remove {path:?}: {e:?}"),
+            }
+        }
+
+        // Remove what a mirrored folder no longer has.
+        for path in differences.remote_removals() {
+            if self
+                .remove_on_peer(folder_id, path.clone(), connection, session)
+                .await?
+            {
+                summary.add_removal(Direction::Upload);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_on_peer(
+        &self,
+        folder_id: &FolderId,
+        path: WirePath,
+        connection: &Connection,
+        session: &Session,
+    ) -> Result<bool> {
+        // Only losing the connection ends the exchange, other failures are reported with the path.
+        let error = match send_removal(folder_id, &path, connection).await {
+            Ok(()) => None,
+            Err(e) if ErrorCode::from_report(&e) == ErrorCode::Disconnected => return Err(e),
+            Err(e) => {
+                let device_id = device_id_to_string(session.device_id());
+                error!("Device {device_id} did not remove {path:?}: {e:#}");
+
+                Some(format!("Device {device_id} did not remove it: {e:#}"))
+            }
+        };
+        let removed = error.is_none();
+        self.folder(folder_id)?
+            .index
+            .write()
+            .await
+            .set_error(path, error);
+
+        Ok(removed)
+    }
+
+    fn register_peer(
+        &self,
+        device_id: DeviceId,
+        connection: &Connection,
+        session: &Arc<Session>,
+        folders: &[FolderId],
+        preferred: bool,
+    ) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+
+        // Keep the connection both sides prefer, otherwise the newest replaces a stale one.
+        if let Some(existing) = peers.get(&device_id) {
+            let alive = existing.connection.close_reason().is_none();
+            if alive && existing.preferred && !preferred {
+                return false;
+            }
+
+            existing
+                .connection
+                .close(DUPLICATE_CONNECTION, b"Replaced by another connection.");
+        }
+
+        peers.insert(
+            device_id,
+            PeerConnection {
+                connection: connection.clone(),
+                session: session.clone(),
+                preferred,
+                folders: folders.to_vec(),
+                since: SystemTime::now(),
+            },
+        );
+
+        true
+    }
+
+    fn unregister_peer(&self, device_id: &DeviceId, connection: &Connection) {
+        // A replaced connection must not remove the one that replaced it.
+        let mut peers = self.peers.lock().unwrap();
+        if peers
+            .get(device_id)
+            .is_some_and(|peer| peer.connection.stable_id() == connection.stable_id())
+        {
+            peers.remove(device_id);
+            self.last_seen
+                .lock()
+                .unwrap()
+                .insert(*device_id, (connection.remote_address(), SystemTime::now()));
+        }
+    }
+}
+
+async fn check_folder_mode(
+    folder_id: &FolderId,
+    index: &RwLock<Index>,
+    mode: FolderMode,
+) -> Result<()> {
+    let index = index.read().await;
+    if index.is_paused() {
+        return Err(eyre!("Folder {folder_id} is paused."));
+    }
+    if index.mode() != mode {
+        return Err(eyre!(
+            "Folder {folder_id} is {}, only {} folders do that.",
+            value_name(index.mode()),
+            value_name(mode)
+        ));
+    }
+
+    Ok(())
+}
+
+async fn reject_peer(mut peer: Peer, reason: &[u8]) {
+    // Let the peer read our hello first, so it knows which device it is already connected to.
+    let _ = peer.write_framed.close().await;
+    peer.connection.close(DUPLICATE_CONNECTION, reason);
+}
+
+fn watch_folder(
+    folder_id: FolderId,
+    root: PathBuf,
+    watcher_tx: mpsc::Sender<(FolderId, ChangeKind, WirePath)>,
+) -> Result<RecommendedWatcher> {
+    let mut watcher = {
+        let root = root.clone();
+        notify::recommended_watcher(move |res: Result<notify::Event, _>| {
+            let event = match res {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Watcher error: {e:?}");
+
+                    return;
+                }
+            };
+
+            let kind = match event.kind {
+                notify::EventKind::Modify(_) => ChangeKind::Modified,
+                notify::EventKind::Remove(_) => ChangeKind::Removed,
+
+                // Other kinds of events don't change what peers should have.
+                _ => return,
+            };
+
+            // Make paths relative to the folder, ignoring files that are still being received.
+            for path in event.paths.iter().filter(|path| !is_temporary_path(path)) {
+                match WirePath::from_local(&root, path) {
+                    Ok(path) => {
+                        if watcher_tx
+                            .blocking_send((folder_id.clone(), kind, path))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Ignoring watcher event: {e:?}"),
+                }
+            }
+        })?
+    };
+
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    Ok(watcher)
+}
+
+async fn alongside<T, F: Future>(
+    transfers: &mut FuturesUnordered<impl Future<Output = T>>,
+    finished: &mut Vec<T>,
+    work: F,
+) -> F::Output {
+    // Running transfers may hold an index and only move on when polled, so keep polling them meanwhile.
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            output = &mut work => return output,
+            Some(transfer) = transfers.next(), if !transfers.is_empty() => finished.push(transfer),
+        }
+    }
+}
+
+fn pop_transfer(
+    schedulers: &mut [(FolderId, TransferScheduler)],
+    next_scheduler: &mut usize,
+    paused: &BTreeSet<FolderId>,
+) -> Option<(FolderId, WirePath)> {
+    // Take turns between folders so a busy one doesn't hold back the others.
+    let len = schedulers.len();
+    for _ in 0..len {
+        let (folder_id, scheduler) = &mut schedulers[*next_scheduler % len];
+        *next_scheduler = (*next_scheduler + 1) % len;
+        if paused.contains(folder_id) {
+            continue;
+        }
+
+        if let Some(path) = scheduler.pop() {
+            return Some((folder_id.clone(), path));
+        }
+    }
+
+    None
+}
+
+pub async fn open_folder_stream(
+    connection: &Connection,
+    folder_id: &FolderId,
+) -> Result<(
+    FramedWrite<SendStream, MessageEncoder>,
+    FramedRead<RecvStream, MessageDecoder>,
+)> {
+    let (send, recv) = connection.open_bi().await?;
+    let mut write_framed = FramedWrite::new(send, MessageEncoder);
+    let read_framed = FramedRead::new(recv, MessageDecoder);
+
+    // Every stream starts by naming the folder it works on.
+    write_framed
+        .send(&Message::SelectFolder(SelectFolder::new(folder_id.clone())))
+        .await?;
+
+    Ok((write_framed, read_framed))
+}
+
+async fn handle_folder_stream(
+    send: SendStream,
+    recv: RecvStream,
+    indexes: &BTreeMap<FolderId, Arc<RwLock<Index>>>,
+    session: &Session,
+) -> Result<()> {
+    let mut write_framed = FramedWrite::new(send, MessageEncoder);
+    let mut read_framed = FramedRead::new(recv, MessageDecoder);
+
+    // Only serve folders shared with the peer.
+    let Message::SelectFolder(select_folder) = receive_message(&mut read_framed).await? else {
+        return Err(eyre!("Stream did not start by selecting a folder."));
+    };
+
+    let folder_id = select_folder.folder_id();
+    let Some(index) = indexes.get(folder_id) else {
+        let message = format!("Folder {folder_id} is not shared with this device.");
+        write_framed
+            .send(&Message::Error(Error::new(
+                ErrorCode::Protocol,
+                PathId::default(),
+                message.clone(),
+            )))
+            .await?;
+
+        return Err(eyre!(message));
+    };
+
+    while let Some(message) = read_framed.try_next().await? {
+        match message {
+            Message::FileInfo(file_info) => {
+                if let Err(e) = handle_file_sync(
+                    &file_info,
+                    &mut write_framed,
+                    &mut read_framed,
+                    index,
+                    session,
+                )
+                .await
+                {
+                    error!("Fail to handle file sync: {e:?}");
+                }
+            }
+            Message::FileInfoRequest(request) => {
+                if let Err(e) = handle_file_info_request(&request, &mut write_framed, index).await {
+                    error!("Fail to handle file info request: {e:?}");
+                }
+            }
+            Message::BlockRequest(request) => {
+                if let Err(e) =
+                    handle_block_request(&request, &mut write_framed, index, session).await
+                {
+                    error!("Fail to handle block request: {e:?}");
+                }
+            }
+            Message::DirectoryInfo(directory_info) => {
+                if let Err(e) =
+                    handle_directory_sync(&directory_info, &mut write_framed, index).await
+                {
+                    error!("Fail to handle directory sync: {e:?}");
+                }
+            }
+            Message::WatcherEvent(event) if matches!(event.kind, notify::EventKind::Remove(_)) => {
+                for path in &event.paths {
+                    let path = WirePath::from_relative(path)?;
+                    let result = apply_removal(&path, index, session).await;
+                    if let Err(e) = &result {
+                        error!("Fail to remove {path:?}: {e:?}");
+                    }
+
+                    // Let the peer know whether the path is gone.
+                    let path_id = PathIdCache::calculate_path_id(&path);
+                    send_outcome(&path_id, &result, &mut write_framed).await?;
+                }
+            }
+
+            // Leftovers of an operation the peer already gave up on.
+            message => debug!("Discarding message outside of an operation: {message:?}"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use crate::messages::{Ack, Error, Message, MessageDecoder, MessageEncoder, PathId};
+use color_eyre::{eyre::eyre, Result};
+use futures::{SinkExt, TryStreamExt};
+use quinn::{RecvStream, SendStream};
+use std::io::ErrorKind;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::*;
+
+pub async fn receive_message(
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+) -> Result<Message> {
+    // An error from the peer ends the current operation.
+    match read_framed.try_next().await? {
+        Some(Message::Error(error)) => Err(error.into()),
+        Some(message) => Ok(message),
+        None => {
+            Err(std::io::Error::new(ErrorKind::UnexpectedEof, "Peer closed the connection.").into())
+        }
+    }
+}
+
+pub async fn send_outcome(
+    path_id: &PathId,
+    result: &Result<()>,
+    write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+) -> Result<()> {
+    let message = match result {
+        Ok(()) => Message::Ack(Ack::new(*path_id)),
+        Err(e) => Message::Error(Error::from_report(*path_id, e)),
+    };
+    write_framed.send(&message).await?;
+
+    Ok(())
+}
+
+pub async fn receive_outcome(
+    path_id: &PathId,
+    read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+) -> Result<()> {
+    loop {
+        match receive_message(read_framed).await? {
+            Message::Ack(ack) if ack.path_id() == path_id => return Ok(()),
+            Message::Ack(_) => return Err(eyre!("Received acknowledgement for another file.")),
+
+            // Skip whatever the peer was still sending when the operation failed.
+            message => debug!("Discarding message while waiting for outcome: {message:?}"),
+        }
+    }
+}
+
+pub fn is_peer_error(report: &color_eyre::Report) -> bool {
+    report.downcast_ref::<Error>().is_some()
+}
@@ -1,32 +1,42 @@
-use crate::messages::PathId;
-use md5::Digest;
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-};
+use crate::messages::{name_bytes, PathId, WirePath};
+use sha3::Digest;
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Default)]
 pub struct PathIdCache {
-    path_ids: HashMap<PathId, PathBuf>,
+    path_ids: HashMap<PathId, WirePath>,
 }
 
 impl PathIdCache {
-    pub fn add_path(&self, path: impl Into<PathBuf>) {
-        todo!();
+    pub fn add_path(&mut self, path: &WirePath) -> PathId {
+        let path_id = Self::calculate_path_id(path);
+
+        self.path_ids.insert(path_id, path.clone());
+
+        path_id
+    }
+
+    pub fn remove_path(&mut self, path_id: &PathId) -> Option<WirePath> {
+        self.path_ids.remove(path_id)
     }
 
-    pub fn get_path(&self, path_id: &PathId) -> Option<&PathId> {
-        todo!();
+    pub fn get_path(&self, path_id: &PathId) -> Option<&WirePath> {
+        self.path_ids.get(path_id)
     }
 
-    pub fn calculate_path_id(path: impl AsRef<Path>) -> PathId {
+    pub fn calculate_path_id(path: &WirePath) -> PathId {
         let mut hasher = sha3::Sha3_256::new();
 
-        let path = path.as_ref().to_str().unwrap();
-        hasher.update(path);
+        // Peers may store the same name in different unicode forms, hash the composed one.
+        let path = path.to_os_string();
+        match path.to_str() {
+            Some(path) => hasher.update(path.nfc().collect::<String>()),
+            None => hasher.update(name_bytes(&path)),
+        }
 
         let path_id = hasher.finalize();
 
-        path_id.try_into().unwrap()
+        path_id.into()
     }
 }
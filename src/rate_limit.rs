@@ -0,0 +1,296 @@
+use chrono::{Local, NaiveTime};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report, Result,
+};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(u64);
+
+impl FromStr for Rate {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Accept plain bytes per second or a binary K, M or G suffix.
+        let (number, multiplier) = match s.trim().to_ascii_uppercase() {
+            s if s.ends_with('K') => (s[..s.len() - 1].to_owned(), 1 << 10),
+            s if s.ends_with('M') => (s[..s.len() - 1].to_owned(), 1 << 20),
+            s if s.ends_with('G') => (s[..s.len() - 1].to_owned(), 1 << 30),
+            s => (s, 1),
+        };
+
+        let number: u64 = number
+            .parse()
+            .wrap_err_with(|| format!("Invalid rate {s:?}, expected a number like 512K or 2M."))?;
+        if number == 0 {
+            return Err(eyre!("Invalid rate {s:?}, the rate must be above zero."));
+        }
+
+        Ok(Self(number.saturating_mul(multiplier)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    upload: Option<Rate>,
+    download: Option<Rate>,
+}
+
+impl RateLimits {
+    pub fn new(upload: Option<Rate>, download: Option<Rate>) -> Self {
+        Self { upload, download }
+    }
+
+    fn get(&self, direction: Direction) -> Option<Rate> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateSchedule {
+    start: NaiveTime,
+    end: NaiveTime,
+    limits: RateLimits,
+}
+
+impl RateSchedule {
+    fn contains(&self, time: NaiveTime) -> bool {
+        // A window ending before it starts runs through midnight, an empty one lasts all day.
+        if self.start == self.end {
+            true
+        } else if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for RateSchedule {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let format_error = || {
+            eyre!("Invalid schedule {s:?}, expected HH:MM-HH:MM=UPLOAD/DOWNLOAD, like 08:00-18:00=512K/-.")
+        };
+
+        let (window, limits) = s.split_once('=').ok_or_else(format_error)?;
+        let (start, end) = window.split_once('-').ok_or_else(format_error)?;
+        let (upload, download) = limits.split_once('/').ok_or_else(format_error)?;
+
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .wrap_err_with(|| format!("Invalid time {time:?} in schedule {s:?}."))
+        };
+        let parse_rate = |rate: &str| match rate.trim() {
+            "-" => Ok(None),
+            rate => rate.parse().map(Some),
+        };
+
+        Ok(Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            limits: RateLimits::new(parse_rate(upload)?, parse_rate(download)?),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct TokenBuckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl TokenBuckets {
+    fn get_mut(&mut self, direction: Direction) -> &mut TokenBucket {
+        match direction {
+            Direction::Upload => &mut self.upload,
+            Direction::Download => &mut self.download,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl TokenBucket {
+    fn reserve(&mut self, rate: Option<Rate>, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+
+        let Some(Rate(rate)) = rate else {
+            self.tokens = 0.0;
+
+            return Duration::ZERO;
+        };
+
+        // Refill the bucket, allowing bursts of up to one second of traffic.
+        let rate = rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+
+        // Take the tokens right away, whoever comes next waits for the debt to be paid.
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Limiter {
+    limits: RateLimits,
+    peer_limits: RateLimits,
+    schedules: Vec<RateSchedule>,
+    buckets: TokenBuckets,
+}
+
+impl Limiter {
+    fn active_limits(&self) -> RateLimits {
+        // The first schedule covering the current time replaces the global limits.
+        let now = Local::now().time();
+        self.schedules
+            .iter()
+            .find(|schedule| schedule.contains(now))
+            .map(|schedule| schedule.limits)
+            .unwrap_or(self.limits)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limiter: Arc<Mutex<Limiter>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, peer_limits: RateLimits, schedules: Vec<RateSchedule>) -> Self {
+        Self {
+            limiter: Arc::new(Mutex::new(Limiter {
+                limits,
+                peer_limits,
+                schedules,
+                buckets: TokenBuckets::default(),
+            })),
+        }
+    }
+
+    pub fn set_limits(
+        &self,
+        limits: RateLimits,
+        peer_limits: RateLimits,
+        schedules: Vec<RateSchedule>,
+    ) {
+        // Peers keep their buckets, only the rates they refill at change.
+        let mut limiter = self.limiter.lock().unwrap();
+        limiter.limits = limits;
+        limiter.peer_limits = peer_limits;
+        limiter.schedules = schedules;
+    }
+
+    pub fn peer(&self) -> PeerRateLimiter {
+        PeerRateLimiter {
+            rate_limiter: self.clone(),
+            buckets: Mutex::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PeerRateLimiter {
+    rate_limiter: RateLimiter,
+    buckets: Mutex<TokenBuckets>,
+}
+
+impl PeerRateLimiter {
+    pub async fn upload(&self, bytes: usize) {
+        self.acquire(Direction::Upload, bytes).await
+    }
+
+    pub async fn download(&self, bytes: usize) {
+        self.acquire(Direction::Download, bytes).await
+    }
+
+    async fn acquire(&self, direction: Direction, bytes: usize) {
+        let delay = {
+            let mut limiter = self.rate_limiter.limiter.lock().unwrap();
+            let limit = limiter.active_limits().get(direction);
+            let peer_limit = limiter.peer_limits.get(direction);
+            let delay = limiter.buckets.get_mut(direction).reserve(limit, bytes);
+
+            // Wait for whichever limit is the tightest.
+            let mut buckets = self.buckets.lock().unwrap();
+            delay.max(buckets.get_mut(direction).reserve(peer_limit, bytes))
+        };
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rate, RateSchedule, TokenBucket};
+    use chrono::NaiveTime;
+    use std::time::Duration;
+
+    #[test]
+    fn rate_schedule() {
+        // Make sure rates and schedules parse.
+        assert_eq!("512K".parse::<Rate>().unwrap(), Rate(512 * 1024));
+        assert_eq!("2m".parse::<Rate>().unwrap(), Rate(2 * 1024 * 1024));
+        assert!("0".parse::<Rate>().is_err());
+        assert!("fast".parse::<Rate>().is_err());
+
+        let schedule: RateSchedule = "22:00-06:00=-/1M".parse().unwrap();
+        assert_eq!(schedule.limits.upload, None);
+        assert_eq!(schedule.limits.download, Some(Rate(1024 * 1024)));
+        assert!("22:00=1M/1M".parse::<RateSchedule>().is_err());
+
+        // Make sure windows can run through midnight.
+        let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        assert!(schedule.contains(time(23)));
+        assert!(schedule.contains(time(3)));
+        assert!(!schedule.contains(time(12)));
+
+        let schedule: RateSchedule = "00:00-00:00=1M/1M".parse().unwrap();
+        assert!(schedule.contains(time(12)));
+    }
+
+    #[test]
+    fn token_bucket() {
+        // Make sure traffic above the rate has to wait for the tokens.
+        let mut bucket = TokenBucket::default();
+        let delay = bucket.reserve(Some(Rate(1000)), 500);
+        assert!(delay > Duration::from_millis(400) && delay <= Duration::from_millis(500));
+
+        // Make sure unlimited traffic never waits.
+        assert_eq!(bucket.reserve(None, 1 << 30), Duration::ZERO);
+    }
+}
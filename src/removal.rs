@@ -0,0 +1,101 @@
+use crate::{
+    file_sync::check_paused,
+    index::Index,
+    messages::{FolderId, Message, WirePath},
+    node::{open_folder_stream, ChangeKind},
+    outcome::receive_outcome,
+    path_id_cache::PathIdCache,
+    session::Session,
+    versioning::keep_versions,
+};
+use color_eyre::{eyre::eyre, Result};
+use futures::SinkExt;
+use notify::{event::RemoveKind, Event, EventKind};
+use quinn::Connection;
+use std::io::ErrorKind;
+use tokio::sync::RwLock;
+
+pub async fn send_removal(
+    folder_id: &FolderId,
+    path: &WirePath,
+    connection: &Connection,
+) -> Result<()> {
+    // Removals get a stream of their own, like transfers, so neither waits on the other.
+    let (mut write_framed, mut read_framed) = open_folder_stream(connection, folder_id).await?;
+
+    let event = Event::new(EventKind::Remove(RemoveKind::Any)).add_path(path.to_relative());
+    write_framed.send(&Message::WatcherEvent(event)).await?;
+
+    // The peer reports whether the path is gone.
+    let path_id = PathIdCache::calculate_path_id(path);
+    receive_outcome(&path_id, &mut read_framed).await?;
+    write_framed.close().await?;
+
+    Ok(())
+}
+
+pub async fn apply_removal(
+    path: &WirePath,
+    index: &RwLock<Index>,
+    session: &Session,
+) -> Result<()> {
+    // Folders that only send keep what they have.
+    let (path, mode, folder_id) = {
+        let index = index.read().await;
+        (
+            index.name_policy().local_path(path)?,
+            index.mode(),
+            index.folder_id().clone(),
+        )
+    };
+    if !mode.receives() {
+        return Err(eyre!(
+            "Folder {folder_id} is {mode:?}, it does not accept removals."
+        ));
+    }
+    if path.depth() == 0 {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("The root of folder {folder_id} is never removed."),
+        )
+        .into());
+    }
+    if index.read().await.is_ignored(&path) {
+        return Err(eyre!("Path {path:?} is ignored by the peer."));
+    }
+    check_paused(index).await?;
+
+    // Keep what is removed without holding the index, it stays in place until removed.
+    let (root, versioning) = {
+        let index = index.read().await;
+        (index.root().to_owned(), index.versioning())
+    };
+    let local_path = path.to_local(&root);
+    if local_path.exists() {
+        let (path, device_id) = (path.clone(), *session.device_id());
+        tokio::task::spawn_blocking(move || keep_versions(&root, &path, versioning, &device_id))
+            .await??;
+    }
+
+    // Hold the index while removing so the watcher never sees a removal the index doesn't know.
+    let mut index = index.write().await;
+    let result = if local_path.is_dir() {
+        tokio::fs::remove_dir_all(&local_path).await
+    } else {
+        tokio::fs::remove_file(&local_path).await
+    };
+
+    // Another peer may have removed it first.
+    match result {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // Only pass on removals this folder did not know about yet, so they don't go around in circles.
+    if index.remove_path(&path) {
+        session.notify(index.folder_id().clone(), ChangeKind::Removed, path);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use crate::{messages::WirePath, rate_limit::Direction, transfers::Queue};
+use globset::{Glob, GlobMatcher};
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::Entry, BinaryHeap, HashSet},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+const SMALL_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Priority {
+    pattern: usize,
+    large: bool,
+    last_modified: Reverse<SystemTime>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Transfer {
+    priority: Priority,
+    sequence: u64,
+    path: WirePath,
+}
+
+pub struct TransferScheduler {
+    root: PathBuf,
+    priority_patterns: Vec<GlobMatcher>,
+    queue: BinaryHeap<Reverse<Transfer>>,
+    queued: Queue,
+    active: HashSet<WirePath>,
+    sequence: u64,
+}
+
+impl TransferScheduler {
+    pub fn new(root: PathBuf, priority_patterns: &[Glob]) -> Self {
+        Self {
+            root,
+            priority_patterns: priority_patterns
+                .iter()
+                .map(Glob::compile_matcher)
+                .collect(),
+            queue: BinaryHeap::new(),
+            queued: Queue::default(),
+            active: HashSet::new(),
+            sequence: 0,
+        }
+    }
+
+    pub fn queue(&self) -> Queue {
+        self.queued.clone()
+    }
+
+    pub fn push(&mut self, path: WirePath, direction: Option<Direction>) {
+        // A file waiting for its turn already picks up the latest change.
+        match self.queued.lock().unwrap().entry(path.clone()) {
+            Entry::Occupied(mut entry) => {
+                if *entry.get() != direction {
+                    entry.insert(None);
+                }
+
+                return;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(direction);
+            }
+        }
+
+        let priority = self.priority(&path);
+        self.queue.push(Reverse(Transfer {
+            priority,
+            sequence: self.sequence,
+            path,
+        }));
+        self.sequence += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<WirePath> {
+        // Never sync the same file twice at once, leave it queued until the running sync ends.
+        let mut skipped = Vec::new();
+        let mut next = None;
+        while let Some(Reverse(transfer)) = self.queue.pop() {
+            if self.active.contains(&transfer.path) {
+                skipped.push(Reverse(transfer));
+
+                continue;
+            }
+
+            next = Some(transfer.path);
+            break;
+        }
+        self.queue.extend(skipped);
+
+        let path = next?;
+        self.queued.lock().unwrap().remove(&path);
+        self.active.insert(path.clone());
+
+        Some(path)
+    }
+
+    pub fn finish(&mut self, path: &WirePath) {
+        self.active.remove(path);
+    }
+
+    fn priority(&self, path: &WirePath) -> Priority {
+        // Files matching an earlier pattern go first, then small and recently modified ones.
+        let relative_path = path.to_relative();
+        let pattern = self
+            .priority_patterns
+            .iter()
+            .position(|pattern| pattern.is_match(&relative_path))
+            .unwrap_or(self.priority_patterns.len());
+
+        // Files that are only on the peer are treated as large and old.
+        let metadata = path.to_local(&self.root).metadata().ok();
+        let large = metadata
+            .as_ref()
+            .is_none_or(|metadata| metadata.len() > SMALL_FILE_SIZE);
+        let last_modified = metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        Priority {
+            pattern,
+            large,
+            last_modified: Reverse(last_modified),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransferScheduler;
+    use crate::{messages::WirePath, rate_limit::Direction};
+    use globset::Glob;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn transfer_scheduler() {
+        let path = |path: &str| WirePath::from_relative(Path::new(path)).unwrap();
+        let mut scheduler =
+            TransferScheduler::new(PathBuf::from("/nonexistent"), &[Glob::new("*.rs").unwrap()]);
+
+        // Make sure files matching a priority pattern go first and queued files are not repeated.
+        scheduler.push(path("assets/video.mp4"), Some(Direction::Download));
+        scheduler.push(path("src/main.rs"), Some(Direction::Upload));
+        scheduler.push(path("assets/video.mp4"), Some(Direction::Upload));
+        assert_eq!(scheduler.pop(), Some(path("src/main.rs")));
+
+        // Make sure a file queued both ways is no longer expected to go either way.
+        assert_eq!(
+            scheduler
+                .queue()
+                .lock()
+                .unwrap()
+                .get(&path("assets/video.mp4")),
+            Some(&None)
+        );
+
+        // Make sure a file being synced waits until its sync finishes.
+        scheduler.push(path("src/main.rs"), Some(Direction::Upload));
+        assert_eq!(scheduler.pop(), Some(path("assets/video.mp4")));
+        assert_eq!(scheduler.pop(), None);
+
+        scheduler.finish(&path("src/main.rs"));
+        assert_eq!(scheduler.pop(), Some(path("src/main.rs")));
+    }
+}
@@ -1,147 +1,82 @@
-use crate::{
-    certificate::*,
-    file_sync::{handle_file_sync, start_file_sync},
-    messages::{Message, MessageDecoder, MessageEncoder},
-};
+use crate::{certificate::*, node::Node};
 use color_eyre::eyre::Result;
-use futures::TryStreamExt;
-use quinn::{Endpoint, RecvStream, SendStream, ServerConfig};
-use std::{net::SocketAddr, path::PathBuf};
-use tokio::sync::broadcast;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use quinn::{Endpoint, ServerConfig};
+use rustls::{
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, DistinguishedNames,
+};
+use std::{sync::Arc, time::SystemTime};
 use tracing::*;
 
-pub async fn listen(
-    address: &str,
+/// Asks every peer for the certificate of its device.
+///
+/// Devices sign their own certificates, so any certificate is accepted as long as the peer proves
+/// it holds the key. The device id derived from it is checked against the one the peer claims.
+struct DeviceCertVerifier;
+
+impl ClientCertVerifier for DeviceCertVerifier {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+pub fn server_config(
     cert_filename: Option<String>,
     private_key_filename: Option<String>,
-    source_path: PathBuf,
-) -> Result<()> {
-    // Try to resolve relative source paths.
-    let source_path = source_path.canonicalize()?;
-
+) -> Result<ServerConfig> {
     // Create server connection configuration.
     let cert_filename = certificate_filename_or_default(cert_filename);
     let private_key_filename = private_key_filename_or_default(private_key_filename);
     let certs = read_certs_from_file(cert_filename)?;
     let private_key = read_private_key_from_file(private_key_filename)?;
-    let server_config = ServerConfig::with_single_cert(certs, private_key)?;
 
-    // Create the endpoint to start receiving connections.
-    let endpoint = Endpoint::server(server_config, address.parse()?)?;
+    // Require the certificate of the connecting device.
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(Arc::new(DeviceCertVerifier))
+        .with_single_cert(certs, private_key)?;
+    crypto.max_early_data_size = u32::MAX;
 
-    // Setup file watcher.
-    let (watcher_tx, _) = broadcast::channel(16);
-    let watcher_tx_clone = watcher_tx.clone();
-    let mut watcher = notify::recommended_watcher(move |res: Result<notify::Event, _>| {
-        dbg!(&res);
-        let event = match res {
-            Ok(event) => event,
-            Err(e) => {
-                error!("Watcher error: {e:?}");
-
-                return;
-            }
-        };
-
-        watcher_tx_clone.send(event).unwrap_or_default();
-    })?;
-    // watcher.watch(&source_path, RecursiveMode::Recursive)?;
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
 
+pub async fn listen(endpoint: Endpoint, node: Arc<Node>) {
     // Process incoming connections.
     info!("Waiting for connections...");
-    while let Some(connection) = endpoint.accept().await {
-        // Accept incoming connection.
-        let connection = match connection.await {
-            Ok(connection) => connection,
-            Err(e) => {
-                error!("Unable to accept incoming connection: {e:?}");
-
-                continue;
-            }
-        };
-
-        // Open incoming connection.
-        let (send, recv) = match connection.accept_bi().await {
-            Ok(ch) => ch,
-            Err(e) => {
-                error!("Fail to open incoming connection: {e:?}");
-
-                continue;
-            }
-        };
-
-        // Create a task to handle client requests.
-        {
-            let connection = connection.clone();
-            tokio::spawn(async move {
-                let remote_address = connection.remote_address();
-
-                match handle_client(remote_address, send, recv).await {
-                    Ok(()) => info!("Client closed connection {}.", remote_address),
-                    Err(e) => error!("Error handling client {}: {}", remote_address, e),
-                }
-
-                connection.closed().await;
-            });
-        }
-    }
-
-    Ok(())
-}
-
-async fn handle_client(
-    remote_address: SocketAddr,
-    send: SendStream,
-    recv: RecvStream,
-) -> Result<()> {
-    let mut write_framed = FramedWrite::new(send, MessageEncoder);
-    let mut read_framed = FramedRead::new(recv, MessageDecoder);
-
-    //
-    loop {
-        let Some(message) = read_framed.try_next().await? else {
-            break;
-        };
-        dbg!(&message);
-
-        match message {
-            Message::WatcherEvent(notify_event) => match notify_event.kind {
-                notify::EventKind::Modify(_) => {
-                    for path in &notify_event.paths {
-                        if let Err(e) =
-                            start_file_sync(path, &mut write_framed, &mut read_framed).await
-                        {
-                            error!("Fail to sync file {path:?}: {e:?}");
-                        }
-                    }
-                }
-                notify::EventKind::Remove(_) => {
-                    for path in &notify_event.paths {
-                        if path.is_dir() {
-                            if let Err(e) = tokio::fs::remove_dir_all(path).await {
-                                error!("Fail to remove folder {path:?}: {e:?}");
-                            }
-                        } else {
-                            if let Err(e) = tokio::fs::remove_file(path).await {
-                                error!("Fail to remove file {path:?}: {e:?}");
-                            }
-                        }
-                    }
-                }
-
-                _ => warn!("Not handling this watcher event: {notify_event:#?}"),
-            },
-            Message::FileInfo(file_info) => {
-                if let Err(e) = handle_file_sync(&file_info).await {
-                    error!("Fail to handle file sync: {e:?}");
+    while let Some(connecting) = endpoint.accept().await {
+        let node = node.clone();
+        tokio::spawn(async move {
+            // Accept incoming connection.
+            let connection = match connecting.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Unable to accept incoming connection: {e:?}");
+
+                    return;
                 }
+            };
+
+            // Handle the peer like any other.
+            let remote_address = connection.remote_address();
+            let result = match node.handshake(connection, false).await {
+                Ok(peer) => node.run_peer(peer).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => info!("Peer {remote_address} closed connection."),
+                Err(e) => error!("Error handling peer {remote_address}: {e:#}"),
             }
-            Message::BlockInfo(block_info) => {
-                todo!();
-            }
-        }
+        });
     }
-
-    Ok(())
 }
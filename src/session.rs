@@ -0,0 +1,291 @@
+use crate::{
+    device_id::device_id_to_string,
+    messages::{
+        Compression, DeviceId, FolderId, FolderMode, HashAlgorithm, Hello, Message, MessageDecoder,
+        MessageEncoder, WirePath, PROTOCOL_VERSION,
+    },
+    node::{Change, ChangeKind},
+    rate_limit::{Direction, PeerRateLimiter, RateLimiter},
+    transfers::{TransferGuard, Transfers},
+};
+use clap::ValueEnum;
+use color_eyre::{eyre::eyre, Result};
+use futures::{SinkExt, TryStreamExt};
+use globset::Glob;
+use quinn::{RecvStream, SendStream};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::broadcast;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::*;
+
+pub const DEFAULT_PARALLEL_TRANSFERS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    hash_algorithm: HashAlgorithm,
+    compression: Compression,
+    compression_level: i32,
+    rate_limiter: RateLimiter,
+    parallel_transfers: usize,
+    priority_patterns: Vec<Glob>,
+}
+
+impl SessionOptions {
+    pub fn new(
+        hash_algorithm: HashAlgorithm,
+        compression: Compression,
+        compression_level: i32,
+        rate_limiter: RateLimiter,
+    ) -> Self {
+        Self {
+            hash_algorithm,
+            compression,
+            compression_level,
+            rate_limiter,
+            parallel_transfers: DEFAULT_PARALLEL_TRANSFERS,
+            priority_patterns: Vec::new(),
+        }
+    }
+
+    pub fn with_transfers(self, parallel_transfers: usize, priority_patterns: Vec<Glob>) -> Self {
+        Self {
+            parallel_transfers,
+            priority_patterns,
+            ..self
+        }
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn parallel_transfers(&self) -> usize {
+        self.parallel_transfers
+    }
+
+    pub fn priority_patterns(&self) -> &[Glob] {
+        &self.priority_patterns
+    }
+}
+
+#[derive(Debug)]
+pub struct Session {
+    device_id: DeviceId,
+    hash_algorithm: HashAlgorithm,
+    compression: Compression,
+    compression_level: i32,
+    rate_limiter: PeerRateLimiter,
+    features: u64,
+    one_shot: bool,
+    folder_modes: HashMap<FolderId, FolderMode>,
+    changes: broadcast::Sender<Change>,
+    transfers: Arc<Transfers>,
+}
+
+impl Session {
+    pub fn local_hello(device_id: DeviceId, options: &SessionOptions) -> Hello {
+        // Advertise every supported hash algorithm, starting with the preferred one.
+        let hash_algorithm = options.hash_algorithm;
+        let mut hash_algorithms = vec![hash_algorithm];
+        hash_algorithms.extend(
+            HashAlgorithm::value_variants()
+                .iter()
+                .filter(|supported| **supported != hash_algorithm),
+        );
+
+        // Advertise every supported compression, starting with the preferred one.
+        let compression = options.compression;
+        let mut compressions = vec![compression];
+        compressions.extend(
+            Compression::value_variants()
+                .iter()
+                .filter(|supported| **supported != compression),
+        );
+
+        // Only platforms with byte based file names can store names that are not unicode.
+        let mut features = Hello::FEATURE_DIRECTORY_SYNC
+            | Hello::FEATURE_FILE_INFO_REQUEST
+            | Hello::FEATURE_BLOCK_REQUEST;
+        if cfg!(unix) {
+            features |= Hello::FEATURE_RAW_PATHS;
+        }
+
+        Hello::new(device_id, hash_algorithms, compressions, features)
+    }
+
+    pub async fn handshake(
+        hello: &Hello,
+        options: &SessionOptions,
+        changes: &broadcast::Sender<Change>,
+        initiator: bool,
+        write_framed: &mut FramedWrite<SendStream, MessageEncoder>,
+        read_framed: &mut FramedRead<RecvStream, MessageDecoder>,
+    ) -> Result<Self> {
+        // Exchange hello messages.
+        write_framed.send(&Message::Hello(hello.clone())).await?;
+
+        let received_hello = match read_framed.try_next().await? {
+            Some(Message::Hello(received_hello)) => received_hello,
+            Some(message) => return Err(eyre!("Expected hello from peer, got: {message:?}")),
+            None => return Err(eyre!("Peer closed the connection during the handshake.")),
+        };
+        debug!("Received hello: {received_hello:?}");
+
+        let session = Self::negotiate(hello, &received_hello, options, changes, initiator)?;
+        info!(
+            "Connected to device {} using {:?} hashes and {:?} compression.",
+            device_id_to_string(session.device_id()),
+            session.hash_algorithm(),
+            session.compression()
+        );
+
+        Ok(session)
+    }
+
+    fn negotiate(
+        hello: &Hello,
+        received_hello: &Hello,
+        options: &SessionOptions,
+        changes: &broadcast::Sender<Change>,
+        initiator: bool,
+    ) -> Result<Self> {
+        // Both sides must speak the same protocol.
+        if received_hello.protocol_version() != PROTOCOL_VERSION {
+            return Err(eyre!(
+                "Incompatible protocol version: peer speaks version {}, this device speaks version {PROTOCOL_VERSION}.",
+                received_hello.protocol_version()
+            ));
+        }
+
+        // Follow the preference of the side that opened the connection so both agree.
+        let (preferred, other) = if initiator {
+            (hello, received_hello)
+        } else {
+            (received_hello, hello)
+        };
+
+        let hash_algorithm = preferred
+            .hash_algorithms()
+            .iter()
+            .find(|hash_algorithm| other.hash_algorithms().contains(hash_algorithm))
+            .copied()
+            .ok_or_else(|| {
+                eyre!(
+                    "No common hash algorithm: peer supports {:?}, this device supports {:?}.",
+                    received_hello.hash_algorithms(),
+                    hello.hash_algorithms()
+                )
+            })?;
+
+        let compression = preferred
+            .compressions()
+            .iter()
+            .find(|compression| other.compressions().contains(compression))
+            .copied()
+            .ok_or_else(|| {
+                eyre!(
+                    "No common compression: peer supports {:?}, this device supports {:?}.",
+                    received_hello.compressions(),
+                    hello.compressions()
+                )
+            })?;
+
+        Ok(Self {
+            device_id: *received_hello.device_id(),
+            hash_algorithm,
+            compression,
+            compression_level: options.compression_level,
+            rate_limiter: options.rate_limiter.peer(),
+            features: hello.features() & received_hello.features(),
+            one_shot: received_hello.features() & Hello::FEATURE_ONE_SHOT != 0,
+            folder_modes: HashMap::new(),
+            changes: changes.clone(),
+            transfers: Arc::default(),
+        })
+    }
+
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level
+    }
+
+    pub fn rate_limiter(&self) -> &PeerRateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn with_folder_modes(self, folder_modes: HashMap<FolderId, FolderMode>) -> Self {
+        Self {
+            folder_modes,
+            ..self
+        }
+    }
+
+    pub fn with_transfers(self, transfers: Arc<Transfers>) -> Self {
+        Self { transfers, ..self }
+    }
+
+    pub fn start_transfer(&self, folder_id: FolderId, path: WirePath) -> TransferGuard {
+        // Listed until the returned guard is dropped.
+        self.transfers.start(self.device_id, folder_id, path)
+    }
+
+    pub fn set_transfer_direction(
+        &self,
+        folder_id: &FolderId,
+        path: &WirePath,
+        direction: Direction,
+    ) {
+        self.transfers
+            .set_direction(&self.device_id, folder_id, path, direction);
+    }
+
+    pub fn count_bytes(&self, direction: Direction, bytes: usize) {
+        self.transfers
+            .count_bytes(&self.device_id, direction, bytes);
+    }
+
+    pub fn folder_mode(&self, folder_id: &FolderId) -> FolderMode {
+        // The mode the peer uses for each folder is learned once folders are shared.
+        self.folder_modes
+            .get(folder_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn is_one_shot(&self) -> bool {
+        // Only the peer decides whether it stays, it needn't be shared.
+        self.one_shot
+    }
+
+    pub fn notify(&self, folder_id: FolderId, kind: ChangeKind, path: WirePath) {
+        // Let the other peers know what this one changed, nobody may be listening.
+        // A single command may run for a device that is also connected, which must hear of it too.
+        let origin = (!self.one_shot).then_some(self.device_id);
+        let _ = self
+            .changes
+            .send(Change::new(origin, folder_id, kind, path));
+    }
+
+    pub fn can_represent(&self, path: &WirePath) -> bool {
+        path.to_os_string().to_str().is_some() || self.has_feature(Hello::FEATURE_RAW_PATHS)
+    }
+}
@@ -0,0 +1,79 @@
+use crate::{
+    index::Index,
+    messages::{FolderId, WirePath},
+    rate_limit::Direction,
+};
+
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    device_id: String,
+    uploads: usize,
+    downloads: usize,
+    removals: usize,
+    remote_removals: usize,
+    conflicts: Vec<(FolderId, WirePath)>,
+    errors: Vec<(FolderId, WirePath, String)>,
+}
+
+impl SyncSummary {
+    pub fn new(device_id: String) -> Self {
+        Self {
+            device_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_transfer(&mut self, direction: Direction) {
+        match direction {
+            Direction::Upload => self.uploads += 1,
+            Direction::Download => self.downloads += 1,
+        }
+    }
+
+    pub fn add_removal(&mut self, direction: Direction) {
+        match direction {
+            Direction::Upload => self.remote_removals += 1,
+            Direction::Download => self.removals += 1,
+        }
+    }
+
+    pub fn add_folder(&mut self, index: &Index) {
+        // What is left once every transfer is over is what didn't sync.
+        let folder_id = index.folder_id();
+        self.conflicts.extend(
+            index
+                .conflicts()
+                .map(|path| (folder_id.clone(), path.clone())),
+        );
+        self.errors.extend(
+            index
+                .errors()
+                .map(|(path, error)| (folder_id.clone(), path.clone(), error.clone())),
+        );
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Synced with device {}: {} uploads, {} downloads, {} removed here, {} removed on the peer",
+            self.device_id, self.uploads, self.downloads, self.removals, self.remote_removals
+        );
+
+        if !self.conflicts.is_empty() {
+            println!("Conflicts, the newer change was kept:");
+            for (folder_id, path) in &self.conflicts {
+                println!("  {folder_id}: {path}");
+            }
+        }
+
+        if !self.errors.is_empty() {
+            println!("Did not sync:");
+            for (folder_id, path, error) in &self.errors {
+                println!("  {folder_id}: {path}: {error}");
+            }
+        }
+    }
+}
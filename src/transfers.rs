@@ -0,0 +1,265 @@
+use crate::{
+    messages::{DeviceId, FolderId, WirePath},
+    rate_limit::Direction,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+pub type Queue = Arc<Mutex<HashMap<WirePath, Option<Direction>>>>;
+
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    device_id: DeviceId,
+    folder_id: FolderId,
+    path: WirePath,
+    direction: Option<Direction>,
+    started: SystemTime,
+}
+
+impl Transfer {
+    pub fn device_id(&self) -> &DeviceId {
+        &self.device_id
+    }
+
+    pub fn folder_id(&self) -> &FolderId {
+        &self.folder_id
+    }
+
+    pub fn path(&self) -> &WirePath {
+        &self.path
+    }
+
+    pub fn direction(&self) -> Option<Direction> {
+        self.direction
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+}
+
+#[derive(Debug)]
+struct Throughput {
+    start: Instant,
+    bytes: u64,
+    rate: u64,
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            bytes: 0,
+            rate: 0,
+        }
+    }
+}
+
+impl Throughput {
+    fn add(&mut self, bytes: u64, now: Instant) {
+        self.roll(now);
+        self.bytes += bytes;
+    }
+
+    fn rate(&mut self, now: Instant) -> u64 {
+        self.roll(now);
+        self.rate
+    }
+
+    fn roll(&mut self, now: Instant) {
+        // Report the rate of the last full window, so it doesn't jump with every block.
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed < THROUGHPUT_WINDOW {
+            return;
+        }
+
+        self.rate = (self.bytes as f64 / elapsed.as_secs_f64()) as u64;
+        self.start = now;
+        self.bytes = 0;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Transfers {
+    next_id: AtomicU64,
+    transfers: Mutex<BTreeMap<u64, Transfer>>,
+    queues: Mutex<HashMap<(DeviceId, FolderId), Queue>>,
+    throughputs: Mutex<HashMap<DeviceId, (Throughput, Throughput)>>,
+}
+
+impl Transfers {
+    pub fn start(
+        self: &Arc<Self>,
+        device_id: DeviceId,
+        folder_id: FolderId,
+        path: WirePath,
+    ) -> TransferGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.transfers.lock().unwrap().insert(
+            id,
+            Transfer {
+                device_id,
+                folder_id,
+                path,
+                direction: None,
+                started: SystemTime::now(),
+            },
+        );
+
+        TransferGuard {
+            transfers: self.clone(),
+            id,
+        }
+    }
+
+    pub fn set_direction(
+        &self,
+        device_id: &DeviceId,
+        folder_id: &FolderId,
+        path: &WirePath,
+        direction: Direction,
+    ) {
+        // Which way a file goes is only known once both sides described it.
+        for transfer in self.transfers.lock().unwrap().values_mut() {
+            if transfer.device_id == *device_id
+                && transfer.folder_id == *folder_id
+                && transfer.path == *path
+            {
+                transfer.direction = Some(direction);
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<Transfer> {
+        self.transfers.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn add_queue(
+        self: &Arc<Self>,
+        device_id: DeviceId,
+        folder_id: FolderId,
+        queue: Queue,
+    ) -> QueueGuard {
+        let key = (device_id, folder_id);
+        self.queues
+            .lock()
+            .unwrap()
+            .insert(key.clone(), queue.clone());
+
+        QueueGuard {
+            transfers: self.clone(),
+            key,
+            queue,
+        }
+    }
+
+    pub fn pending(&self, folder_id: &FolderId) -> Vec<(WirePath, Option<Direction>)> {
+        // Files waiting for their turn with any peer, then the ones being transferred.
+        let mut pending = Vec::new();
+        for ((_, queue_folder_id), queue) in self.queues.lock().unwrap().iter() {
+            if queue_folder_id == folder_id {
+                pending.extend(
+                    queue
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|(path, direction)| (path.clone(), *direction)),
+                );
+            }
+        }
+
+        pending.extend(
+            self.transfers
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|transfer| transfer.folder_id == *folder_id)
+                .map(|transfer| (transfer.path.clone(), transfer.direction)),
+        );
+
+        pending
+    }
+
+    pub fn count_bytes(&self, device_id: &DeviceId, direction: Direction, bytes: usize) {
+        let mut throughputs = self.throughputs.lock().unwrap();
+        let (upload, download) = throughputs.entry(*device_id).or_default();
+        let throughput = match direction {
+            Direction::Upload => upload,
+            Direction::Download => download,
+        };
+
+        throughput.add(bytes as u64, Instant::now());
+    }
+
+    pub fn throughput(&self, device_id: &DeviceId) -> (u64, u64) {
+        // Bytes per second sent to and received from the device.
+        let now = Instant::now();
+        match self.throughputs.lock().unwrap().get_mut(device_id) {
+            Some((upload, download)) => (upload.rate(now), download.rate(now)),
+            None => (0, 0),
+        }
+    }
+}
+
+pub struct TransferGuard {
+    transfers: Arc<Transfers>,
+    id: u64,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        // The transfer is over however it ended.
+        self.transfers.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub struct QueueGuard {
+    transfers: Arc<Transfers>,
+    key: (DeviceId, FolderId),
+    queue: Queue,
+}
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        // A new connection to the same device may have registered its queue already.
+        let mut queues = self.transfers.queues.lock().unwrap();
+        if queues
+            .get(&self.key)
+            .is_some_and(|queue| Arc::ptr_eq(queue, &self.queue))
+        {
+            queues.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Throughput, THROUGHPUT_WINDOW};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn throughput() {
+        let start = Instant::now();
+        let mut throughput = Throughput {
+            start,
+            bytes: 0,
+            rate: 0,
+        };
+
+        // Make sure the rate only changes once a window is over.
+        throughput.add(5 * 1024 * 1024, start + Duration::from_secs(1));
+        assert_eq!(throughput.rate(start + Duration::from_secs(2)), 0);
+        assert_eq!(throughput.rate(start + THROUGHPUT_WINDOW), 1024 * 1024);
+
+        // Make sure an idle device drops to nothing.
+        assert_eq!(throughput.rate(start + THROUGHPUT_WINDOW * 2), 0);
+    }
+}
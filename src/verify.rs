@@ -0,0 +1,269 @@
+use crate::{
+    device_id::device_id_to_string,
+    file_sync::request_file_info,
+    index::Index,
+    messages::{BlockInfo, BlockRequest, FileInfo, FolderId, Hello, Message, WirePath},
+    node::open_folder_stream,
+    outcome::receive_message,
+    path_id_cache::PathIdCache,
+    rate_limit::Direction,
+    session::Session,
+};
+use color_eyre::{eyre::eyre, Result};
+use futures::SinkExt;
+use quinn::Connection;
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{Seek, SeekFrom, Write},
+    sync::Arc,
+};
+use tokio::sync::RwLock;
+use tracing::*;
+
+#[derive(Debug)]
+pub enum Problem {
+    Missing,
+    Modified,
+    Corrupted(Vec<BlockInfo>),
+    Unreadable(String),
+    MissingOnPeer(String),
+    DiffersFromPeer(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing, rescan to forget it"),
+            Problem::Modified => write!(f, "changed unnoticed, rescan to pick up the change"),
+            Problem::Corrupted(block_infos) => {
+                write!(f, "{} blocks no longer match the index", block_infos.len())
+            }
+            Problem::Unreadable(error) => write!(f, "unreadable: {error}"),
+            Problem::MissingOnPeer(device_id) => write!(f, "missing on device {device_id}"),
+            Problem::DiffersFromPeer(device_id) => write!(f, "differs on device {device_id}"),
+        }
+    }
+}
+
+pub async fn verify_file(path: &WirePath, index: &RwLock<Index>) -> Result<Option<Problem>> {
+    let (local_path, known, known_block_infos) = {
+        let index = index.read().await;
+        let Some(known) = index.file_info(path).cloned() else {
+            return Ok(None);
+        };
+        (
+            path.to_local(index.root()),
+            known,
+            index.block_infos(path).to_vec(),
+        )
+    };
+    if !local_path.exists() {
+        return Ok(Some(Problem::Missing));
+    }
+
+    // Only content that changed behind the back of the file system is corruption.
+    let metadata = local_path.metadata()?;
+    if metadata.len() != known.size() || metadata.modified()? != *known.last_modified() {
+        return Ok(Some(Problem::Modified));
+    }
+
+    // Rehash every block and compare it with the indexed one at the same offset.
+    let hash_algorithm = known.hash_algorithm();
+    let file_path = path.clone();
+    let (_, block_infos) = tokio::task::spawn_blocking(move || {
+        FileInfo::with_blocks(&local_path, file_path, hash_algorithm)
+    })
+    .await??;
+
+    let corrupted: Vec<_> = known_block_infos
+        .into_iter()
+        .filter(|known_block_info| {
+            !block_infos.iter().any(|block_info| {
+                block_info.offset() == known_block_info.offset()
+                    && block_info.hash() == known_block_info.hash()
+            })
+        })
+        .collect();
+    if corrupted.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Problem::Corrupted(corrupted)))
+}
+
+pub async fn compare_with_peer(
+    folder_id: &FolderId,
+    paths: &[WirePath],
+    index: &RwLock<Index>,
+    connection: &Connection,
+    session: &Session,
+) -> Result<Vec<(WirePath, Problem)>> {
+    let device_id = device_id_to_string(session.device_id());
+    if !session.has_feature(Hello::FEATURE_FILE_INFO_REQUEST) {
+        return Err(eyre!(
+            "Device {device_id} is unable to describe files without syncing them."
+        ));
+    }
+
+    // Ask the peer how it indexed every file, hashed like this folder was.
+    let (mut write_framed, mut read_framed) = open_folder_stream(connection, folder_id).await?;
+    let mut problems = Vec::new();
+    for path in paths {
+        let Some(known) = index.read().await.file_info(path).cloned() else {
+            continue;
+        };
+
+        let (file_info, _) = request_file_info(
+            path,
+            known.hash_algorithm(),
+            &mut write_framed,
+            &mut read_framed,
+        )
+        .await?;
+        if file_info.is_removed() {
+            problems.push((path.clone(), Problem::MissingOnPeer(device_id.clone())));
+        } else if !file_info.has_same_content(&known) {
+            problems.push((path.clone(), Problem::DiffersFromPeer(device_id.clone())));
+        }
+    }
+
+    write_framed.close().await?;
+
+    Ok(problems)
+}
+
+pub async fn repair_file(
+    path: &WirePath,
+    block_infos: &[BlockInfo],
+    index: &RwLock<Index>,
+    peers: &[(Connection, Arc<Session>)],
+) -> Result<()> {
+    // Copies elsewhere in the folder come first, peers only send what is still missing.
+    let mut blocks = Vec::new();
+    let mut missing = Vec::new();
+    for block_info in block_infos {
+        let buffer = index.read().await.read_block(block_info).await;
+        match buffer {
+            Some(buffer) => blocks.push((block_info.offset(), buffer)),
+            None => missing.push(block_info.clone()),
+        }
+    }
+
+    let folder_id = index.read().await.folder_id().clone();
+    for (connection, session) in peers {
+        if missing.is_empty() {
+            break;
+        }
+
+        if !session.has_feature(Hello::FEATURE_BLOCK_REQUEST) {
+            continue;
+        }
+
+        match fetch_blocks(&folder_id, path, &missing, connection, session).await {
+            Ok(fetched) => {
+                missing.retain(|block_info| {
+                    !fetched
+                        .iter()
+                        .any(|(offset, _)| *offset == block_info.offset())
+                });
+                blocks.extend(fetched);
+            }
+            Err(e) => warn!(
+                "Device {} did not send blocks of {path:?}: {e:#}",
+                device_id_to_string(session.device_id())
+            ),
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(eyre!(
+            "No intact copy of {} blocks of {path:?} was found.",
+            missing.len()
+        ));
+    }
+
+    // Write the blocks back in place, keeping the date so the file is not taken for a new version.
+    let index = index.write().await;
+    let Some(known) = index.file_info(path) else {
+        return Err(eyre!("File {path:?} is no longer indexed."));
+    };
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path.to_local(index.root()))?;
+    for (offset, buffer) in &blocks {
+        file.seek(SeekFrom::Start(*offset))?;
+        file.write_all(buffer)?;
+    }
+    file.sync_all()?;
+    file.set_modified(*known.last_modified())?;
+
+    info!("Repaired {} blocks of {path:?}.", blocks.len());
+
+    Ok(())
+}
+
+async fn fetch_blocks(
+    folder_id: &FolderId,
+    path: &WirePath,
+    block_infos: &[BlockInfo],
+    connection: &Connection,
+    session: &Session,
+) -> Result<Vec<(u64, Vec<u8>)>> {
+    let Some(hash_algorithm) = block_infos.first().map(BlockInfo::hash_algorithm) else {
+        return Ok(Vec::new());
+    };
+
+    // Only ask for the blocks the peer still holds with the expected content.
+    let (mut write_framed, mut read_framed) = open_folder_stream(connection, folder_id).await?;
+    let (_, peer_block_infos) =
+        request_file_info(path, hash_algorithm, &mut write_framed, &mut read_framed).await?;
+    let block_infos: Vec<_> = block_infos
+        .iter()
+        .filter(|block_info| {
+            peer_block_infos.iter().any(|peer_block_info| {
+                peer_block_info.offset() == block_info.offset()
+                    && peer_block_info.hash() == block_info.hash()
+            })
+        })
+        .collect();
+
+    let path_id = PathIdCache::calculate_path_id(path);
+    let offsets = block_infos
+        .iter()
+        .map(|block_info| block_info.offset())
+        .collect();
+    write_framed
+        .send(&Message::BlockRequest(BlockRequest::new(path_id, offsets)))
+        .await?;
+
+    // Receive and verify block data.
+    let mut blocks = Vec::new();
+    for block_info in block_infos {
+        let Message::BlockData(block_data) = receive_message(&mut read_framed).await? else {
+            return Err(eyre!("Did not receive block data."));
+        };
+        if block_data.path_id() != &path_id || block_data.offset() != block_info.offset() {
+            return Err(eyre!("Received unexpected block data."));
+        }
+
+        session.count_bytes(Direction::Download, block_data.data().len());
+
+        let data = block_data.decompress(block_info.block_size() as usize)?;
+        let received_block_info =
+            BlockInfo::from_buffer(&data, path_id, block_info.offset(), hash_algorithm);
+        if received_block_info.hash() != block_info.hash() {
+            return Err(eyre!(
+                "Block at offset {} of file {path:?} is corrupted on the peer too.",
+                block_info.offset()
+            ));
+        }
+
+        blocks.push((block_info.offset(), data.to_vec()));
+    }
+
+    write_framed.close().await?;
+
+    Ok(blocks)
+}
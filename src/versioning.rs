@@ -0,0 +1,482 @@
+use crate::{
+    device_id::device_id_to_string,
+    file_sync::TemporaryFile,
+    ignore::METADATA_DIRECTORY,
+    messages::{DeviceId, WirePath},
+};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report, Result,
+};
+use std::{
+    cmp::Reverse,
+    ffi::OsString,
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+use tracing::*;
+use walkdir::WalkDir;
+
+const VERSIONS_DIRECTORY: &str = "versions";
+const VERSION_TIME_FORMAT: &str = "%Y%m%d-%H%M%S-%3f";
+const DEFAULT_SIMPLE_COUNT: usize = 5;
+const DEFAULT_STAGGERED_DAYS: u32 = 365;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Versioning {
+    #[default]
+    Off,
+    Trash {
+        days: u32,
+    },
+    Simple {
+        count: usize,
+    },
+    Staggered {
+        days: u32,
+    },
+}
+
+impl Versioning {
+    pub fn is_enabled(&self) -> bool {
+        *self != Versioning::Off
+    }
+
+    fn expired(&self, times: &[DateTime<Utc>], now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let mut times = times.to_vec();
+        times.sort_unstable_by(|a, b| b.cmp(a));
+
+        match *self {
+            Versioning::Off => Vec::new(),
+            // A trash can empties itself of what it held for too long, if ever.
+            Versioning::Trash { days } => times
+                .into_iter()
+                .filter(|time| days > 0 && now - *time > Duration::days(days.into()))
+                .collect(),
+            Versioning::Simple { count } => times.into_iter().skip(count).collect(),
+            // Keep every recent version, then one per hour, one per day and one per week until too old.
+            Versioning::Staggered { days } => {
+                let mut expired = Vec::new();
+                let mut kept: Option<DateTime<Utc>> = None;
+                for time in times {
+                    let age = now - time;
+                    if age > Duration::days(days.into()) {
+                        expired.push(time);
+
+                        continue;
+                    }
+
+                    let interval = if age < Duration::hours(1) {
+                        Duration::zero()
+                    } else if age < Duration::days(1) {
+                        Duration::hours(1)
+                    } else if age < Duration::days(30) {
+                        Duration::days(1)
+                    } else {
+                        Duration::weeks(1)
+                    };
+                    match kept {
+                        Some(kept) if kept - time < interval => expired.push(time),
+                        _ => kept = Some(time),
+                    }
+                }
+
+                expired
+            }
+        }
+    }
+}
+
+impl FromStr for Versioning {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (strategy, setting) = match s.split_once(':') {
+            Some((strategy, setting)) => (strategy, Some(setting)),
+            None => (s, None),
+        };
+        let invalid = || {
+            eyre!("Invalid versioning {s:?}, expected off, trash[:DAYS], simple[:COUNT] or staggered[:DAYS], like simple:10.")
+        };
+
+        match (strategy, setting) {
+            ("off", None) => Ok(Versioning::Off),
+            ("trash", None) => Ok(Versioning::Trash { days: 0 }),
+            ("trash", Some(days)) => Ok(Versioning::Trash {
+                days: days.parse().map_err(|_| invalid())?,
+            }),
+            ("simple", None) => Ok(Versioning::Simple {
+                count: DEFAULT_SIMPLE_COUNT,
+            }),
+            ("simple", Some(count)) => match count.parse() {
+                Ok(count) if count > 0 => Ok(Versioning::Simple { count }),
+                _ => Err(invalid()),
+            },
+            ("staggered", None) => Ok(Versioning::Staggered {
+                days: DEFAULT_STAGGERED_DAYS,
+            }),
+            ("staggered", Some(days)) => match days.parse() {
+                Ok(days) if days > 0 => Ok(Versioning::Staggered { days }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Version {
+    path: PathBuf,
+    time: DateTime<Utc>,
+    device_id: String,
+    size: u64,
+}
+
+impl Version {
+    fn from_path(path: PathBuf, size: u64) -> Option<Self> {
+        // Versions are named after when they were replaced and by which device, maybe with a counter.
+        let mut parts = path.file_stem()?.to_str()?.split('~');
+        let time = NaiveDateTime::parse_from_str(parts.next()?, VERSION_TIME_FORMAT)
+            .ok()?
+            .and_utc();
+        let device_id = parts.next()?.to_owned();
+
+        Some(Self {
+            path,
+            time,
+            device_id,
+            size,
+        })
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.time.into()
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Keeps the current content of a file, or of every file in a directory, as a version.
+///
+/// The files stay in place for the caller to replace or remove, so the watcher never sees them go
+/// before the index does.
+pub fn keep_versions(
+    root: &Path,
+    path: &WirePath,
+    versioning: Versioning,
+    device_id: &DeviceId,
+) -> Result<()> {
+    if !versioning.is_enabled() {
+        return Ok(());
+    }
+
+    // A removed directory leaves a version of every file in it, never of the versions themselves.
+    let local_path = path.to_local(root);
+    if local_path.is_dir() {
+        let metadata_directory = root.join(METADATA_DIRECTORY);
+        for entry in WalkDir::new(&local_path)
+            .into_iter()
+            .filter_entry(|entry| entry.path() != metadata_directory)
+        {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let path = WirePath::from_local(root, entry.path())?;
+                keep_version(root, &path, versioning, device_id)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    keep_version(root, path, versioning, device_id)
+}
+
+fn keep_version(
+    root: &Path,
+    path: &WirePath,
+    versioning: Versioning,
+    device_id: &DeviceId,
+) -> Result<()> {
+    // Every file gets a directory of its own, keeping the extension so versions still open.
+    let local_path = path.to_local(root);
+    let directory = versions_directory(root, path);
+    let now = Utc::now();
+    let stem = format!(
+        "{}~{}",
+        now.format(VERSION_TIME_FORMAT),
+        device_id_to_string(device_id)
+    );
+
+    // Versions replaced within the same millisecond get a counter, a rename would overwrite the first one.
+    let path_for = |stem: String| {
+        let mut name = OsString::from(stem);
+        if let Some(extension) = local_path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+
+        directory.join(name)
+    };
+    let mut version_path = path_for(stem.clone());
+    let mut counter = 1;
+    while version_path.exists() {
+        version_path = path_for(format!("{stem}~{counter}"));
+        counter += 1;
+    }
+
+    // Versions live in the same folder, so a link keeps the content unless the filesystem has none.
+    std::fs::create_dir_all(&directory)
+        .and_then(|()| {
+            std::fs::hard_link(&local_path, &version_path)
+                .or_else(|_| std::fs::copy(&local_path, &version_path).map(drop))
+        })
+        .wrap_err_with(|| format!("Unable to keep a version of {path:?}."))?;
+    info!("Kept the previous version of {path:?}.");
+
+    remove_expired_versions(&directory, versioning, now)?;
+
+    Ok(())
+}
+
+pub fn list_versions(root: &Path, path: &WirePath) -> Result<Vec<Version>> {
+    let directory = versions_directory(root, path);
+    if !directory.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    // Newest first, the version wanted back is usually a recent one.
+    let mut versions = read_versions(&directory)?;
+    versions.sort_unstable_by_key(|version| Reverse(version.time));
+
+    Ok(versions)
+}
+
+pub fn version_at(versions: &[Version], at: Option<SystemTime>) -> Option<&Version> {
+    // The content a file had at some time was replaced by the first change after it.
+    match at {
+        Some(at) => versions.iter().rev().find(|version| version.time() >= at),
+        None => versions.first(),
+    }
+}
+
+/// Copies a version next to the file, for the caller to move in place.
+pub fn restore_version(
+    root: &Path,
+    path: &WirePath,
+    version: &Version,
+    versioning: Versioning,
+    device_id: &DeviceId,
+) -> Result<TemporaryFile> {
+    // The current content becomes a version of its own, so a restore can be undone.
+    let local_path = path.to_local(root);
+    if local_path.exists() {
+        keep_versions(root, path, versioning, device_id)?;
+    }
+
+    // Copy next to the destination so the watcher only sees the whole file, dated now so it wins everywhere.
+    if let Some(parent) = local_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temporary_file = TemporaryFile::next_to(&local_path);
+    std::fs::copy(&version.path, temporary_file.path())?;
+    OpenOptions::new()
+        .write(true)
+        .open(temporary_file.path())?
+        .set_modified(SystemTime::now())?;
+
+    Ok(temporary_file)
+}
+
+pub fn clean_versions(root: &Path, versioning: Versioning) -> Result<usize> {
+    let versions = root.join(METADATA_DIRECTORY).join(VERSIONS_DIRECTORY);
+    if !versioning.is_enabled() || !versions.is_dir() {
+        return Ok(0);
+    }
+
+    // Directories come after what they hold, so the ones left empty go too.
+    let now = Utc::now();
+    let mut removed = 0;
+    for entry in WalkDir::new(&versions).contents_first(true) {
+        let entry = entry?;
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+
+        removed += remove_expired_versions(entry.path(), versioning, now)?;
+        if std::fs::read_dir(entry.path())?.next().is_none() {
+            std::fs::remove_dir(entry.path())?;
+        }
+    }
+
+    Ok(removed)
+}
+
+fn remove_expired_versions(
+    directory: &Path,
+    versioning: Versioning,
+    now: DateTime<Utc>,
+) -> Result<usize> {
+    let versions = read_versions(directory)?;
+    let times: Vec<_> = versions.iter().map(|version| version.time).collect();
+    let mut expired = versioning.expired(&times, now);
+
+    // Versions kept at the same time expire one by one.
+    let mut removed = 0;
+    for version in versions {
+        if let Some(position) = expired.iter().position(|time| *time == version.time) {
+            expired.swap_remove(position);
+            std::fs::remove_file(&version.path)?;
+            debug!("Removed expired version {:?}.", version.path);
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+fn read_versions(directory: &Path) -> Result<Vec<Version>> {
+    let mut versions = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            versions.extend(Version::from_path(entry.path(), entry.metadata()?.len()));
+        }
+    }
+
+    Ok(versions)
+}
+
+fn versions_directory(root: &Path, path: &WirePath) -> PathBuf {
+    path.to_local(&root.join(METADATA_DIRECTORY).join(VERSIONS_DIRECTORY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{version_at, Version, Versioning};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::{path::PathBuf, time::SystemTime};
+
+    #[test]
+    fn versioning() {
+        // Make sure strategies parse with and without their setting.
+        assert_eq!("off".parse::<Versioning>().unwrap(), Versioning::Off);
+        assert_eq!(
+            "trash".parse::<Versioning>().unwrap(),
+            Versioning::Trash { days: 0 }
+        );
+        assert_eq!(
+            "simple:10".parse::<Versioning>().unwrap(),
+            Versioning::Simple { count: 10 }
+        );
+        assert_eq!(
+            "staggered".parse::<Versioning>().unwrap(),
+            Versioning::Staggered { days: 365 }
+        );
+        assert!("simple:0".parse::<Versioning>().is_err());
+        assert!("trash:forever".parse::<Versioning>().is_err());
+        assert!("off:1".parse::<Versioning>().is_err());
+
+        // Make sure version names keep when and by which device a file was replaced.
+        let version = Version::from_path(
+            PathBuf::from(format!(
+                "notes.txt/20261018-213403-120~{}.txt",
+                "ab".repeat(32)
+            )),
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            version.time,
+            Utc.with_ymd_and_hms(2026, 10, 18, 21, 34, 3).unwrap() + Duration::milliseconds(120)
+        );
+        assert_eq!(version.device_id(), "ab".repeat(32));
+        let version = Version::from_path(
+            PathBuf::from(format!(
+                "notes.txt/20261018-213403-120~{}~1.txt",
+                "ab".repeat(32)
+            )),
+            0,
+        )
+        .unwrap();
+        assert_eq!(version.device_id(), "ab".repeat(32));
+        assert!(Version::from_path(PathBuf::from("notes.txt/notes.txt"), 0).is_none());
+
+        // Make sure a time picks the content the file had then.
+        let versions: Vec<_> = ["20261018-120000-000", "20261018-110000-000"]
+            .into_iter()
+            .map(|time| Version::from_path(PathBuf::from(format!("{time}~ab")), 0).unwrap())
+            .collect();
+        let at = |hour, minute| {
+            Some(SystemTime::from(
+                Utc.with_ymd_and_hms(2026, 10, 18, hour, minute, 0).unwrap(),
+            ))
+        };
+        assert_eq!(
+            version_at(&versions, None).unwrap().time(),
+            versions[0].time()
+        );
+        assert_eq!(
+            version_at(&versions, at(11, 30)).unwrap().time(),
+            versions[0].time()
+        );
+        assert_eq!(
+            version_at(&versions, at(10, 0)).unwrap().time(),
+            versions[1].time()
+        );
+        assert!(version_at(&versions, at(12, 30)).is_none());
+    }
+
+    #[test]
+    fn retention() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let ago = |minutes| now - Duration::minutes(minutes);
+
+        // Make sure a trash can only empties itself when asked to.
+        let times = [ago(10), ago(60 * 24 * 40)];
+        assert!(Versioning::Trash { days: 0 }
+            .expired(&times, now)
+            .is_empty());
+        assert_eq!(
+            Versioning::Trash { days: 30 }.expired(&times, now),
+            [ago(60 * 24 * 40)]
+        );
+
+        // Make sure only the newest versions are kept.
+        let times = [ago(30), ago(10), ago(20)];
+        assert_eq!(
+            Versioning::Simple { count: 2 }.expired(&times, now),
+            [ago(30)]
+        );
+
+        // Make sure older versions thin out to one per hour, day and week.
+        let times = [
+            ago(10),
+            ago(20),
+            ago(120),
+            ago(150),
+            ago(60 * 24 * 3),
+            ago(60 * 24 * 3 + 60),
+            ago(60 * 24 * 40),
+            ago(60 * 24 * 42),
+            ago(60 * 24 * 400),
+        ];
+        assert_eq!(
+            Versioning::Staggered { days: 365 }.expired(&times, now),
+            [
+                ago(150),
+                ago(60 * 24 * 3 + 60),
+                ago(60 * 24 * 42),
+                ago(60 * 24 * 400)
+            ]
+        );
+    }
+}